@@ -1,19 +1,41 @@
+pub mod attachment;
+pub mod audit;
 pub mod auth;
+pub mod boxconf;
+pub mod command;
+pub mod device;
 pub mod discussion;
+pub mod dm;
+pub mod group;
+pub mod keys;
+pub mod notification;
+pub mod post;
+pub mod read;
+pub mod release;
+pub mod search;
+pub mod session;
+pub mod tag;
+pub mod telemetry;
+pub mod vote;
 
 use axum::{
     Json,
     http::StatusCode,
     response::{IntoResponse, Response},
 };
+use serde::Deserialize;
 use serde_json::json;
 
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
 #[derive(Debug)]
 pub enum HandleError {
     WrongCredentials,
     MissingCredentials,
     NotFound(String),
     BadRequest(String),
+    TooManyRequests(String),
     ServerError(String),
 }
 
@@ -28,6 +50,7 @@ impl IntoResponse for HandleError {
             }
             HandleError::NotFound(s) => (StatusCode::NOT_FOUND, s),
             HandleError::BadRequest(s) => (StatusCode::BAD_REQUEST, s),
+            HandleError::TooManyRequests(s) => (StatusCode::TOO_MANY_REQUESTS, s),
             HandleError::ServerError(s) => (StatusCode::INTERNAL_SERVER_ERROR, s),
         };
         let body = Json(json!({
@@ -37,3 +60,19 @@ impl IntoResponse for HandleError {
         (status, body).into_response()
     }
 }
+
+#[derive(Debug, Deserialize)]
+pub struct Paging {
+    limit: Option<i64>,
+    skip: Option<u64>,
+}
+
+impl Paging {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    pub fn skip(&self) -> u64 {
+        self.skip.unwrap_or(0)
+    }
+}
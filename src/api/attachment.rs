@@ -0,0 +1,227 @@
+use axum::{
+    Extension, Json,
+    extract::{Multipart, Path, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use socketioxide::SocketIo;
+use tracing::{error, info};
+
+use crate::{
+    db::{Attachment, AttachmentDoc, DbState, OidDec, encode_oid},
+    media::{Kind, process},
+};
+
+use super::{
+    HandleError,
+    auth::Claims,
+    post::{authored_post, publish},
+};
+
+// Read the `file` field into memory, refusing it once it grows past the limit.
+async fn receive_file(
+    db_state: &DbState,
+    multipart: &mut Multipart,
+) -> Result<Vec<u8>, HandleError> {
+    let bad = |e: axum::extract::multipart::MultipartError| HandleError::BadRequest(e.to_string());
+    let max_size = db_state.attachments_max_size();
+    while let Some(mut field) = multipart.next_field().await.map_err(bad)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let mut data = Vec::new();
+        while let Some(chunk) = field.chunk().await.map_err(bad)? {
+            if data.len() + chunk.len() > max_size {
+                return Err(HandleError::BadRequest(format!(
+                    "File larger than {max_size} bytes"
+                )));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        return Ok(data);
+    }
+    Err(HandleError::BadRequest("Missing file".to_string()))
+}
+
+/// Process and store an upload, reusing the stored copy of identical files.
+async fn store_upload(
+    db_state: &DbState,
+    uploader: ObjectId,
+    multipart: &mut Multipart,
+) -> Result<AttachmentDoc, HandleError> {
+    if db_state.max_attachments() == 0 {
+        return Err(HandleError::BadRequest(
+            "Attachments are disabled".to_string(),
+        ));
+    }
+    let data = receive_file(db_state, multipart).await?;
+    let thumb_size = db_state.thumb_size();
+    let file = tokio::task::spawn_blocking(move || process(data, thumb_size))
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?
+        .map_err(HandleError::BadRequest)?;
+
+    let existing = db_state
+        .find_attachment(&file.hash)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let storage = db_state.storage();
+    let store_err =
+        |e: std::io::Error| HandleError::ServerError(format!("Failed to store file: {e}"));
+    if let Some(att) = existing {
+        // Put the file back if it went missing from storage since.
+        if !storage.exists(&att.key).await.map_err(store_err)? {
+            storage.put(&att.key, &file.data).await.map_err(store_err)?;
+        }
+        return Ok(att);
+    }
+
+    let key = format!("{}.{}", file.hash, file.kind.ext());
+    storage.put(&key, &file.data).await.map_err(store_err)?;
+    let (thumb, thumb_kind) = &file.thumbnail;
+    let thumb_key = format!("{}.thumb.{}", file.hash, thumb_kind.ext());
+    storage.put(&thumb_key, thumb).await.map_err(store_err)?;
+
+    let att = AttachmentDoc {
+        oid: ObjectId::new(),
+        hash: file.hash,
+        key,
+        mime: file.kind.mime().to_string(),
+        size: file.data.len() as i64,
+        width: Some(file.dimensions.0),
+        height: Some(file.dimensions.1),
+        thumbnail: Some(thumb_key),
+        uploader,
+        created_at: DateTime::now(),
+    };
+    info!("Attachment stored: {} ({} bytes)", att.key, att.size);
+    db_state
+        .add_attachment(att)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))
+}
+
+pub async fn attach_topic(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    OidDec(tid): OidDec,
+    mut multipart: Multipart,
+) -> Result<Json<AttachmentsPayload>, HandleError> {
+    let uploader = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let topic = db_state
+        .get_topic(tid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Topic not found: {err}")))?;
+    if topic.author != uploader {
+        return Err(HandleError::NotFound("Topic not found".to_string()));
+    }
+    let att = store_upload(&db_state, uploader, &mut multipart).await?;
+    let topic = db_state
+        .attach_to_topic(tid, &Attachment::from(&att), db_state.max_attachments())
+        .await
+        .map_err(|e| HandleError::BadRequest(e.to_string()))?;
+    Ok(Json(AttachmentsPayload::new(topic.attachments)))
+}
+
+pub async fn attach_post(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    Path((tid, pid)): Path<(String, String)>,
+    mut multipart: Multipart,
+) -> Result<Json<AttachmentsPayload>, HandleError> {
+    let post = authored_post(&db_state, &claims, &tid, &pid).await?;
+    let att = store_upload(&db_state, post.author, &mut multipart).await?;
+    let post = db_state
+        .attach_to_post(
+            post.oid,
+            &Attachment::from(&att),
+            db_state.max_attachments(),
+        )
+        .await
+        .map_err(|e| HandleError::BadRequest(e.to_string()))?;
+    publish(&io, "postUpdated", &post).await;
+    Ok(Json(AttachmentsPayload::new(post.attachments)))
+}
+
+// `<sha256>.<ext>` or `<sha256>.thumb.<ext>`, the only names uploads are stored under.
+fn file_kind(key: &str) -> Option<Kind> {
+    let (name, ext) = key.rsplit_once('.')?;
+    let hash = name.strip_suffix(".thumb").unwrap_or(name);
+    let valid = hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    valid.then(|| Kind::from_ext(ext)).flatten()
+}
+
+/// Content addressed, so a URL always serves the same bytes.
+pub async fn serve_file(
+    State(db_state): State<DbState>,
+    Path(key): Path<String>,
+) -> Result<Response, HandleError> {
+    let kind = file_kind(&key).ok_or(HandleError::NotFound("File not found".to_string()))?;
+    let data = db_state.storage().get(&key).await.map_err(|e| {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            error!("Failed to read {key}: {e}");
+        }
+        HandleError::NotFound("File not found".to_string())
+    })?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, kind.mime()),
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                "default-src 'none'; sandbox",
+            ),
+            (header::CONTENT_DISPOSITION, "inline"),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachmentInfo {
+    aid: String,
+    url: String,
+    thumbnail_url: Option<String>,
+    mime: String,
+    size: i64,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+impl From<Attachment> for AttachmentInfo {
+    fn from(a: Attachment) -> Self {
+        Self {
+            aid: encode_oid(a.aid),
+            url: format!("/files/{}", a.key),
+            thumbnail_url: a.thumbnail.map(|t| format!("/files/{t}")),
+            mime: a.mime,
+            size: a.size,
+            width: a.width,
+            height: a.height,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttachmentsPayload {
+    success: bool,
+    message: String,
+    attachments: Vec<AttachmentInfo>,
+}
+
+impl AttachmentsPayload {
+    fn new(attachments: Vec<Attachment>) -> Self {
+        Self {
+            success: true,
+            message: "Attachment added".to_string(),
+            attachments: attachments.into_iter().map(AttachmentInfo::from).collect(),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::db::{AuditEvent, AuditKind, DbState, encode_oid};
+
+use super::{HandleError, Paging, auth::Claims, device::owned_device};
+
+pub async fn device_events(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(devid): Path<String>,
+    Query(paging): Query<Paging>,
+) -> Result<Json<EventsPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+    let events = db_state
+        .device_events(&devid, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(EventsPayload::new(events)))
+}
+
+pub async fn user_events(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Query(paging): Query<Paging>,
+) -> Result<Json<EventsPayload>, HandleError> {
+    let uid = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let events = db_state
+        .user_events(uid, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(EventsPayload::new(events)))
+}
+
+#[derive(Debug, Serialize)]
+struct Event {
+    eid: String,
+    ts: i64,
+    kind: AuditKind,
+    devid: String,
+    sid: String,
+    user: Option<String>,
+    detail: Option<Value>,
+}
+
+impl From<AuditEvent> for Event {
+    fn from(e: AuditEvent) -> Self {
+        Self {
+            eid: encode_oid(e.oid),
+            ts: e.ts.timestamp_millis(),
+            kind: e.kind,
+            devid: e.devid,
+            sid: e.sid,
+            user: e.user,
+            detail: e.detail,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventsPayload {
+    success: bool,
+    message: String,
+    events: Vec<Event>,
+}
+
+impl EventsPayload {
+    fn new(events: Vec<AuditEvent>) -> Self {
+        Self {
+            success: true,
+            message: "Events queried".to_string(),
+            events: events.into_iter().map(Event::from).collect(),
+        }
+    }
+}
@@ -11,7 +11,7 @@ use chrono::Utc;
 use email_address::EmailAddress;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use mongodb::bson::oid::ObjectId;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::{fmt::Display, str::FromStr};
 // use tracing::{error, info};
 
@@ -28,14 +28,8 @@ pub async fn register(
         return Err(HandleError::MissingCredentials);
     }
     let email = match payload.email {
-        Some(m) => {
-            if EmailAddress::is_valid(&m) {
-                m
-            } else {
-                return Err(HandleError::MissingCredentials);
-            }
-        }
-        None => return Err(HandleError::MissingCredentials),
+        Some(m) if EmailAddress::is_valid(&m) => m,
+        _ => return Err(HandleError::MissingCredentials),
     };
 
     let useroid = db_state
@@ -116,6 +110,14 @@ pub struct Claims {
     exp: i64,
 }
 
+/// Credentials handed to a box once it has been claimed by a user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceClaims {
+    devid: String,
+    owner: String,
+    exp: i64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AuthBody {
     success: bool,
@@ -145,6 +147,31 @@ impl Display for Claims {
         write!(f, "User: {}", self.user)
     }
 }
+async fn bearer_claims<T: DeserializeOwned>(parts: &mut Parts) -> Result<T, HandleError> {
+    // Extract the token from the authorization header
+    let TypedHeader(Authorization(bearer)) = parts
+        .extract::<TypedHeader<Authorization<Bearer>>>()
+        .await
+        .map_err(|_| HandleError::BadRequest("Invalid token".to_string()))?;
+
+    let Extension(db_state) = parts
+        .extract::<Extension<DbState>>()
+        .await
+        .map_err(|_| HandleError::ServerError("Extension rejected".to_string()))?;
+
+    let secret = db_state.secret().ok_or(HandleError::ServerError(
+        "Secret not found in config".to_string(),
+    ))?;
+    decode_claims(bearer.token(), &secret)
+}
+
+fn decode_claims<T: DeserializeOwned>(token: &str, secret: &str) -> Result<T, HandleError> {
+    let keys = Keys::new(secret.as_bytes());
+    let token_data = decode::<T>(token, &keys.decoding, &Validation::default())
+        .map_err(|_| HandleError::BadRequest("Invalid token".to_string()))?;
+    Ok(token_data.claims)
+}
+
 impl<S> FromRequestParts<S> for Claims
 where
     S: Send + Sync,
@@ -152,31 +179,26 @@ where
     type Rejection = HandleError;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        // Extract the token from the authorization header
-        let TypedHeader(Authorization(bearer)) = parts
-            .extract::<TypedHeader<Authorization<Bearer>>>()
-            .await
-            .map_err(|_| HandleError::BadRequest("Invalid token".to_string()))?;
-
-        let Extension(db_state) = parts
-            .extract::<Extension<DbState>>()
-            .await
-            .map_err(|_| HandleError::ServerError("Extension rejected".to_string()))?;
-
-        let secret = db_state.secret().ok_or(HandleError::ServerError(
-            "Secret not found in config".to_string(),
-        ))?;
-        let keys = Keys::new(secret.as_bytes());
+        bearer_claims(parts).await
+    }
+}
 
-        // Decode the user data
-        let token_data = decode::<Claims>(bearer.token(), &keys.decoding, &Validation::default())
-            .map_err(|_| HandleError::BadRequest("Invalid token".to_string()))?;
+impl<S> FromRequestParts<S> for DeviceClaims
+where
+    S: Send + Sync,
+{
+    type Rejection = HandleError;
 
-        Ok(token_data.claims)
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        bearer_claims(parts).await
     }
 }
 
 impl Claims {
+    pub fn verify(token: &str, secret: &str) -> Result<Self, HandleError> {
+        decode_claims(token, secret)
+    }
+
     pub fn getuser(&self) -> String {
         self.user.clone()
     }
@@ -186,6 +208,31 @@ impl Claims {
     }
 }
 
+impl DeviceClaims {
+    pub fn new(devid: &str, owner: &ObjectId) -> Self {
+        Self {
+            devid: devid.to_owned(),
+            owner: owner.to_string(),
+            // Boxes are expected to run unattended, so their tokens live longer.
+            exp: Utc::now().timestamp() + 365 * 24 * 3600,
+        }
+    }
+
+    pub fn verify(token: &str, secret: &str) -> Result<Self, HandleError> {
+        decode_claims(token, secret)
+    }
+
+    pub fn devid(&self) -> String {
+        self.devid.clone()
+    }
+
+    pub fn token(&self, secret: &str) -> Result<String, HandleError> {
+        let keys = Keys::new(secret.as_bytes());
+        encode(&Header::default(), self, &keys.encoding)
+            .map_err(|_| HandleError::ServerError("Token creation failed".to_string()))
+    }
+}
+
 impl AuthBody {
     pub fn new(access_token: String) -> Self {
         Self {
@@ -0,0 +1,111 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use socketioxide::SocketIo;
+
+use crate::{
+    db::{ConfigRevision, DbState},
+    socketio::{OnlineDevs, device_socket, push_config},
+};
+
+use super::{HandleError, Paging, auth::Claims, device::owned_device};
+
+pub async fn get_config(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(devid): Path<String>,
+) -> Result<Json<ConfigPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+    let rev = db_state
+        .latest_box_config(&devid)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?
+        .ok_or(HandleError::NotFound("No config stored".to_string()))?;
+    Ok(Json(ConfigPayload::new(vec![rev])))
+}
+
+pub async fn config_history(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(devid): Path<String>,
+    Query(paging): Query<Paging>,
+) -> Result<Json<ConfigPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+    let revs = db_state
+        .box_config_history(&devid, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(ConfigPayload::new(revs)))
+}
+
+pub async fn put_config(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    Extension(onlinedevs): Extension<OnlineDevs>,
+    claims: Claims,
+    Path(devid): Path<String>,
+    Json(payload): Json<ConfigBody>,
+) -> Result<Json<ConfigPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+    let rev = db_state
+        .save_box_config(&devid, payload.schema, payload.config, &claims.getuser())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+
+    // Offline boxes pick the revision up on their next `find`.
+    if let Some(camid) = onlinedevs.getcamid(&devid).await
+        && let Some(dev) = device_socket(&io, camid)
+    {
+        tokio::spawn(push_config(dev, rev.clone(), db_state.clone()));
+    }
+    Ok(Json(ConfigPayload::new(vec![rev])))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfigBody {
+    schema: i32,
+    config: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Revision {
+    revision: i64,
+    schema: i32,
+    config: Value,
+    author: String,
+    created_at: i64,
+    applied_at: Option<i64>,
+}
+
+impl From<ConfigRevision> for Revision {
+    fn from(r: ConfigRevision) -> Self {
+        Self {
+            revision: r.revision,
+            schema: r.schema,
+            config: r.config,
+            author: r.author,
+            created_at: r.created_at.timestamp_millis(),
+            applied_at: r.applied_at.map(|t| t.timestamp_millis()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigPayload {
+    success: bool,
+    message: String,
+    revisions: Vec<Revision>,
+}
+
+impl ConfigPayload {
+    fn new(revs: Vec<ConfigRevision>) -> Self {
+        Self {
+            success: true,
+            message: "Config queried".to_string(),
+            revisions: revs.into_iter().map(Revision::from).collect(),
+        }
+    }
+}
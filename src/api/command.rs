@@ -0,0 +1,144 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use socketioxide::SocketIo;
+
+use crate::{
+    db::{CommandDoc, CommandStatus, DbState, decode_oid, encode_oid},
+    socketio::{OnlineDevs, deliver_commands, device_socket},
+};
+
+use super::{HandleError, Paging, auth::Claims, device::owned_device};
+
+const DEFAULT_TTL: u64 = 24 * 3600;
+const MAX_TTL: u64 = 30 * 24 * 3600;
+
+pub async fn queue_command(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    Extension(onlinedevs): Extension<OnlineDevs>,
+    claims: Claims,
+    Path(devid): Path<String>,
+    Json(payload): Json<CommandBody>,
+) -> Result<Json<CommandsPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+    if payload.kind.is_empty() {
+        return Err(HandleError::BadRequest("Missing command type".to_string()));
+    }
+    let ttl = payload.ttl.unwrap_or(DEFAULT_TTL);
+    if ttl > MAX_TTL {
+        return Err(HandleError::BadRequest(format!(
+            "TTL must not exceed {MAX_TTL} seconds"
+        )));
+    }
+    let cmd = db_state
+        .queue_command(
+            &devid,
+            &payload.kind,
+            payload.payload.unwrap_or(Value::Null),
+            payload.priority.unwrap_or(0),
+            ttl,
+            &claims.getuser(),
+        )
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+
+    // Online boxes get it right away, the rest on their next `find`.
+    if let Some(camid) = onlinedevs.getcamid(&devid).await
+        && let Some(dev) = device_socket(&io, camid)
+    {
+        tokio::spawn(deliver_commands(dev, devid, db_state.clone()));
+    }
+    Ok(Json(CommandsPayload::new(vec![cmd])))
+}
+
+pub async fn list_commands(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(devid): Path<String>,
+    Query(paging): Query<Paging>,
+) -> Result<Json<CommandsPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+    let cmds = db_state
+        .device_commands(&devid, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(CommandsPayload::new(cmds)))
+}
+
+pub async fn command_status(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path((devid, cid)): Path<(String, String)>,
+) -> Result<Json<CommandsPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+    let cid = decode_oid(&cid).ok_or(HandleError::NotFound("Invalid path".to_string()))?;
+    let cmd = db_state
+        .get_command(&devid, cid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Command not found: {err}")))?;
+    Ok(Json(CommandsPayload::new(vec![cmd])))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommandBody {
+    #[serde(rename = "type")]
+    kind: String,
+    payload: Option<Value>,
+    priority: Option<i32>,
+    ttl: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Command {
+    cid: String,
+    #[serde(rename = "type")]
+    kind: String,
+    payload: Value,
+    priority: i32,
+    status: CommandStatus,
+    author: String,
+    result: Option<Value>,
+    created_at: i64,
+    expires_at: i64,
+    delivered_at: Option<i64>,
+    acked_at: Option<i64>,
+}
+
+impl From<CommandDoc> for Command {
+    fn from(c: CommandDoc) -> Self {
+        Self {
+            cid: encode_oid(c.oid),
+            kind: c.kind,
+            payload: c.payload,
+            priority: c.priority,
+            status: c.status,
+            author: c.author,
+            result: c.result,
+            created_at: c.created_at.timestamp_millis(),
+            expires_at: c.expires_at.timestamp_millis(),
+            delivered_at: c.delivered_at.map(|t| t.timestamp_millis()),
+            acked_at: c.acked_at.map(|t| t.timestamp_millis()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommandsPayload {
+    success: bool,
+    message: String,
+    commands: Vec<Command>,
+}
+
+impl CommandsPayload {
+    fn new(cmds: Vec<CommandDoc>) -> Self {
+        Self {
+            success: true,
+            message: "Commands queried".to_string(),
+            commands: cmds.into_iter().map(Command::from).collect(),
+        }
+    }
+}
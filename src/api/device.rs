@@ -0,0 +1,199 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use socketioxide::SocketIo;
+use tracing::info;
+
+use crate::{
+    db::{DbState, DeviceDoc},
+    socketio::{OnlineDevs, device_socket},
+};
+
+use super::{
+    HandleError,
+    auth::{Claims, DeviceClaims},
+};
+
+/// Look up a claimed device, failing unless it belongs to the caller.
+pub async fn owned_device(
+    db_state: &DbState,
+    claims: &Claims,
+    devid: &str,
+) -> Result<DeviceDoc, HandleError> {
+    let owner = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let device = db_state
+        .get_device(devid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Device not found: {err}")))?;
+    // Don't leak which device ids exist to other accounts.
+    if device.owner != owner {
+        return Err(HandleError::NotFound("Device not found".to_string()));
+    }
+    Ok(device)
+}
+
+pub async fn claim(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    Extension(onlinedevs): Extension<OnlineDevs>,
+    claims: Claims,
+    Json(payload): Json<ClaimPayload>,
+) -> Result<Json<ClaimBody>, HandleError> {
+    let owner = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    if payload.code.is_empty() {
+        return Err(HandleError::BadRequest("Missing pairing code".to_string()));
+    }
+
+    let user = owner.to_string();
+    if !onlinedevs.may_claim(&user).await {
+        return Err(HandleError::TooManyRequests(
+            "Too many pairing attempts, try again later".to_string(),
+        ));
+    }
+
+    let Some(pairing) = onlinedevs.claim(&payload.code).await else {
+        onlinedevs.claim_failed(&user).await;
+        return Err(HandleError::NotFound("Invalid pairing code".to_string()));
+    };
+    let socket = device_socket(&io, pairing.sid).ok_or(HandleError::NotFound(
+        "Box is no longer connected".to_string(),
+    ))?;
+
+    let name = payload.name.unwrap_or(pairing.device.to_owned());
+    let device = db_state
+        .bind_device(&owner, &name)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+
+    let secret = db_state.secret().ok_or(HandleError::ServerError(
+        "Secret not found in config".to_string(),
+    ))?;
+    let token = DeviceClaims::new(&device.devid, &owner).token(&secret)?;
+
+    // A box that never got its credentials can't use the binding, so undo it
+    // and keep the code valid for another try.
+    if let Err(e) = socket.emit("bound", &json!({"devid": device.devid, "token": token})) {
+        db_state
+            .unbind_device(&device.devid)
+            .await
+            .map_err(|e| HandleError::ServerError(e.to_string()))?;
+        onlinedevs.restore(&payload.code, pairing).await;
+        return Err(HandleError::ServerError(format!(
+            "Failed to notify box, claim undone: {e}"
+        )));
+    }
+
+    // The box is expected to `find` again under its permanent id.
+    onlinedevs.remove(&pairing.sid).await;
+    onlinedevs.speaker_off(&pairing.device).await;
+    info!(
+        "{} claimed {} as {}",
+        claims.getuser(),
+        pairing.device,
+        device.devid
+    );
+
+    Ok(Json(ClaimBody {
+        success: true,
+        message: "Device claimed".to_string(),
+        devid: device.devid,
+        name: device.name,
+    }))
+}
+
+// Account ids of `names`, failing on the first name nobody goes by.
+async fn user_ids(db_state: &DbState, names: &[String]) -> Result<Vec<ObjectId>, HandleError> {
+    let users = db_state
+        .find_users(names)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    names
+        .iter()
+        .map(|n| {
+            users
+                .iter()
+                .find(|u| &u.name == n)
+                .map(|u| u.uid())
+                .ok_or(HandleError::BadRequest(format!("Unknown user: {n}")))
+        })
+        .collect()
+}
+
+/// Grant or revoke watching a device for other users.
+pub async fn update_viewers(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(devid): Path<String>,
+    Json(payload): Json<ViewersBody>,
+) -> Result<Json<ViewersPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+    let (add, remove) = payload.resolve(&db_state).await?;
+    let device = db_state
+        .update_device_viewers(&devid, &add, &remove)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    ViewersPayload::new(&db_state, &device.viewers)
+        .await
+        .map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ViewersBody {
+    add: Option<Vec<String>>,
+    remove: Option<Vec<String>>,
+}
+
+impl ViewersBody {
+    /// Account ids to grant and to revoke.
+    pub async fn resolve(
+        self,
+        db_state: &DbState,
+    ) -> Result<(Vec<ObjectId>, Vec<ObjectId>), HandleError> {
+        let add = user_ids(db_state, &self.add.unwrap_or_default()).await?;
+        let remove = user_ids(db_state, &self.remove.unwrap_or_default()).await?;
+        Ok((add, remove))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ViewersPayload {
+    success: bool,
+    message: String,
+    viewers: Vec<String>,
+}
+
+impl ViewersPayload {
+    pub async fn new(db_state: &DbState, uids: &[ObjectId]) -> Result<Self, HandleError> {
+        let users = db_state
+            .get_users(uids)
+            .await
+            .map_err(|e| HandleError::ServerError(e.to_string()))?;
+        Ok(Self {
+            success: true,
+            message: "Viewers updated".to_string(),
+            viewers: users.into_iter().map(|u| u.name).collect(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClaimPayload {
+    code: String,
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClaimBody {
+    success: bool,
+    message: String,
+    devid: String,
+    name: String,
+}
@@ -1,19 +1,47 @@
-use axum::{Json, extract::State};
+use axum::{
+    Json,
+    extract::{Query, State},
+};
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 
-use crate::db::{DbState, OidDec, encode_oid};
+use tracing::error;
 
-use super::HandleError;
+use crate::{
+    db::{DbState, OidDec, encode_oid},
+    markup::render,
+};
+
+use super::{HandleError, Paging, attachment::AttachmentInfo, auth::Claims, vote::CountsInfo};
 
 pub async fn topic(
     State(db_state): State<DbState>,
+    claims: Result<Claims, HandleError>,
     OidDec(tid): OidDec,
 ) -> Result<Json<TopicPayload>, HandleError> {
     let d = db_state
         .get_topic(tid)
         .await
         .map_err(|err| HandleError::NotFound(format!("Topic not found: {err}")))?;
+    // Topics of shadowbanned users only exist for themselves.
+    let viewer = claims.ok().and_then(|c| c.userid());
+    let hidden = db_state
+        .shadowbanned_users(viewer)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    if hidden.contains(&d.author) {
+        return Err(HandleError::NotFound("Topic not found".to_string()));
+    }
+    let html = match d.rendered {
+        Some(r) if r.is_current() => r.html,
+        _ => {
+            let rendered = render(&d.content);
+            if let Err(e) = db_state.set_topic_rendered(tid, &rendered).await {
+                error!("Failed to cache rendered topic {tid}: {e}");
+            }
+            rendered.html
+        }
+    };
 
     let u = db_state
         .get_user(d.author)
@@ -28,14 +56,74 @@ pub async fn topic(
             email: u.email,
             name: u.name,
         },
-        channel: Channel::new(d.channel),
+        channel: Channel::new(
+            d.channel,
+            db_state.allowed_tags(d.channel),
+            db_state.reactions(d.channel),
+        ),
         title: d.title,
+        tags: d.tags,
         content: d.content,
+        html,
+        attachments: d
+            .attachments
+            .into_iter()
+            .map(AttachmentInfo::from)
+            .collect(),
+        counts: CountsInfo::new(d.votes, d.reactions),
         created_at: d.created_at.timestamp_millis(),
     };
     Ok(Json(resp))
 }
 
+/// Newest topics of a channel, with how many posts the caller has not read yet.
+pub async fn channel_topics(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    OidDec(cid): OidDec,
+    Query(paging): Query<Paging>,
+) -> Result<Json<TopicsPayload>, HandleError> {
+    let reader = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let hidden = db_state
+        .shadowbanned_users(Some(reader))
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let docs = db_state
+        .channel_topics(cid, &hidden, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let tids: Vec<_> = docs.iter().map(|t| t.tid()).collect();
+    let markers = db_state
+        .read_markers(reader, &tids)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let unread = db_state
+        .unread_posts(&tids, reader, &markers)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let topics = docs
+        .into_iter()
+        .map(|t| {
+            let tid = t.tid();
+            TopicSummary {
+                tid: encode_oid(tid),
+                title: t.title,
+                tags: t.tags,
+                created_at: t.created_at.timestamp_millis(),
+                last_read: markers.get(&tid).copied().map(encode_oid),
+                unread: unread.get(&tid).copied().unwrap_or(0),
+            }
+        })
+        .collect();
+    Ok(Json(TopicsPayload {
+        success: true,
+        message: "Topics queried".to_string(),
+        topics,
+    }))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Author {
     uid: String,
@@ -48,14 +136,17 @@ struct Channel {
     cid: String,
     title: String,
     tags: Vec<String>,
+    reactions: Vec<String>,
 }
 
 impl Channel {
-    fn new(cid: ObjectId) -> Self {
+    /// `tags` are the ones the channel allows, empty for free-form channels.
+    fn new(cid: ObjectId, tags: Option<Vec<String>>, reactions: Vec<String>) -> Self {
         Self {
             cid: encode_oid(cid),
             title: "unset".to_string(),
-            tags: Vec::new(),
+            tags: tags.unwrap_or_default(),
+            reactions,
         }
     }
 }
@@ -68,5 +159,26 @@ pub struct TopicPayload {
     channel: Channel,
     title: String,
     content: String,
+    html: String,
+    attachments: Vec<AttachmentInfo>,
+    counts: CountsInfo,
+    tags: Vec<String>,
     created_at: i64,
 }
+
+#[derive(Debug, Serialize)]
+struct TopicSummary {
+    tid: String,
+    title: String,
+    tags: Vec<String>,
+    created_at: i64,
+    last_read: Option<String>,
+    unread: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopicsPayload {
+    success: bool,
+    message: String,
+    topics: Vec<TopicSummary>,
+}
@@ -0,0 +1,135 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+};
+use serde::Serialize;
+
+use crate::db::{ConversationDoc, DbState, MessageDoc, OidDec, encode_oid};
+
+use super::{HandleError, Paging, auth::Claims};
+
+pub async fn list_conversations(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Query(paging): Query<Paging>,
+) -> Result<Json<ConversationsPayload>, HandleError> {
+    let user = claims.getuser();
+    let reader = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let convs = db_state
+        .user_conversations(&user, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let cids: Vec<_> = convs.iter().map(|c| c.oid).collect();
+    let markers = db_state
+        .read_markers(reader, &cids)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let unread = db_state
+        .unread_messages(&cids, &user, &markers)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let conversations = convs
+        .into_iter()
+        .map(|c| {
+            let n = unread.get(&c.oid).copied().unwrap_or(0);
+            Conversation::new(c, &user, n)
+        })
+        .collect();
+    Ok(Json(ConversationsPayload {
+        success: true,
+        message: "Conversations queried".to_string(),
+        conversations,
+    }))
+}
+
+/// Newest first, page backwards with `skip`.
+pub async fn conversation_messages(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    OidDec(cid): OidDec,
+    Query(paging): Query<Paging>,
+) -> Result<Json<MessagesPayload>, HandleError> {
+    let conv = db_state
+        .get_conversation(cid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Conversation not found: {err}")))?;
+    if !conv.has(&claims.getuser()) {
+        return Err(HandleError::NotFound("Conversation not found".to_string()));
+    }
+    let msgs = db_state
+        .conversation_messages(cid, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(MessagesPayload {
+        success: true,
+        message: "Messages queried".to_string(),
+        messages: msgs.into_iter().map(DirectMessage::from).collect(),
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct DirectMessage {
+    mid: String,
+    cid: String,
+    from: String,
+    to: String,
+    body: String,
+    encrypted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    header: Option<String>,
+    created_at: i64,
+    delivered_at: Option<i64>,
+}
+
+impl From<MessageDoc> for DirectMessage {
+    fn from(m: MessageDoc) -> Self {
+        Self {
+            mid: encode_oid(m.oid),
+            cid: encode_oid(m.conversation),
+            from: m.sender,
+            to: m.recipient,
+            body: m.body,
+            encrypted: m.encrypted,
+            header: m.header,
+            created_at: m.created_at.timestamp_millis(),
+            delivered_at: m.delivered_at.map(|t| t.timestamp_millis()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Conversation {
+    cid: String,
+    peer: Option<String>,
+    created_at: i64,
+    last_message_at: Option<i64>,
+    unread: u64,
+}
+
+impl Conversation {
+    fn new(c: ConversationDoc, user: &str, unread: u64) -> Self {
+        Self {
+            cid: encode_oid(c.oid),
+            peer: c.peer(user).map(str::to_owned),
+            created_at: c.created_at.timestamp_millis(),
+            last_message_at: c.last_message_at.map(|t| t.timestamp_millis()),
+            unread,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversationsPayload {
+    success: bool,
+    message: String,
+    conversations: Vec<Conversation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagesPayload {
+    success: bool,
+    message: String,
+    messages: Vec<DirectMessage>,
+}
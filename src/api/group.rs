@@ -0,0 +1,335 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+};
+use futures_util::future::join_all;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use socketioxide::SocketIo;
+
+use crate::{
+    db::{DbState, GroupDoc, GroupKind, decode_oid, encode_oid},
+    socketio::{AckSummary, OnlineDevs, device_socket, push_config},
+};
+
+use super::{
+    HandleError,
+    auth::Claims,
+    device::{ViewersBody, ViewersPayload, owned_device},
+};
+
+fn owner_of(claims: &Claims) -> Result<ObjectId, HandleError> {
+    claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))
+}
+
+async fn owned_group(
+    db_state: &DbState,
+    claims: &Claims,
+    gid: &str,
+) -> Result<GroupDoc, HandleError> {
+    let owner = owner_of(claims)?;
+    let gid = decode_oid(gid).ok_or(HandleError::NotFound("Invalid path".to_string()))?;
+    db_state
+        .get_group(&owner, gid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Group not found: {err}")))
+}
+
+// Groups may only hold devices their owner has claimed.
+async fn check_devices(
+    db_state: &DbState,
+    claims: &Claims,
+    devices: &[String],
+) -> Result<(), HandleError> {
+    for devid in devices {
+        owned_device(db_state, claims, devid).await?;
+    }
+    Ok(())
+}
+
+pub async fn create_group(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Json(payload): Json<GroupBody>,
+) -> Result<Json<GroupsPayload>, HandleError> {
+    let owner = owner_of(&claims)?;
+    if payload.name.trim().is_empty() {
+        return Err(HandleError::BadRequest("Missing group name".to_string()));
+    }
+    let devices = payload.devices.unwrap_or_default();
+    check_devices(&db_state, &claims, &devices).await?;
+    let group = db_state
+        .create_group(&owner, payload.name.trim(), payload.kind, devices)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(GroupsPayload::new(vec![group])))
+}
+
+pub async fn list_groups(
+    State(db_state): State<DbState>,
+    claims: Claims,
+) -> Result<Json<GroupsPayload>, HandleError> {
+    let owner = owner_of(&claims)?;
+    let groups = db_state
+        .list_groups(&owner)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(GroupsPayload::new(groups)))
+}
+
+pub async fn get_group(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(gid): Path<String>,
+) -> Result<Json<GroupsPayload>, HandleError> {
+    let group = owned_group(&db_state, &claims, &gid).await?;
+    Ok(Json(GroupsPayload::new(vec![group])))
+}
+
+pub async fn update_members(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(gid): Path<String>,
+    Json(payload): Json<MembersBody>,
+) -> Result<Json<GroupsPayload>, HandleError> {
+    let owner = owner_of(&claims)?;
+    let group = owned_group(&db_state, &claims, &gid).await?;
+    let add = payload.add.unwrap_or_default();
+    let remove = payload.remove.unwrap_or_default();
+    check_devices(&db_state, &claims, &add).await?;
+    let group = db_state
+        .update_group_devices(&owner, group.oid, &add, &remove)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(GroupsPayload::new(vec![group])))
+}
+
+/// Grant or revoke watching every device of a group.
+pub async fn update_group_viewers(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(gid): Path<String>,
+    Json(payload): Json<ViewersBody>,
+) -> Result<Json<ViewersPayload>, HandleError> {
+    let owner = owner_of(&claims)?;
+    let group = owned_group(&db_state, &claims, &gid).await?;
+    let (add, remove) = payload.resolve(&db_state).await?;
+    let group = db_state
+        .update_group_viewers(&owner, group.oid, &add, &remove)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    ViewersPayload::new(&db_state, &group.viewers)
+        .await
+        .map(Json)
+}
+
+pub async fn delete_group(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(gid): Path<String>,
+) -> Result<Json<GroupsPayload>, HandleError> {
+    let owner = owner_of(&claims)?;
+    let group = owned_group(&db_state, &claims, &gid).await?;
+    db_state
+        .delete_group(&owner, group.oid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Group not found: {err}")))?;
+    Ok(Json(GroupsPayload::new(Vec::new())))
+}
+
+pub async fn presence(
+    State(db_state): State<DbState>,
+    Extension(onlinedevs): Extension<OnlineDevs>,
+    claims: Claims,
+    Path(gid): Path<String>,
+) -> Result<Json<PresencePayload>, HandleError> {
+    let group = owned_group(&db_state, &claims, &gid).await?;
+    let online = onlinedevs.val().await;
+    let (online, offline): (Vec<String>, Vec<String>) =
+        group.devices.into_iter().partition(|d| online.contains(d));
+    Ok(Json(PresencePayload {
+        success: true,
+        message: format!(
+            "{} of {} online",
+            online.len(),
+            online.len() + offline.len()
+        ),
+        online,
+        offline,
+    }))
+}
+
+pub async fn broadcast(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    Extension(onlinedevs): Extension<OnlineDevs>,
+    claims: Claims,
+    Path(gid): Path<String>,
+    Json(msg): Json<Value>,
+) -> Result<Json<BroadcastPayload>, HandleError> {
+    let group = owned_group(&db_state, &claims, &gid).await?;
+    let members = onlinedevs.camids(&group.devices).await;
+    let offline = group
+        .devices
+        .iter()
+        .filter(|d| !members.values().any(|m| &m == d))
+        .cloned()
+        .collect();
+
+    // Address the boxes themselves rather than their rooms, which also hold viewers.
+    let timeout = db_state.message_timeout(msg.get("type").and_then(Value::as_str));
+    let mut summary = AckSummary::default();
+    let mut pending = Vec::new();
+    for &sid in members.keys() {
+        match device_socket(&io, sid) {
+            Some(dev) => match dev
+                .timeout(timeout)
+                .emit_with_ack::<Value, Value>("message", &msg)
+            {
+                Ok(ack) => pending.push(async move { (sid, ack.await) }),
+                Err(e) => summary.fail(sid, e.to_string()),
+            },
+            None => summary.fail(sid, "Socket disconnected".to_string()),
+        }
+    }
+    for (sid, res) in join_all(pending).await {
+        summary.record(sid, res);
+    }
+    let timed_out = summary
+        .timeouts
+        .iter()
+        .filter_map(|sid| members.get(sid).cloned())
+        .collect();
+
+    Ok(Json(BroadcastPayload {
+        success: summary.answered(),
+        message: format!("{} of {} replied", summary.replies.len(), members.len()),
+        acks: summary.with_devices(&members),
+        timed_out,
+        offline,
+    }))
+}
+
+pub async fn push_group_config(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    Extension(onlinedevs): Extension<OnlineDevs>,
+    claims: Claims,
+    Path(gid): Path<String>,
+    Json(payload): Json<GroupConfigBody>,
+) -> Result<Json<GroupConfigPayload>, HandleError> {
+    let group = owned_group(&db_state, &claims, &gid).await?;
+    let author = claims.getuser();
+    let mut revisions = Vec::new();
+    for devid in group.devices {
+        let rev = db_state
+            .save_box_config(&devid, payload.schema, payload.config.clone(), &author)
+            .await
+            .map_err(|e| HandleError::ServerError(e.to_string()))?;
+        revisions.push(MemberRevision {
+            devid: devid.to_owned(),
+            revision: rev.revision,
+        });
+        // Offline boxes pick the revision up on their next `find`.
+        if let Some(camid) = onlinedevs.getcamid(&devid).await
+            && let Some(dev) = device_socket(&io, camid)
+        {
+            tokio::spawn(push_config(dev, rev, db_state.clone()));
+        }
+    }
+    Ok(Json(GroupConfigPayload {
+        success: true,
+        message: "Config pushed".to_string(),
+        revisions,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupBody {
+    name: String,
+    kind: GroupKind,
+    devices: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MembersBody {
+    add: Option<Vec<String>>,
+    remove: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupConfigBody {
+    schema: i32,
+    config: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Group {
+    gid: String,
+    name: String,
+    kind: GroupKind,
+    devices: Vec<String>,
+    created_at: i64,
+}
+
+impl From<GroupDoc> for Group {
+    fn from(g: GroupDoc) -> Self {
+        Self {
+            gid: encode_oid(g.oid),
+            name: g.name,
+            kind: g.kind,
+            devices: g.devices,
+            created_at: g.created_at.timestamp_millis(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupsPayload {
+    success: bool,
+    message: String,
+    groups: Vec<Group>,
+}
+
+impl GroupsPayload {
+    fn new(groups: Vec<GroupDoc>) -> Self {
+        Self {
+            success: true,
+            message: "Groups queried".to_string(),
+            groups: groups.into_iter().map(Group::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PresencePayload {
+    success: bool,
+    message: String,
+    online: Vec<String>,
+    offline: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BroadcastPayload {
+    success: bool,
+    message: String,
+    acks: AckSummary,
+    timed_out: Vec<String>,
+    offline: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MemberRevision {
+    devid: String,
+    revision: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupConfigPayload {
+    success: bool,
+    message: String,
+    revisions: Vec<MemberRevision>,
+}
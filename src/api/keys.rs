@@ -0,0 +1,199 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use socketioxide::SocketIo;
+use tracing::{error, info};
+
+use crate::{
+    db::{DbState, KeyBundle, PreKey, SignedPreKey},
+    socketio::emit_to_user,
+};
+
+use super::{HandleError, auth::Claims};
+
+// Decoded sizes, wide enough for Curve25519 / Ed25519 and P-256 encodings.
+const MAX_KEY_BYTES: usize = 65;
+const MAX_SIGNATURE_BYTES: usize = 128;
+const MAX_UPLOAD_PREKEYS: usize = 100;
+
+// Keys are only checked to be base64 of a sane size, never interpreted.
+fn valid_blob(name: &str, value: &str, max: usize) -> Result<(), HandleError> {
+    match BASE64_STANDARD.decode(value) {
+        Ok(b) if !b.is_empty() && b.len() <= max => Ok(()),
+        _ => Err(HandleError::BadRequest(format!(
+            "{name} must be base64 of 1 to {max} bytes"
+        ))),
+    }
+}
+
+fn valid_prekeys(prekeys: &[PreKey]) -> Result<(), HandleError> {
+    if prekeys.len() > MAX_UPLOAD_PREKEYS {
+        return Err(HandleError::BadRequest(format!(
+            "At most {MAX_UPLOAD_PREKEYS} prekeys per upload"
+        )));
+    }
+    prekeys
+        .iter()
+        .try_for_each(|k| valid_blob("Prekey", &k.key, MAX_KEY_BYTES))
+}
+
+pub async fn publish_keys(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    Json(payload): Json<PublishKeys>,
+) -> Result<Json<KeysPayload>, HandleError> {
+    let user = claims.getuser();
+    valid_blob("Identity key", &payload.identity_key, MAX_KEY_BYTES)?;
+    valid_blob("Signed prekey", &payload.signed_prekey.key, MAX_KEY_BYTES)?;
+    valid_blob(
+        "Signature",
+        &payload.signed_prekey.signature,
+        MAX_SIGNATURE_BYTES,
+    )?;
+    valid_prekeys(&payload.one_time_prekeys)?;
+
+    let changed = db_state
+        .publish_keys(
+            &user,
+            &payload.identity_key,
+            &payload.signed_prekey,
+            &payload.one_time_prekeys,
+        )
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    if changed {
+        info!("Identity key of {user} changed");
+        notify_key_change(&io, &db_state, &user, &payload.identity_key).await;
+    }
+    let remaining = db_state
+        .prekey_count(&user)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(KeysPayload {
+        success: true,
+        message: "Keys published".to_string(),
+        prekeys: remaining,
+    }))
+}
+
+pub async fn add_prekeys(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Json(prekeys): Json<Vec<PreKey>>,
+) -> Result<Json<KeysPayload>, HandleError> {
+    valid_prekeys(&prekeys)?;
+    let remaining = db_state
+        .add_prekeys(&claims.getuser(), &prekeys)
+        .await
+        .map_err(|e| HandleError::BadRequest(e.to_string()))?;
+    Ok(Json(KeysPayload {
+        success: true,
+        message: "Prekeys added".to_string(),
+        prekeys: remaining,
+    }))
+}
+
+/// How many one-time prekeys of the caller are left, so clients know when to refill.
+pub async fn prekey_count(
+    State(db_state): State<DbState>,
+    claims: Claims,
+) -> Result<Json<KeysPayload>, HandleError> {
+    let remaining = db_state
+        .prekey_count(&claims.getuser())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(KeysPayload {
+        success: true,
+        message: "Prekeys counted".to_string(),
+        prekeys: remaining,
+    }))
+}
+
+/// Bundle for starting a session with `user`, consuming one of their one-time prekeys.
+/// A POST since it changes state, and rate-limited so nobody can drain the prekeys.
+pub async fn key_bundle(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(user): Path<String>,
+) -> Result<Json<BundlePayload>, HandleError> {
+    if !db_state.may_fetch_bundle(&claims.getuser(), &user) {
+        return Err(HandleError::TooManyRequests(
+            "Too many key bundle requests".to_string(),
+        ));
+    }
+    let bundle = db_state
+        .claim_key_bundle(&user)
+        .await
+        .map_err(|e| HandleError::NotFound(format!("Keys not found: {e}")))?;
+    Ok(Json(BundlePayload {
+        success: true,
+        message: "Key bundle queried".to_string(),
+        bundle: Bundle::from(bundle),
+    }))
+}
+
+// Warn everyone `user` has a conversation with, so they re-verify before sending.
+async fn notify_key_change(io: &SocketIo, db_state: &DbState, user: &str, identity_key: &str) {
+    let peers = match db_state.conversation_peers(user).await {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Failed to load conversation peers of {user}: {e}");
+            return;
+        }
+    };
+    let warning = json!({"user": user, "identityKey": identity_key});
+    for peer in peers {
+        emit_to_user(io, &peer, "keyChange", &warning).await;
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublishKeys {
+    #[serde(rename = "identityKey")]
+    identity_key: String,
+    #[serde(rename = "signedPreKey")]
+    signed_prekey: SignedPreKey,
+    #[serde(rename = "oneTimePreKeys", default)]
+    one_time_prekeys: Vec<PreKey>,
+}
+
+#[derive(Debug, Serialize)]
+struct Bundle {
+    user: String,
+    identity_key: String,
+    signed_prekey: SignedPreKey,
+    /// Absent once the user ran out of one-time prekeys.
+    one_time_prekey: Option<PreKey>,
+    identity_changed_at: i64,
+}
+
+impl From<KeyBundle> for Bundle {
+    fn from(b: KeyBundle) -> Self {
+        Self {
+            user: b.user,
+            identity_key: b.identity_key,
+            signed_prekey: b.signed_prekey,
+            one_time_prekey: b.one_time_prekeys.into_iter().next(),
+            identity_changed_at: b.identity_changed_at.timestamp_millis(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeysPayload {
+    success: bool,
+    message: String,
+    prekeys: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BundlePayload {
+    success: bool,
+    message: String,
+    bundle: Bundle,
+}
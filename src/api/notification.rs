@@ -0,0 +1,157 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use socketioxide::SocketIo;
+use std::collections::HashMap;
+use tracing::error;
+
+use crate::{
+    db::{DbState, NotificationDoc, NotificationKind, OidDec, encode_oid},
+    socketio::emit_to_user,
+};
+
+use super::{HandleError, Paging, auth::Claims};
+
+/// Push freshly stored notifications to the signed-in sessions of their users.
+pub async fn deliver(db_state: &DbState, io: &SocketIo, notes: Vec<NotificationDoc>) {
+    let uids: Vec<_> = notes.iter().map(|n| n.user).collect();
+    let names: HashMap<_, _> = match db_state.get_users(&uids).await {
+        Ok(users) => users.into_iter().map(|u| (u.uid(), u.name)).collect(),
+        Err(e) => {
+            error!("Failed to look up notified users: {e}");
+            return;
+        }
+    };
+    for note in notes {
+        let Some(name) = names.get(&note.user) else {
+            continue;
+        };
+        // Clients predating the inbox only know `replyreminder` with the thread id.
+        if note.kind != NotificationKind::Mention {
+            emit_to_user(io, name, "replyreminder", &encode_oid(note.topic)).await;
+        }
+        emit_to_user(io, name, "notification", &Notification::from(note)).await;
+    }
+}
+
+pub async fn list_notifications(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Query(paging): Query<Paging>,
+    Query(filter): Query<NotificationFilter>,
+) -> Result<Json<NotificationsPayload>, HandleError> {
+    let user = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let notes = db_state
+        .user_notifications(
+            user,
+            filter.unread.unwrap_or_default(),
+            paging.limit(),
+            paging.skip(),
+        )
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(NotificationsPayload {
+        success: true,
+        message: "Notifications queried".to_string(),
+        notifications: notes.into_iter().map(Notification::from).collect(),
+    }))
+}
+
+pub async fn unread_count(
+    State(db_state): State<DbState>,
+    claims: Claims,
+) -> Result<Json<CountPayload>, HandleError> {
+    let user = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let count = db_state
+        .unread_notifications(user)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(CountPayload {
+        success: true,
+        message: "Unread notifications counted".to_string(),
+        count,
+    }))
+}
+
+pub async fn mark_read(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    OidDec(nid): OidDec,
+) -> Result<Json<CountPayload>, HandleError> {
+    mark(&db_state, &claims, Some(nid)).await
+}
+
+pub async fn mark_all_read(
+    State(db_state): State<DbState>,
+    claims: Claims,
+) -> Result<Json<CountPayload>, HandleError> {
+    mark(&db_state, &claims, None).await
+}
+
+async fn mark(
+    db_state: &DbState,
+    claims: &Claims,
+    nid: Option<ObjectId>,
+) -> Result<Json<CountPayload>, HandleError> {
+    let user = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let count = db_state
+        .mark_notifications_read(user, nid)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(CountPayload {
+        success: true,
+        message: "Notifications marked read".to_string(),
+        count,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationFilter {
+    unread: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct Notification {
+    nid: String,
+    kind: NotificationKind,
+    tid: String,
+    pid: String,
+    read: bool,
+    created_at: i64,
+}
+
+impl From<NotificationDoc> for Notification {
+    fn from(n: NotificationDoc) -> Self {
+        Self {
+            nid: encode_oid(n.oid),
+            kind: n.kind,
+            tid: encode_oid(n.topic),
+            pid: encode_oid(n.post),
+            read: n.read,
+            created_at: n.created_at.timestamp_millis(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotificationsPayload {
+    success: bool,
+    message: String,
+    notifications: Vec<Notification>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CountPayload {
+    success: bool,
+    message: String,
+    count: u64,
+}
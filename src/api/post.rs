@@ -0,0 +1,283 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+};
+use serde::{Deserialize, Serialize};
+use socketioxide::SocketIo;
+use std::collections::HashMap;
+use tracing::error;
+
+use crate::{
+    db::{
+        DbState, NotificationDoc, NotificationKind, OidDec, PostDoc, decode_oid, encode_oid,
+        mentions, pseudonym,
+    },
+    markup::{Rendered, render},
+    socketio::emit_to_room,
+};
+
+use super::{
+    HandleError, Paging, attachment::AttachmentInfo, auth::Claims, notification::deliver,
+    vote::CountsInfo,
+};
+
+// Cached HTML if it came from the current renderer, a fresh rendering otherwise.
+fn current_html(rendered: Option<Rendered>, content: &str) -> String {
+    match rendered {
+        Some(r) if r.is_current() => r.html,
+        _ => render(content).html,
+    }
+}
+
+const MAX_POST_LEN: usize = 10000;
+// Every quoted author is notified, so a post can't fan out to arbitrarily many.
+const MAX_QUOTES: usize = 10;
+
+fn valid_content(content: &str) -> Result<&str, HandleError> {
+    let content = content.trim();
+    if content.is_empty() || content.len() > MAX_POST_LEN {
+        return Err(HandleError::BadRequest(format!(
+            "Content must be 1 to {MAX_POST_LEN} bytes"
+        )));
+    }
+    Ok(content)
+}
+
+// Tell the live room of the topic, subscribers get the same shape as the REST reply.
+pub async fn publish(io: &SocketIo, event: &str, post: &PostDoc) {
+    let tid = encode_oid(post.topic);
+    emit_to_room(io, &tid, event, &Post::from(post.clone())).await;
+}
+
+// Load a post of the caller, decoding both path ids.
+pub async fn authored_post(
+    db_state: &DbState,
+    claims: &Claims,
+    tid: &str,
+    pid: &str,
+) -> Result<PostDoc, HandleError> {
+    let author = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let (Some(tid), Some(pid)) = (decode_oid(tid), decode_oid(pid)) else {
+        return Err(HandleError::NotFound("Invalid path".to_string()));
+    };
+    let post = db_state
+        .get_post(tid, pid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Post not found: {err}")))?;
+    if post.author != author {
+        return Err(HandleError::NotFound("Post not found".to_string()));
+    }
+    Ok(post)
+}
+
+pub async fn create_post(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    OidDec(tid): OidDec,
+    Json(payload): Json<NewPost>,
+) -> Result<Json<PostsPayload>, HandleError> {
+    let author = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let content = valid_content(&payload.content)?;
+    let topic = db_state
+        .get_topic(tid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Topic not found: {err}")))?;
+    let quotes = payload
+        .quotes
+        .unwrap_or_default()
+        .iter()
+        .map(|q| decode_oid(q).ok_or(HandleError::BadRequest(format!("Invalid quote: {q}"))))
+        .collect::<Result<Vec<_>, _>>()?;
+    if quotes.len() > MAX_QUOTES {
+        return Err(HandleError::BadRequest(format!(
+            "At most {MAX_QUOTES} posts can be quoted"
+        )));
+    }
+    let quoted = db_state
+        .find_posts(tid, &quotes)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+
+    let secret = db_state.secret().ok_or(HandleError::ServerError(
+        "Secret not found in config".to_string(),
+    ))?;
+    let post = db_state
+        .new_post(
+            tid,
+            author,
+            pseudonym(&secret, &claims.getuser(), tid),
+            content,
+            quoted.iter().map(|p| p.oid).collect(),
+        )
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    publish(&io, "postCreated", &post).await;
+
+    // One notification per user, the most specific reason wins.
+    let mut recipients = HashMap::new();
+    recipients.insert(topic.author, NotificationKind::Reply);
+    match db_state.find_users(&mentions(content)).await {
+        Ok(users) => {
+            for u in users {
+                recipients.insert(u.uid(), NotificationKind::Mention);
+            }
+        }
+        Err(e) => error!("Failed to resolve mentions: {e}"),
+    }
+    for q in &quoted {
+        recipients.insert(q.author, NotificationKind::Quote);
+    }
+    recipients.remove(&author);
+
+    let notes: Vec<_> = recipients
+        .into_iter()
+        .map(|(user, kind)| NotificationDoc::new(user, kind, tid, post.oid))
+        .collect();
+    match db_state.add_notifications(&notes).await {
+        Ok(()) => deliver(&db_state, &io, notes).await,
+        Err(e) => error!("Failed to store notifications for {}: {e}", post.oid),
+    }
+
+    Ok(Json(PostsPayload::new(vec![post])))
+}
+
+pub async fn edit_post(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    Path((tid, pid)): Path<(String, String)>,
+    Json(payload): Json<PostEdit>,
+) -> Result<Json<PostsPayload>, HandleError> {
+    let content = valid_content(&payload.content)?;
+    let post = authored_post(&db_state, &claims, &tid, &pid).await?;
+    let post = db_state
+        .edit_post(post.oid, content)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    publish(&io, "postUpdated", &post).await;
+    Ok(Json(PostsPayload::new(vec![post])))
+}
+
+pub async fn delete_post(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    Path((tid, pid)): Path<(String, String)>,
+) -> Result<Json<PostsPayload>, HandleError> {
+    let post = authored_post(&db_state, &claims, &tid, &pid).await?;
+    let post = db_state
+        .delete_post(post.oid)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    publish(&io, "postDeleted", &post).await;
+    Ok(Json(PostsPayload::new(vec![post])))
+}
+
+pub async fn list_posts(
+    State(db_state): State<DbState>,
+    claims: Result<Claims, HandleError>,
+    OidDec(tid): OidDec,
+    Query(paging): Query<Paging>,
+) -> Result<Json<PostsPayload>, HandleError> {
+    // Shadowbanned users still see their own posts.
+    let viewer = claims.ok().and_then(|c| c.userid());
+    let hidden = db_state
+        .shadowbanned_users(viewer)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let mut posts = db_state
+        .topic_posts(tid, &hidden, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    // Posts from before rendering, or from an older renderer, get their cache refreshed.
+    for p in posts
+        .iter_mut()
+        .filter(|p| !p.rendered.as_ref().is_some_and(Rendered::is_current))
+    {
+        let rendered = render(&p.content);
+        if let Err(e) = db_state.set_post_rendered(p.oid, &rendered).await {
+            error!("Failed to cache rendered post {}: {e}", p.oid);
+        }
+        p.rendered = Some(rendered);
+    }
+    Ok(Json(PostsPayload::new(posts)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewPost {
+    content: String,
+    quotes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PostEdit {
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Post {
+    pid: String,
+    tid: String,
+    pseudonym: String,
+    content: String,
+    html: String,
+    quotes: Vec<String>,
+    attachments: Vec<AttachmentInfo>,
+    counts: CountsInfo,
+    created_at: i64,
+    edited_at: Option<i64>,
+    deleted: bool,
+}
+
+impl From<PostDoc> for Post {
+    fn from(mut p: PostDoc) -> Self {
+        // Deleted posts go out as tombstones, without their body.
+        if p.deleted_at.is_some() {
+            p.content.clear();
+            p.quotes.clear();
+            p.attachments.clear();
+        }
+        Self {
+            pid: encode_oid(p.oid),
+            tid: encode_oid(p.topic),
+            pseudonym: p.pseudonym,
+            html: match p.deleted_at {
+                Some(_) => String::new(),
+                None => current_html(p.rendered, &p.content),
+            },
+            content: p.content,
+            quotes: p.quotes.into_iter().map(encode_oid).collect(),
+            attachments: p
+                .attachments
+                .into_iter()
+                .map(AttachmentInfo::from)
+                .collect(),
+            counts: CountsInfo::new(p.votes, p.reactions),
+            created_at: p.created_at.timestamp_millis(),
+            edited_at: p.edited_at.map(|t| t.timestamp_millis()),
+            deleted: p.deleted_at.is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PostsPayload {
+    success: bool,
+    message: String,
+    posts: Vec<Post>,
+}
+
+impl PostsPayload {
+    fn new(posts: Vec<PostDoc>) -> Self {
+        Self {
+            success: true,
+            message: "Posts queried".to_string(),
+            posts: posts.into_iter().map(Post::from).collect(),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use axum::{Extension, Json, extract::State};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use socketioxide::{SocketIo, adapter::Adapter};
+
+use crate::{
+    db::{DbState, OidDec, ReadTarget, decode_oid, encode_oid},
+    socketio::emit_to_user,
+};
+
+use super::{HandleError, auth::Claims};
+
+pub async fn mark_topic_read(
+    db_state: &DbState,
+    reader: ObjectId,
+    tid: ObjectId,
+    pid: ObjectId,
+) -> Result<(), String> {
+    db_state
+        .get_post(tid, pid)
+        .await
+        .map_err(|e| format!("Post not found: {e}"))?;
+    db_state
+        .set_read_marker(reader, ReadTarget::Topic, tid, pid)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Advance the marker of `reader`, named `user`, and, if both sides opted in, tell
+/// the peer how far they read.
+pub async fn mark_conversation_read<A: Adapter>(
+    io: &SocketIo<A>,
+    db_state: &DbState,
+    reader: ObjectId,
+    user: &str,
+    cid: ObjectId,
+    mid: ObjectId,
+) -> Result<(), String> {
+    let conv = db_state
+        .get_conversation(cid)
+        .await
+        .map_err(|e| format!("Conversation not found: {e}"))?;
+    if !conv.has(user) {
+        return Err("Conversation not found".to_string());
+    }
+    db_state
+        .get_message(cid, mid)
+        .await
+        .map_err(|e| format!("Message not found: {e}"))?;
+    let advanced = db_state
+        .set_read_marker(reader, ReadTarget::Conversation, cid, mid)
+        .await
+        .map_err(|e| e.to_string())?;
+    let Some(peer) = conv.peer(user) else {
+        return Ok(());
+    };
+    if advanced && receipts_enabled(db_state, user, peer).await? {
+        let receipt = json!({"cid": encode_oid(cid), "user": user, "mid": encode_oid(mid)});
+        emit_to_user(io, peer, "readReceipt", &receipt).await;
+    }
+    Ok(())
+}
+
+// Receipts are off unless both users turned them on.
+async fn receipts_enabled(db_state: &DbState, a: &str, b: &str) -> Result<bool, String> {
+    let users = db_state
+        .find_users(&[a.to_owned(), b.to_owned()])
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(users.len() == 2 && users.iter().all(|u| u.read_receipts == Some(true)))
+}
+
+fn decode_last(last: &str) -> Result<ObjectId, HandleError> {
+    decode_oid(last).ok_or(HandleError::BadRequest(format!("Invalid id: {last}")))
+}
+
+pub async fn read_topic(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    OidDec(tid): OidDec,
+    Json(payload): Json<ReadUpdate>,
+) -> Result<Json<ReadPayload>, HandleError> {
+    let reader = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let last = decode_last(&payload.last)?;
+    mark_topic_read(&db_state, reader, tid, last)
+        .await
+        .map_err(HandleError::NotFound)?;
+    Ok(Json(ReadPayload::new("Topic marked read")))
+}
+
+pub async fn read_conversation(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    OidDec(cid): OidDec,
+    Json(payload): Json<ReadUpdate>,
+) -> Result<Json<ReadPayload>, HandleError> {
+    let reader = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let last = decode_last(&payload.last)?;
+    mark_conversation_read(&io, &db_state, reader, &claims.getuser(), cid, last)
+        .await
+        .map_err(HandleError::NotFound)?;
+    Ok(Json(ReadPayload::new("Conversation marked read")))
+}
+
+pub async fn set_receipts(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Json(payload): Json<ReceiptsSetting>,
+) -> Result<Json<ReadPayload>, HandleError> {
+    let user = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    db_state
+        .set_read_receipts(user, payload.enabled)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let message = if payload.enabled {
+        "Read receipts enabled"
+    } else {
+        "Read receipts disabled"
+    };
+    Ok(Json(ReadPayload::new(message)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReadUpdate {
+    last: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReceiptsSetting {
+    enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadPayload {
+    success: bool,
+    message: String,
+}
+
+impl ReadPayload {
+    fn new(message: &str) -> Self {
+        Self {
+            success: true,
+            message: message.to_string(),
+        }
+    }
+}
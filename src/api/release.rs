@@ -0,0 +1,405 @@
+use axum::{
+    Extension, Json,
+    extract::{Multipart, Path, Query, Request, State},
+    response::{IntoResponse, Response},
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use socketioxide::SocketIo;
+use std::path::PathBuf;
+use tokio::{fs, io::AsyncWriteExt};
+use tower_http::services::ServeFile;
+use tracing::info;
+
+use crate::{
+    db::{
+        DbState, OtaReport, OtaState, ReleaseDoc, ReleaseStatus, Rollout, decode_oid, encode_oid,
+    },
+    socketio::{OnlineDevs, device_socket, ota_offer},
+};
+
+use super::{
+    HandleError, Paging,
+    auth::{Claims, DeviceClaims},
+    device::owned_device,
+};
+
+fn artifact_path(db_state: &DbState, rid: &ObjectId) -> PathBuf {
+    PathBuf::from(db_state.releases_dir()).join(format!("{}.bin", rid.to_hex()))
+}
+
+async fn owned_release(
+    db_state: &DbState,
+    claims: &Claims,
+    rid: &str,
+) -> Result<ReleaseDoc, HandleError> {
+    let owner = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let rid = decode_oid(rid).ok_or(HandleError::NotFound("Invalid path".to_string()))?;
+    let rel = db_state
+        .get_release(rid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Release not found: {err}")))?;
+    if rel.owner != owner {
+        return Err(HandleError::NotFound("Release not found".to_string()));
+    }
+    Ok(rel)
+}
+
+pub async fn upload(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    mut multipart: Multipart,
+) -> Result<Json<ReleasesPayload>, HandleError> {
+    let owner = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let rid = ObjectId::new();
+    let path = artifact_path(&db_state, &rid);
+    fs::create_dir_all(db_state.releases_dir())
+        .await
+        .map_err(|e| HandleError::ServerError(format!("Failed to create releases dir: {e}")))?;
+
+    let res = receive_artifact(&db_state, &mut multipart, &path).await;
+    let (fields, checksum, size) = match res {
+        Ok(r) => r,
+        Err(e) => {
+            fs::remove_file(&path).await.ok();
+            return Err(e);
+        }
+    };
+    if let Some(expected) = fields.checksum.as_ref()
+        && !expected.eq_ignore_ascii_case(&checksum)
+    {
+        fs::remove_file(&path).await.ok();
+        return Err(HandleError::BadRequest("Checksum mismatch".to_string()));
+    }
+    let (Some(version), Some(model)) = (fields.version, fields.model) else {
+        fs::remove_file(&path).await.ok();
+        return Err(HandleError::BadRequest(
+            "Missing version or model".to_string(),
+        ));
+    };
+
+    let rel = db_state
+        .create_release(rid, &owner, &version, &model, &checksum, size as i64)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    info!("Release uploaded: {} {} ({} bytes)", model, version, size);
+    Ok(Json(ReleasesPayload::new(vec![rel])))
+}
+
+#[derive(Default)]
+struct UploadFields {
+    version: Option<String>,
+    model: Option<String>,
+    checksum: Option<String>,
+}
+
+// Stream the `file` field to disk while hashing it, collect the text fields on the way.
+async fn receive_artifact(
+    db_state: &DbState,
+    multipart: &mut Multipart,
+    path: &PathBuf,
+) -> Result<(UploadFields, String, usize), HandleError> {
+    let bad = |e: axum::extract::multipart::MultipartError| HandleError::BadRequest(e.to_string());
+    let io_err = |e: std::io::Error| HandleError::ServerError(e.to_string());
+    let max_size = db_state.releases_max_size();
+
+    let mut fields = UploadFields::default();
+    let mut artifact = None;
+    while let Some(mut field) = multipart.next_field().await.map_err(bad)? {
+        match field.name() {
+            Some("version") => fields.version = Some(field.text().await.map_err(bad)?),
+            Some("model") => fields.model = Some(field.text().await.map_err(bad)?),
+            Some("checksum") => fields.checksum = Some(field.text().await.map_err(bad)?),
+            Some("file") => {
+                let mut file = fs::File::create(path).await.map_err(io_err)?;
+                let mut hasher = Sha256::new();
+                let mut size = 0;
+                while let Some(chunk) = field.chunk().await.map_err(bad)? {
+                    size += chunk.len();
+                    if size > max_size {
+                        return Err(HandleError::BadRequest("Artifact too large".to_string()));
+                    }
+                    hasher.update(&chunk);
+                    file.write_all(&chunk).await.map_err(io_err)?;
+                }
+                file.flush().await.map_err(io_err)?;
+                artifact = Some((hex::encode(hasher.finalize()), size));
+            }
+            _ => {}
+        }
+    }
+    let (checksum, size) = artifact.ok_or(HandleError::BadRequest("Missing file".to_string()))?;
+    Ok((fields, checksum, size))
+}
+
+pub async fn list_releases(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Query(paging): Query<Paging>,
+) -> Result<Json<ReleasesPayload>, HandleError> {
+    let owner = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let releases = db_state
+        .list_releases(&owner, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(ReleasesPayload::new(releases)))
+}
+
+pub async fn get_release(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(rid): Path<String>,
+) -> Result<Json<ReleasesPayload>, HandleError> {
+    let rel = owned_release(&db_state, &claims, &rid).await?;
+    Ok(Json(ReleasesPayload::new(vec![rel])))
+}
+
+pub async fn rollout(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    Extension(onlinedevs): Extension<OnlineDevs>,
+    claims: Claims,
+    Path(rid): Path<String>,
+    Json(payload): Json<RolloutBody>,
+) -> Result<Json<ReleasesPayload>, HandleError> {
+    let rel = owned_release(&db_state, &claims, &rid).await?;
+    // Rollouts may only reach the caller's own groups and devices.
+    let mut groups = Vec::new();
+    for g in payload.groups.unwrap_or_default() {
+        let gid = decode_oid(&g).ok_or(HandleError::BadRequest(format!("Invalid group: {g}")))?;
+        db_state
+            .get_group(&rel.owner, gid)
+            .await
+            .map_err(|_| HandleError::BadRequest(format!("Unknown group: {g}")))?;
+        groups.push(gid);
+    }
+    let canary = payload.canary.unwrap_or_default();
+    for devid in &canary {
+        owned_device(&db_state, &claims, devid)
+            .await
+            .map_err(|_| HandleError::BadRequest(format!("Unknown canary device: {devid}")))?;
+    }
+    let defaults = Rollout::default();
+    let rollout = Rollout {
+        percentage: payload.percentage.unwrap_or(0).min(100),
+        groups,
+        canary,
+        failure_threshold: payload
+            .failure_threshold
+            .unwrap_or(defaults.failure_threshold)
+            .clamp(0.0, 1.0),
+        min_reports: payload.min_reports.unwrap_or(defaults.min_reports),
+    };
+    let status = if payload.active {
+        ReleaseStatus::Rolling
+    } else {
+        ReleaseStatus::Draft
+    };
+    let rel = db_state
+        .set_rollout(rel.oid, &rollout, status)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+
+    if rel.status == ReleaseStatus::Rolling {
+        notify_online(&db_state, &io, &onlinedevs, &rel).await?;
+    }
+    Ok(Json(ReleasesPayload::new(vec![rel])))
+}
+
+// Offer the release to every eligible box that is online right now.
+// The rest get it when they report their firmware on the next connect.
+async fn notify_online(
+    db_state: &DbState,
+    io: &SocketIo,
+    onlinedevs: &OnlineDevs,
+    rel: &ReleaseDoc,
+) -> Result<(), HandleError> {
+    let online: Vec<String> = onlinedevs.val().await.into_iter().collect();
+    let devices = db_state
+        .get_devices(&online)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let members = db_state
+        .group_members(&rel.owner, &rel.rollout.groups)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let offer = ota_offer(rel, &db_state.releases_base_url());
+
+    let mut offered = 0;
+    for dev in devices {
+        if dev.owner != rel.owner
+            || dev.model.as_deref() != Some(rel.model.as_str())
+            || dev.firmware.as_deref() == Some(rel.version.as_str())
+            || !rel.rollout.includes(&rel.oid, &dev.devid, &members)
+        {
+            continue;
+        }
+        if let Some(camid) = onlinedevs.getcamid(&dev.devid).await
+            && let Some(sock) = device_socket(io, camid)
+            && sock.emit("ota", &offer).is_ok()
+        {
+            offered += 1;
+        }
+    }
+    info!(
+        "Release {} offered to {} online boxes",
+        rel.version, offered
+    );
+    Ok(())
+}
+
+pub async fn reports(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(rid): Path<String>,
+) -> Result<Json<ReportsPayload>, HandleError> {
+    let rel = owned_release(&db_state, &claims, &rid).await?;
+    let reports = db_state
+        .release_reports(rel.oid)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(ReportsPayload {
+        success: true,
+        message: "Reports queried".to_string(),
+        status: rel.status,
+        reports: reports.into_iter().map(Report::from).collect(),
+    }))
+}
+
+pub async fn artifact(
+    State(db_state): State<DbState>,
+    claims: DeviceClaims,
+    Path(rid): Path<String>,
+    req: Request,
+) -> Result<Response, HandleError> {
+    let rid = decode_oid(&rid).ok_or(HandleError::NotFound("Invalid path".to_string()))?;
+    let rel = db_state
+        .get_release(rid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Release not found: {err}")))?;
+    let device = db_state
+        .get_device(&claims.devid())
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Device not found: {err}")))?;
+    // Only devices the release was offered to may fetch it.
+    let eligible = rel.status != ReleaseStatus::Draft
+        && db_state
+            .offers_release(&rel, &device)
+            .await
+            .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    if !eligible {
+        return Err(HandleError::NotFound("Release not found".to_string()));
+    }
+    let res = ServeFile::new(artifact_path(&db_state, &rel.oid))
+        .try_call(req)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(res.into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RolloutBody {
+    active: bool,
+    percentage: Option<u8>,
+    groups: Option<Vec<String>>,
+    canary: Option<Vec<String>>,
+    failure_threshold: Option<f64>,
+    min_reports: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct RolloutRules {
+    percentage: u8,
+    groups: Vec<String>,
+    canary: Vec<String>,
+    failure_threshold: f64,
+    min_reports: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct Release {
+    rid: String,
+    version: String,
+    model: String,
+    checksum: String,
+    size: i64,
+    status: ReleaseStatus,
+    rollout: RolloutRules,
+    created_at: i64,
+    halted_at: Option<i64>,
+}
+
+impl From<ReleaseDoc> for Release {
+    fn from(r: ReleaseDoc) -> Self {
+        Self {
+            rid: encode_oid(r.oid),
+            version: r.version,
+            model: r.model,
+            checksum: r.checksum,
+            size: r.size,
+            status: r.status,
+            rollout: RolloutRules {
+                percentage: r.rollout.percentage,
+                groups: r.rollout.groups.into_iter().map(encode_oid).collect(),
+                canary: r.rollout.canary,
+                failure_threshold: r.rollout.failure_threshold,
+                min_reports: r.rollout.min_reports,
+            },
+            created_at: r.created_at.timestamp_millis(),
+            halted_at: r.halted_at.map(|t| t.timestamp_millis()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReleasesPayload {
+    success: bool,
+    message: String,
+    releases: Vec<Release>,
+}
+
+impl ReleasesPayload {
+    fn new(releases: Vec<ReleaseDoc>) -> Self {
+        Self {
+            success: true,
+            message: "Releases queried".to_string(),
+            releases: releases.into_iter().map(Release::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    devid: String,
+    state: OtaState,
+    progress: Option<u8>,
+    error: Option<String>,
+    updated_at: i64,
+}
+
+impl From<OtaReport> for Report {
+    fn from(r: OtaReport) -> Self {
+        Self {
+            devid: r.devid,
+            state: r.state,
+            progress: r.progress,
+            error: r.error,
+            updated_at: r.updated_at.timestamp_millis(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportsPayload {
+    success: bool,
+    message: String,
+    status: ReleaseStatus,
+    reports: Vec<Report>,
+}
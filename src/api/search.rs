@@ -0,0 +1,269 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+};
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+use crate::db::{DbState, SearchFilter, decode_oid, encode_oid, normalize_tag};
+
+use super::{HandleError, Paging, auth::Claims};
+
+const MAX_QUERY_LEN: usize = 200;
+// Deeper pages would need every match of both collections merged in memory.
+const MAX_SEARCH_DEPTH: u64 = 1000;
+const SNIPPET_LEN: usize = 200;
+const SNIPPET_LEAD: usize = 60;
+
+/// Topics and posts matching `q`, best first unless `sort=date`.
+pub async fn search(
+    State(db_state): State<DbState>,
+    claims: Result<Claims, HandleError>,
+    Query(params): Query<SearchQuery>,
+    Query(paging): Query<Paging>,
+) -> Result<Json<SearchPayload>, HandleError> {
+    let q = params.q.trim();
+    if q.is_empty() || q.len() > MAX_QUERY_LEN {
+        return Err(HandleError::BadRequest(format!(
+            "Query must be 1 to {MAX_QUERY_LEN} bytes"
+        )));
+    }
+    let (limit, skip) = (paging.limit(), paging.skip());
+    if skip >= MAX_SEARCH_DEPTH {
+        return Err(HandleError::BadRequest(format!(
+            "Results are limited to the first {MAX_SEARCH_DEPTH}"
+        )));
+    }
+
+    let channel = match &params.channel {
+        Some(c) => {
+            Some(decode_oid(c).ok_or(HandleError::BadRequest(format!("Invalid channel: {c}")))?)
+        }
+        None => None,
+    };
+    // Shadowbanned users still find their own content.
+    let viewer = claims.ok().and_then(|c| c.userid());
+    let hidden = db_state
+        .shadowbanned_users(viewer)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let filter = SearchFilter {
+        channel,
+        pseudonym: params.pseudonym.clone(),
+        from: params.from.map(DateTime::from_millis),
+        to: params.to.map(DateTime::from_millis),
+        tags: params
+            .tags
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(normalize_tag)
+            .collect(),
+        by_date: params.sort.as_deref() == Some("date"),
+        hidden,
+    };
+
+    let window = limit + skip as i64;
+    let topics = db_state
+        .search_topics(q, &filter, window)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let posts = db_state
+        .search_posts(q, &filter, window)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+
+    let terms = terms(q);
+    let mut hits: Vec<Hit> = topics
+        .into_iter()
+        .map(|(t, score)| Hit {
+            kind: "topic",
+            tid: encode_oid(t.tid()),
+            pid: None,
+            title: Some(t.title.clone()),
+            pseudonym: None,
+            snippet: snippet(&format!("{} {}", t.title, t.content), &terms),
+            score,
+            created_at: t.created_at.timestamp_millis(),
+        })
+        .chain(posts.into_iter().map(|(p, score)| Hit {
+            kind: "post",
+            tid: encode_oid(p.topic),
+            pid: Some(encode_oid(p.oid)),
+            title: None,
+            pseudonym: Some(p.pseudonym),
+            snippet: snippet(&p.content, &terms),
+            score,
+            created_at: p.created_at.timestamp_millis(),
+        }))
+        .collect();
+    if filter.by_date {
+        hits.sort_by_key(|h| Reverse(h.created_at));
+    } else {
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    }
+    let results = hits
+        .into_iter()
+        .skip(skip as usize)
+        .take(limit as usize)
+        .collect();
+
+    Ok(Json(SearchPayload {
+        success: true,
+        message: "Search done".to_string(),
+        results,
+    }))
+}
+
+// Words of the query worth highlighting; negated terms never appear in results.
+fn terms(q: &str) -> Vec<Vec<char>> {
+    q.split_whitespace()
+        .filter(|w| !w.starts_with('-'))
+        .map(|w| w.trim_matches('"'))
+        .filter(|w| !w.is_empty())
+        .map(|w| w.chars().flat_map(char::to_lowercase).collect())
+        .collect()
+}
+
+fn lower(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+fn escape(c: char, out: &mut String) {
+    match c {
+        '&' => out.push_str("&amp;"),
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '"' => out.push_str("&quot;"),
+        '\'' => out.push_str("&#39;"),
+        _ => out.push(c),
+    }
+}
+
+/// HTML-escaped excerpt around the first match, matches wrapped in `<mark>`.
+fn snippet(text: &str, terms: &[Vec<char>]) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut marks = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let hit = terms.iter().find(|t| {
+            i + t.len() <= chars.len() && t.iter().zip(&chars[i..]).all(|(a, b)| *a == lower(*b))
+        });
+        match hit {
+            Some(t) => {
+                marks.push((i, i + t.len()));
+                i += t.len();
+            }
+            None => i += 1,
+        }
+    }
+
+    let start = marks
+        .first()
+        .map(|m| m.0.saturating_sub(SNIPPET_LEAD))
+        .unwrap_or(0);
+    let end = chars.len().min(start + SNIPPET_LEN);
+    let mut out = String::new();
+    if start > 0 {
+        out.push('…');
+    }
+    let mut open = false;
+    for (i, c) in chars.iter().enumerate().take(end).skip(start) {
+        if !open && marks.iter().any(|m| m.0 == i) {
+            out.push_str("<mark>");
+            open = true;
+        }
+        escape(*c, &mut out);
+        if open && marks.iter().any(|m| m.1 == i + 1) {
+            out.push_str("</mark>");
+            open = false;
+        }
+    }
+    if open {
+        out.push_str("</mark>");
+    }
+    if end < chars.len() {
+        out.push('…');
+    }
+    out
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    channel: Option<String>,
+    pseudonym: Option<String>,
+    /// Unix millis, inclusive.
+    from: Option<i64>,
+    to: Option<i64>,
+    /// Comma separated, results carry all of them.
+    tags: Option<String>,
+    /// `relevance` (default) or `date`.
+    sort: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Hit {
+    kind: &'static str,
+    tid: String,
+    pid: Option<String>,
+    title: Option<String>,
+    pseudonym: Option<String>,
+    snippet: String,
+    score: f64,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchPayload {
+    success: bool,
+    message: String,
+    results: Vec<Hit>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_are_marked_and_escaped() {
+        let s = snippet("Tom & <Jerry> like RUST, rust!", &terms("rust \"jerry\""));
+        assert_eq!(
+            s,
+            "Tom &amp; &lt;<mark>Jerry</mark>&gt; like <mark>RUST</mark>, <mark>rust</mark>!"
+        );
+    }
+
+    #[test]
+    fn excluded_terms_are_not_marked() {
+        assert_eq!(terms("rust -go \"\""), [vec!['r', 'u', 's', 't']]);
+        assert_eq!(
+            snippet("go rust", &terms("-go rust")),
+            "go <mark>rust</mark>"
+        );
+    }
+
+    #[test]
+    fn long_text_is_cut_around_the_first_match() {
+        let text = format!("{}needle{}", "a".repeat(500), "b".repeat(500));
+        let s = snippet(&text, &terms("needle"));
+        assert!(s.starts_with('…') && s.ends_with('…'));
+        assert!(s.contains("<mark>needle</mark>"));
+        let lead = s.find("<mark>").unwrap() - '…'.len_utf8();
+        assert_eq!(lead, SNIPPET_LEAD);
+        assert_eq!(
+            s.chars().filter(|c| *c != '…').count(),
+            SNIPPET_LEN + "<mark></mark>".len()
+        );
+    }
+
+    #[test]
+    fn no_match_starts_at_the_beginning() {
+        let text = "x".repeat(SNIPPET_LEN + 1);
+        let s = snippet(&text, &terms("zzz"));
+        assert!(!s.starts_with('…'));
+        assert!(s.ends_with('…'));
+        assert_eq!(snippet("short", &[]), "short");
+    }
+}
@@ -0,0 +1,82 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::Serialize;
+
+use crate::db::{DbState, EndReason, SessionDoc, encode_oid};
+
+use super::{HandleError, Paging, auth::Claims, device::owned_device};
+
+pub async fn device_sessions(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(devid): Path<String>,
+    Query(paging): Query<Paging>,
+) -> Result<Json<SessionsPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+    let sessions = db_state
+        .device_sessions(&devid, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(SessionsPayload::new(sessions)))
+}
+
+pub async fn user_sessions(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Query(paging): Query<Paging>,
+) -> Result<Json<SessionsPayload>, HandleError> {
+    let uid = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    let sessions = db_state
+        .user_sessions(uid, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(SessionsPayload::new(sessions)))
+}
+
+#[derive(Debug, Serialize)]
+struct Session {
+    sid: String,
+    devid: String,
+    viewer: String,
+    user: Option<String>,
+    started_at: i64,
+    ended_at: Option<i64>,
+    end_reason: Option<EndReason>,
+    speech: bool,
+}
+
+impl From<SessionDoc> for Session {
+    fn from(d: SessionDoc) -> Self {
+        Self {
+            sid: encode_oid(d.oid),
+            devid: d.devid,
+            viewer: d.viewer,
+            user: d.user,
+            started_at: d.started_at.timestamp_millis(),
+            ended_at: d.ended_at.map(|t| t.timestamp_millis()),
+            end_reason: d.end_reason,
+            speech: d.speech,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionsPayload {
+    success: bool,
+    message: String,
+    sessions: Vec<Session>,
+}
+
+impl SessionsPayload {
+    fn new(sessions: Vec<SessionDoc>) -> Self {
+        Self {
+            success: true,
+            message: "Sessions queried".to_string(),
+            sessions: sessions.into_iter().map(Session::from).collect(),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{DbState, OidDec, encode_oid, normalize_tag};
+
+use super::{HandleError, Paging, auth::Claims};
+
+/// Replace the tags of a topic. Only its author may do so.
+pub async fn set_tags(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    OidDec(tid): OidDec,
+    Json(payload): Json<TagsUpdate>,
+) -> Result<Json<TagsPayload>, HandleError> {
+    let topic = db_state
+        .get_topic(tid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Topic not found: {err}")))?;
+    if claims.userid() != Some(topic.author) {
+        return Err(HandleError::NotFound("Topic not found".to_string()));
+    }
+
+    let mut tags: Vec<String> = Vec::new();
+    for t in &payload.tags {
+        let tag = normalize_tag(t).ok_or(HandleError::BadRequest(format!("Invalid tag: {t}")))?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    let max = db_state.max_tags();
+    if tags.len() > max {
+        return Err(HandleError::BadRequest(format!(
+            "A topic takes at most {max} tags"
+        )));
+    }
+    if let Some(allowed) = db_state.allowed_tags(topic.channel) {
+        let allowed: Vec<_> = allowed.iter().filter_map(|a| normalize_tag(a)).collect();
+        if let Some(t) = tags.iter().find(|t| !allowed.contains(t)) {
+            return Err(HandleError::BadRequest(format!(
+                "Tag not allowed in this channel: {t}"
+            )));
+        }
+    }
+
+    let topic = db_state
+        .set_topic_tags(tid, &tags)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(TagsPayload {
+        success: true,
+        message: "Tags updated".to_string(),
+        tags: topic.tags,
+    }))
+}
+
+/// Newest topics carrying `tag`.
+pub async fn tagged_topics(
+    State(db_state): State<DbState>,
+    claims: Result<Claims, HandleError>,
+    Path(tag): Path<String>,
+    Query(paging): Query<Paging>,
+) -> Result<Json<TaggedPayload>, HandleError> {
+    let tag = normalize_tag(&tag).ok_or(HandleError::NotFound(format!("Invalid tag: {tag}")))?;
+    // Shadowbanned users still see their own topics.
+    let viewer = claims.ok().and_then(|c| c.userid());
+    let hidden = db_state
+        .shadowbanned_users(viewer)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    let topics = db_state
+        .tagged_topics(&tag, &hidden, paging.limit(), paging.skip())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(TaggedPayload {
+        success: true,
+        message: "Topics queried".to_string(),
+        topics: topics
+            .into_iter()
+            .map(|t| TaggedTopic {
+                tid: encode_oid(t.tid()),
+                cid: encode_oid(t.channel),
+                title: t.title,
+                tags: t.tags,
+                created_at: t.created_at.timestamp_millis(),
+            })
+            .collect(),
+    }))
+}
+
+/// Tags starting with `prefix` and how many topics use them, most used first.
+pub async fn autocomplete(
+    State(db_state): State<DbState>,
+    Query(query): Query<TagQuery>,
+    Query(paging): Query<Paging>,
+) -> Result<Json<TagCountsPayload>, HandleError> {
+    // A prefix that normalizes to nothing lists the most used tags overall.
+    let prefix = query
+        .prefix
+        .as_deref()
+        .and_then(normalize_tag)
+        .unwrap_or_default();
+    let counts = db_state
+        .tag_counts(&prefix, paging.limit())
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(TagCountsPayload {
+        success: true,
+        message: "Tags queried".to_string(),
+        tags: counts
+            .into_iter()
+            .map(|(tag, count)| TagCount { tag, count })
+            .collect(),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagsUpdate {
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagQuery {
+    prefix: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TaggedTopic {
+    tid: String,
+    cid: String,
+    title: String,
+    tags: Vec<String>,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct TagCount {
+    tag: String,
+    count: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagsPayload {
+    success: bool,
+    message: String,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaggedPayload {
+    success: bool,
+    message: String,
+    topics: Vec<TaggedTopic>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagCountsPayload {
+    success: bool,
+    message: String,
+    tags: Vec<TagCount>,
+}
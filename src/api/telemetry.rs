@@ -0,0 +1,109 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::db::{Bucket, DbState, Sample, valid_metric};
+
+use super::{
+    HandleError,
+    auth::{Claims, DeviceClaims},
+    device::owned_device,
+};
+
+const DEFAULT_BUCKET: u64 = 60;
+// Keep a single query from grouping an unbounded number of buckets.
+const MAX_BUCKETS: u64 = 10_000;
+
+pub async fn ingest(
+    State(db_state): State<DbState>,
+    claims: DeviceClaims,
+    Json(payload): Json<TelemetryBody>,
+) -> Result<Json<IngestPayload>, HandleError> {
+    let sample = Sample::new(&claims.devid(), payload.ts, payload.metrics)
+        .map_err(HandleError::BadRequest)?;
+    let stored = db_state
+        .add_sample(&sample)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(IngestPayload {
+        success: true,
+        message: "Telemetry received".to_string(),
+        stored,
+    }))
+}
+
+pub async fn query(
+    State(db_state): State<DbState>,
+    claims: Claims,
+    Path(devid): Path<String>,
+    Query(q): Query<TelemetryQuery>,
+) -> Result<Json<TelemetryPayload>, HandleError> {
+    owned_device(&db_state, &claims, &devid).await?;
+
+    let to = q.to.unwrap_or(DateTime::now().timestamp_millis());
+    let from = q.from.unwrap_or(to - 24 * 3600 * 1000);
+    if from >= to {
+        return Err(HandleError::BadRequest("Empty time range".to_string()));
+    }
+    let bucket = q.bucket.unwrap_or(DEFAULT_BUCKET).max(1);
+    if (to - from) as u64 / 1000 / bucket > MAX_BUCKETS {
+        return Err(HandleError::BadRequest("Too many buckets".to_string()));
+    }
+    let metrics: Vec<String> = q
+        .metrics
+        .split(',')
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty())
+        .collect();
+    if metrics.is_empty() || !metrics.iter().all(|m| valid_metric(m)) {
+        return Err(HandleError::BadRequest("Invalid metrics".to_string()));
+    }
+
+    let buckets = db_state
+        .telemetry_buckets(
+            &devid,
+            DateTime::from_millis(from),
+            DateTime::from_millis(to),
+            bucket,
+            &metrics,
+        )
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(TelemetryPayload {
+        success: true,
+        message: "Telemetry queried".to_string(),
+        buckets,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TelemetryBody {
+    ts: Option<i64>,
+    metrics: HashMap<String, f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TelemetryQuery {
+    from: Option<i64>,
+    to: Option<i64>,
+    bucket: Option<u64>,
+    metrics: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IngestPayload {
+    success: bool,
+    message: String,
+    stored: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TelemetryPayload {
+    success: bool,
+    message: String,
+    buckets: Vec<Bucket>,
+}
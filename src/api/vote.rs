@@ -0,0 +1,203 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use socketioxide::SocketIo;
+use std::collections::HashMap;
+
+use crate::{
+    db::{Counts, DbState, OidDec, Tally, VoteTarget, decode_oid, encode_oid},
+    socketio::emit_to_room,
+};
+
+use super::{HandleError, auth::Claims};
+
+// The voted item, and the channel its reaction set comes from.
+struct Item {
+    kind: VoteTarget,
+    tid: ObjectId,
+    pid: Option<ObjectId>,
+    channel: ObjectId,
+}
+
+impl Item {
+    fn oid(&self) -> ObjectId {
+        self.pid.unwrap_or(self.tid)
+    }
+}
+
+async fn find_item(
+    db_state: &DbState,
+    tid: ObjectId,
+    pid: Option<&str>,
+) -> Result<Item, HandleError> {
+    let topic = db_state
+        .get_topic(tid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Topic not found: {err}")))?;
+    let Some(pid) = pid else {
+        return Ok(Item {
+            kind: VoteTarget::Topic,
+            tid,
+            pid: None,
+            channel: topic.channel,
+        });
+    };
+    let pid = decode_oid(pid).ok_or(HandleError::NotFound("Invalid path".to_string()))?;
+    db_state
+        .get_post(tid, pid)
+        .await
+        .map_err(|err| HandleError::NotFound(format!("Post not found: {err}")))?;
+    Ok(Item {
+        kind: VoteTarget::Post,
+        tid,
+        pid: Some(pid),
+        channel: topic.channel,
+    })
+}
+
+async fn vote(
+    db_state: &DbState,
+    io: &SocketIo,
+    claims: &Claims,
+    item: Item,
+    value: i32,
+) -> Result<Json<CountsPayload>, HandleError> {
+    let user = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    if !(-1..=1).contains(&value) {
+        return Err(HandleError::BadRequest(
+            "Vote must be 1, -1 or 0".to_string(),
+        ));
+    }
+    let counts = db_state
+        .cast_vote(user, item.kind, item.oid(), value)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(publish(io, &item, counts).await))
+}
+
+async fn react(
+    db_state: &DbState,
+    io: &SocketIo,
+    claims: &Claims,
+    item: Item,
+    update: ReactionUpdate,
+) -> Result<Json<CountsPayload>, HandleError> {
+    let user = claims
+        .userid()
+        .ok_or(HandleError::BadRequest("Invalid token".to_string()))?;
+    if !db_state.reactions(item.channel).contains(&update.emoji) {
+        return Err(HandleError::BadRequest(format!(
+            "Reaction not offered in this channel: {}",
+            update.emoji
+        )));
+    }
+    let counts = db_state
+        .react(user, item.kind, item.oid(), &update.emoji, update.active)
+        .await
+        .map_err(|e| HandleError::ServerError(e.to_string()))?;
+    Ok(Json(publish(io, &item, counts).await))
+}
+
+// Readers of the topic see counters move live, with the REST reply's shape.
+async fn publish(io: &SocketIo, item: &Item, counts: Counts) -> CountsPayload {
+    let payload = CountsPayload {
+        success: true,
+        message: "Counts updated".to_string(),
+        tid: encode_oid(item.tid),
+        pid: item.pid.map(encode_oid),
+        counts: CountsInfo::new(counts.votes, counts.reactions),
+    };
+    emit_to_room(io, &payload.tid, "votes", &payload).await;
+    payload
+}
+
+pub async fn vote_topic(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    OidDec(tid): OidDec,
+    Json(payload): Json<VoteUpdate>,
+) -> Result<Json<CountsPayload>, HandleError> {
+    let item = find_item(&db_state, tid, None).await?;
+    vote(&db_state, &io, &claims, item, payload.value).await
+}
+
+pub async fn vote_post(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    Path((tid, pid)): Path<(String, String)>,
+    Json(payload): Json<VoteUpdate>,
+) -> Result<Json<CountsPayload>, HandleError> {
+    let tid = decode_oid(&tid).ok_or(HandleError::NotFound("Invalid path".to_string()))?;
+    let item = find_item(&db_state, tid, Some(&pid)).await?;
+    vote(&db_state, &io, &claims, item, payload.value).await
+}
+
+pub async fn react_topic(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    OidDec(tid): OidDec,
+    Json(payload): Json<ReactionUpdate>,
+) -> Result<Json<CountsPayload>, HandleError> {
+    let item = find_item(&db_state, tid, None).await?;
+    react(&db_state, &io, &claims, item, payload).await
+}
+
+pub async fn react_post(
+    State(db_state): State<DbState>,
+    Extension(io): Extension<SocketIo>,
+    claims: Claims,
+    Path((tid, pid)): Path<(String, String)>,
+    Json(payload): Json<ReactionUpdate>,
+) -> Result<Json<CountsPayload>, HandleError> {
+    let tid = decode_oid(&tid).ok_or(HandleError::NotFound("Invalid path".to_string()))?;
+    let item = find_item(&db_state, tid, Some(&pid)).await?;
+    react(&db_state, &io, &claims, item, payload).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VoteUpdate {
+    value: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReactionUpdate {
+    emoji: String,
+    active: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CountsInfo {
+    up: i64,
+    down: i64,
+    score: i64,
+    reactions: HashMap<String, i64>,
+}
+
+impl CountsInfo {
+    pub fn new(votes: Tally, reactions: HashMap<String, i64>) -> Self {
+        Self {
+            up: votes.up,
+            down: votes.down,
+            score: votes.up - votes.down,
+            // Reactions everyone took back stay stored at zero.
+            reactions: reactions.into_iter().filter(|(_, n)| *n > 0).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CountsPayload {
+    success: bool,
+    message: String,
+    tid: String,
+    pid: Option<String>,
+    counts: CountsInfo,
+}
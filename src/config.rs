@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::{collections::HashMap, error::Error, time::Duration};
 use tokio::{fs::File, io::AsyncReadExt};
 // use tracing::info;
 
@@ -14,10 +14,83 @@ struct Mongo {
     db: Option<String>,
 }
 
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Signaling {
+    legacy: Option<bool>,
+    legacy_namespace: Option<bool>,
+}
+
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Devices {
+    max_viewers: Option<usize>,
+}
+
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Messages {
+    timeout: Option<u64>,
+    timeouts: Option<HashMap<String, u64>>,
+}
+
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Telemetry {
+    retention_days: Option<u64>,
+    granularity: Option<String>,
+    sample_interval: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Releases {
+    dir: Option<String>,
+    base_url: Option<String>,
+    max_size: Option<usize>,
+}
+
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Audit {
+    retention_days: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Tags {
+    max_per_topic: Option<usize>,
+    /// Allowed tags by encoded channel id. Other channels take any tag.
+    channels: Option<HashMap<String, Vec<String>>>,
+}
+
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Attachments {
+    dir: Option<String>,
+    max_size: Option<usize>,
+    thumb_size: Option<u32>,
+    max_per_item: Option<usize>,
+}
+
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Reactions {
+    default: Option<Vec<String>>,
+    /// Reaction sets by encoded channel id, replacing the default one.
+    channels: Option<HashMap<String, Vec<String>>>,
+}
+
+#[derive(Clone, Serialize, Debug, Deserialize)]
+struct Dm {
+    allow_plaintext: Option<bool>,
+}
+
 #[derive(Clone, Serialize, Debug, Deserialize)]
 pub struct Config {
     auth: Option<Auth>,
     mongodb: Option<Mongo>,
+    signaling: Option<Signaling>,
+    devices: Option<Devices>,
+    messages: Option<Messages>,
+    telemetry: Option<Telemetry>,
+    releases: Option<Releases>,
+    audit: Option<Audit>,
+    tags: Option<Tags>,
+    attachments: Option<Attachments>,
+    reactions: Option<Reactions>,
+    dm: Option<Dm>,
 }
 
 impl Config {
@@ -30,32 +103,172 @@ impl Config {
     }
 
     pub fn get_secret(&self) -> Option<String> {
-        match &self.auth {
-            Some(a) => match &a.secret {
-                Some(secret) => Some(secret.clone()),
-                None => None,
-            },
-            None => None,
-        }
+        self.auth.as_ref().and_then(|a| a.secret.clone())
     }
 
     pub fn mongo_uri(&self) -> Option<String> {
-        match &self.mongodb {
-            Some(mongo) => match &mongo.uri {
-                Some(u) => Some(u.clone()),
-                None => None,
-            },
-            None => None,
-        }
+        self.mongodb.as_ref().and_then(|mongo| mongo.uri.clone())
     }
 
     pub fn mongo_db(&self) -> Option<String> {
-        match &self.mongodb {
-            Some(mongo) => match &mongo.db {
-                Some(db) => Some(db.clone()),
-                None => None,
-            },
-            None => None,
-        }
+        self.mongodb.as_ref().and_then(|mongo| mongo.db.clone())
+    }
+
+    /// Whether the untyped `auth`/`accept`/`reject` events stay registered.
+    pub fn legacy_signaling(&self) -> bool {
+        self.signaling
+            .as_ref()
+            .and_then(|s| s.legacy)
+            .unwrap_or(true)
+    }
+
+    /// Whether the old `/` namespace is still served next to `/chat`, `/box` and `/call`.
+    /// On until operators turn it off, so clients that predate the split keep working.
+    pub fn legacy_namespace(&self) -> bool {
+        self.signaling
+            .as_ref()
+            .and_then(|s| s.legacy_namespace)
+            .unwrap_or(true)
+    }
+
+    /// Default number of concurrent viewers per device. `None` means unlimited.
+    pub fn max_viewers(&self) -> Option<usize> {
+        self.devices.as_ref().and_then(|d| d.max_viewers)
+    }
+
+    /// Ack timeout for a device room `message`, looked up by its `type` field.
+    pub fn message_timeout(&self, kind: Option<&str>) -> Duration {
+        let messages = self.messages.as_ref();
+        let secs = kind
+            .and_then(|k| messages?.timeouts.as_ref()?.get(k).copied())
+            .or(messages.and_then(|m| m.timeout))
+            .unwrap_or(5);
+        Duration::from_secs(secs)
+    }
+
+    /// How long telemetry samples are kept before Mongo expires them.
+    pub fn telemetry_retention(&self) -> Duration {
+        let days = self
+            .telemetry
+            .as_ref()
+            .and_then(|t| t.retention_days)
+            .unwrap_or(30);
+        Duration::from_secs(days * 24 * 3600)
+    }
+
+    /// Time-series bucket granularity: `seconds`, `minutes` or `hours`.
+    pub fn telemetry_granularity(&self) -> Option<String> {
+        self.telemetry.as_ref().and_then(|t| t.granularity.clone())
+    }
+
+    /// Samples of one device closer together than this are dropped on ingestion.
+    pub fn telemetry_sample_interval(&self) -> Duration {
+        let secs = self
+            .telemetry
+            .as_ref()
+            .and_then(|t| t.sample_interval)
+            .unwrap_or(0);
+        Duration::from_secs(secs)
+    }
+
+    /// Directory firmware artifacts are stored in.
+    pub fn releases_dir(&self) -> String {
+        self.releases
+            .as_ref()
+            .and_then(|r| r.dir.clone())
+            .unwrap_or("releases".to_string())
+    }
+
+    /// Public origin boxes download artifacts from. Relative URLs are used when unset.
+    pub fn releases_base_url(&self) -> String {
+        self.releases
+            .as_ref()
+            .and_then(|r| r.base_url.clone())
+            .unwrap_or_default()
+    }
+
+    /// Largest accepted artifact upload in bytes.
+    pub fn releases_max_size(&self) -> usize {
+        self.releases
+            .as_ref()
+            .and_then(|r| r.max_size)
+            .unwrap_or(512 * 1024 * 1024)
+    }
+
+    /// How long audit events are kept before Mongo expires them.
+    pub fn audit_retention(&self) -> Duration {
+        let days = self
+            .audit
+            .as_ref()
+            .and_then(|a| a.retention_days)
+            .unwrap_or(30);
+        Duration::from_secs(days * 24 * 3600)
+    }
+
+    /// Most tags a single topic can carry.
+    pub fn max_tags(&self) -> usize {
+        self.tags
+            .as_ref()
+            .and_then(|t| t.max_per_topic)
+            .unwrap_or(5)
+    }
+
+    /// Tags a channel is restricted to, `None` for free-form channels.
+    pub fn allowed_tags(&self, channel: &str) -> Option<Vec<String>> {
+        self.tags.as_ref()?.channels.as_ref()?.get(channel).cloned()
+    }
+
+    /// Directory the local storage backend keeps attachments in.
+    pub fn attachments_dir(&self) -> String {
+        self.attachments
+            .as_ref()
+            .and_then(|a| a.dir.clone())
+            .unwrap_or("attachments".to_string())
+    }
+
+    /// Largest accepted attachment in bytes.
+    pub fn attachments_max_size(&self) -> usize {
+        self.attachments
+            .as_ref()
+            .and_then(|a| a.max_size)
+            .unwrap_or(10 * 1024 * 1024)
+    }
+
+    /// Bounding box of generated thumbnails, in pixels.
+    pub fn thumb_size(&self) -> u32 {
+        self.attachments
+            .as_ref()
+            .and_then(|a| a.thumb_size)
+            .unwrap_or(250)
+    }
+
+    /// Most attachments a single topic or post can carry.
+    pub fn max_attachments(&self) -> usize {
+        self.attachments
+            .as_ref()
+            .and_then(|a| a.max_per_item)
+            .unwrap_or(4)
+    }
+
+    /// Whether `dm` takes unencrypted bodies. On while clients move to encryption; turn
+    /// it off so the server only ever stores ciphertext.
+    pub fn allow_plaintext_dm(&self) -> bool {
+        self.dm
+            .as_ref()
+            .and_then(|d| d.allow_plaintext)
+            .unwrap_or(true)
+    }
+
+    /// Emoji reactions offered in a channel.
+    pub fn reactions(&self, channel: &str) -> Vec<String> {
+        let reactions = self.reactions.as_ref();
+        reactions
+            .and_then(|r| r.channels.as_ref()?.get(channel).cloned())
+            .or(reactions.and_then(|r| r.default.clone()))
+            .unwrap_or_else(|| {
+                ["👍", "👎", "❤️", "😂", "😮", "😢"]
+                    .map(str::to_string)
+                    .to_vec()
+            })
     }
 }
@@ -1,15 +1,64 @@
+mod attachment;
+mod audit;
+mod boxconf;
+mod command;
+mod device;
+mod dm;
+mod group;
+mod keys;
+mod notification;
+mod post;
+mod read;
+mod release;
+mod search;
+mod session;
+mod telemetry;
 mod topic;
 mod user;
+mod vote;
 
-use crate::{api::HandleError, config::Config};
+pub use attachment::{Attachment, AttachmentDoc};
+pub use audit::{AuditEvent, AuditKind};
+pub use boxconf::ConfigRevision;
+pub use command::{CommandDoc, CommandStatus};
+pub use device::DeviceDoc;
+pub use dm::{ConversationDoc, MessageDoc};
+pub use group::{GroupDoc, GroupKind};
+pub use keys::{KeyBundle, PreKey, SignedPreKey};
+pub use notification::{NotificationDoc, NotificationKind};
+pub use post::{PostDoc, mentions, pseudonym};
+pub use read::ReadTarget;
+pub use release::{OtaReport, OtaState, ReleaseDoc, ReleaseStatus, Rollout};
+pub use search::SearchFilter;
+pub use session::{EndReason, SessionDoc};
+pub use telemetry::{Bucket, Sample, valid_metric};
+pub use topic::normalize_tag;
+pub use user::PresenceStatus;
+pub use vote::{Counts, Tally, VoteTarget};
+
+use crate::{
+    api::HandleError,
+    config::Config,
+    storage::{LocalStorage, Storage},
+};
 use axum::{
     RequestPartsExt,
     extract::{FromRequestParts, Path},
     http::request::Parts,
 };
 use base64::{Engine, prelude::BASE64_URL_SAFE};
-use mongodb::{Client, Database, bson::oid::ObjectId};
-use std::error::Error;
+use mongodb::{
+    Client, Database,
+    bson::oid::ObjectId,
+    error::{ErrorKind, WriteFailure},
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex as AsyncMutex;
 
 #[derive(Debug, Clone, Default)]
 pub struct OidDec(pub ObjectId);
@@ -35,7 +84,7 @@ pub fn encode_oid(oid: ObjectId) -> String {
     BASE64_URL_SAFE.encode(oid.bytes())
 }
 
-fn decode_oid<T: AsRef<[u8]>>(enc: T) -> Option<ObjectId> {
+pub fn decode_oid<T: AsRef<[u8]>>(enc: T) -> Option<ObjectId> {
     match BASE64_URL_SAFE.decode(enc) {
         Ok(d) => {
             let arr: [u8; 12] = match d.try_into() {
@@ -48,25 +97,104 @@ fn decode_oid<T: AsRef<[u8]>>(enc: T) -> Option<ObjectId> {
     }
 }
 
+/// Whether a write lost a race against a unique index (E11000).
+pub fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == 11000,
+        ErrorKind::Command(e) => e.code == 11000,
+        _ => false,
+    }
+}
+
 // Our shared state
 #[derive(Clone)]
 pub struct DbState {
     config: Config,
     mongo_client: Client,
+    // Timestamp (ms) of the last telemetry sample accepted per device.
+    last_samples: Arc<Mutex<HashMap<String, i64>>>,
+    // One delivery run at a time per device, so commands go out in order.
+    command_locks: Arc<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>>,
+    // Key bundles fetched per requester and per requester/target pair in the current window.
+    bundle_claims: Arc<Mutex<HashMap<String, (u32, Instant)>>>,
+    storage: Arc<dyn Storage>,
 }
 
 impl DbState {
     pub fn new(config: Config, mongo_client: Client) -> Self {
+        let storage = Arc::new(LocalStorage::new(config.attachments_dir()));
         Self {
             config,
             mongo_client,
+            last_samples: Arc::default(),
+            command_locks: Arc::default(),
+            bundle_claims: Arc::default(),
+            storage,
         }
     }
 
+    /// Lock to hold while delivering commands to `devid`.
+    pub fn command_lock(&self, devid: &str) -> Arc<AsyncMutex<()>> {
+        let mut locks = self.command_locks.lock().unwrap_or_else(|e| e.into_inner());
+        locks.entry(devid.to_owned()).or_default().clone()
+    }
+
     pub fn secret(&self) -> Option<String> {
         self.config.get_secret()
     }
 
+    pub fn legacy_signaling(&self) -> bool {
+        self.config.legacy_signaling()
+    }
+
+    pub fn message_timeout(&self, kind: Option<&str>) -> Duration {
+        self.config.message_timeout(kind)
+    }
+
+    pub fn releases_dir(&self) -> String {
+        self.config.releases_dir()
+    }
+
+    pub fn releases_base_url(&self) -> String {
+        self.config.releases_base_url()
+    }
+
+    pub fn releases_max_size(&self) -> usize {
+        self.config.releases_max_size()
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    pub fn attachments_max_size(&self) -> usize {
+        self.config.attachments_max_size()
+    }
+
+    pub fn thumb_size(&self) -> u32 {
+        self.config.thumb_size()
+    }
+
+    pub fn max_attachments(&self) -> usize {
+        self.config.max_attachments()
+    }
+
+    pub fn reactions(&self, channel: ObjectId) -> Vec<String> {
+        self.config.reactions(&encode_oid(channel))
+    }
+
+    pub fn max_tags(&self) -> usize {
+        self.config.max_tags()
+    }
+
+    pub fn allowed_tags(&self, channel: ObjectId) -> Option<Vec<String>> {
+        self.config.allowed_tags(&encode_oid(channel))
+    }
+
+    pub fn allow_plaintext_dm(&self) -> bool {
+        self.config.allow_plaintext_dm()
+    }
+
     pub fn db(&self) -> Result<Database, Box<dyn Error + Send + Sync>> {
         let db_name = match self.config.mongo_db() {
             Some(n) => n,
@@ -0,0 +1,130 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId, to_bson},
+    error::ErrorKind,
+    options::{IndexOptions, ReturnDocument},
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use super::{DbState, PostDoc, topic::TopicDoc};
+
+/// A stored file. Identical uploads share one document through their hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttachmentDoc {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    pub hash: String,
+    pub key: String,
+    pub mime: String,
+    pub size: i64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub thumbnail: Option<String>,
+    pub uploader: ObjectId,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime,
+}
+
+/// What a topic or post keeps of each of its attachments.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub aid: ObjectId,
+    pub key: String,
+    pub mime: String,
+    pub size: i64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub thumbnail: Option<String>,
+}
+
+impl From<&AttachmentDoc> for Attachment {
+    fn from(a: &AttachmentDoc) -> Self {
+        Self {
+            aid: a.oid,
+            key: a.key.clone(),
+            mime: a.mime.clone(),
+            size: a.size,
+            width: a.width,
+            height: a.height,
+            thumbnail: a.thumbnail.clone(),
+        }
+    }
+}
+
+// Matches only while the list holds fewer than `max` attachments.
+fn has_room(max: usize) -> Document {
+    let max = i64::try_from(max).unwrap_or(i64::MAX);
+    doc! {"$lt": [{"$size": {"$ifNull": ["$attachments", []]}}, max]}
+}
+
+impl DbState {
+    pub async fn find_attachment(
+        &self,
+        hash: &str,
+    ) -> Result<Option<AttachmentDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<AttachmentDoc> = db.collection("attachments");
+        Ok(coll.find_one(doc! {"hash": hash}).await?)
+    }
+
+    /// Store `att`, or return the document a concurrent upload of the same file created.
+    pub async fn add_attachment(
+        &self,
+        att: AttachmentDoc,
+    ) -> Result<AttachmentDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<AttachmentDoc> = db.collection("attachments");
+        let opts = IndexOptions::builder().unique(true).build();
+        let index = IndexModel::builder()
+            .keys(doc! {"hash": 1})
+            .options(opts)
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        match coll.insert_one(&att).await {
+            Ok(_) => Ok(att),
+            Err(e) if matches!(*e.kind, ErrorKind::Write(_)) => self
+                .find_attachment(&att.hash)
+                .await?
+                .ok_or("No attachment found".into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn attach_to_post(
+        &self,
+        pid: ObjectId,
+        att: &Attachment,
+        max: usize,
+    ) -> Result<PostDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<PostDoc> = db.collection("posts");
+        let post = coll
+            .find_one_and_update(
+                doc! {"_id": pid, "deletedAt": null, "$expr": has_room(max)},
+                doc! {"$addToSet": {"attachments": to_bson(att)?}},
+            )
+            .return_document(ReturnDocument::After)
+            .await?;
+        post.ok_or(format!("No post found or already {max} attachments").into())
+    }
+
+    pub async fn attach_to_topic(
+        &self,
+        tid: ObjectId,
+        att: &Attachment,
+        max: usize,
+    ) -> Result<TopicDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<TopicDoc> = db.collection("topics");
+        let topic = coll
+            .find_one_and_update(
+                doc! {"_id": tid, "$expr": has_room(max)},
+                doc! {"$addToSet": {"attachments": to_bson(att)?}},
+            )
+            .return_document(ReturnDocument::After)
+            .await?;
+        topic.ok_or(format!("No topic found or already {max} attachments").into())
+    }
+}
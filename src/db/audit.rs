@@ -0,0 +1,130 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+
+use super::DbState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    DeviceOnline,
+    DeviceOffline,
+    DeviceUnset,
+    WatcherJoin,
+    WatcherLeave,
+    SpeakerChange,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEvent {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    pub ts: DateTime,
+    pub kind: AuditKind,
+    pub devid: String,
+    pub sid: String,
+    pub uid: Option<ObjectId>,
+    pub user: Option<String>,
+    pub detail: Option<Value>,
+}
+
+impl AuditEvent {
+    pub fn new(kind: AuditKind, devid: &str, sid: &str) -> Self {
+        Self {
+            oid: ObjectId::new(),
+            ts: DateTime::now(),
+            kind,
+            devid: devid.to_owned(),
+            sid: sid.to_owned(),
+            uid: None,
+            user: None,
+            detail: None,
+        }
+    }
+
+    /// The signed-in account behind the socket, by id and name.
+    pub fn with_user(mut self, user: Option<(ObjectId, String)>) -> Self {
+        (self.uid, self.user) = user.unzip();
+        self
+    }
+
+    pub fn with_detail(mut self, detail: Value) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+impl DbState {
+    /// Indexes of the audit log. The TTL index expires events after the configured retention.
+    pub async fn init_audit(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<AuditEvent> = db.collection("audit_events");
+        let ttl = IndexModel::builder()
+            .keys(doc! {"ts": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(self.config.audit_retention())
+                    .build(),
+            )
+            .build();
+        let by_device = IndexModel::builder()
+            .keys(doc! {"devid": 1, "ts": -1})
+            .build();
+        let by_user = IndexModel::builder()
+            .keys(doc! {"uid": 1, "ts": -1})
+            .build();
+        coll.create_indexes([ttl, by_device, by_user]).await?;
+        Ok(())
+    }
+
+    pub async fn log_event(&self, event: &AuditEvent) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<AuditEvent> = db.collection("audit_events");
+        coll.insert_one(event).await?;
+        Ok(())
+    }
+
+    pub async fn device_events(
+        &self,
+        devid: &str,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<AuditEvent>, Box<dyn Error + Send + Sync>> {
+        self.find_events(doc! {"devid": devid}, limit, skip).await
+    }
+
+    pub async fn user_events(
+        &self,
+        uid: ObjectId,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<AuditEvent>, Box<dyn Error + Send + Sync>> {
+        self.find_events(doc! {"uid": uid}, limit, skip).await
+    }
+
+    async fn find_events(
+        &self,
+        filter: Document,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<AuditEvent>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<AuditEvent> = db.collection("audit_events");
+        let mut cursor = coll
+            .find(filter)
+            .sort(doc! {"ts": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut events = Vec::new();
+        while cursor.advance().await? {
+            events.push(cursor.deserialize_current()?);
+        }
+        Ok(events)
+    }
+}
@@ -0,0 +1,124 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+
+use super::{DbState, is_duplicate_key};
+
+// How often a writer retries after losing the race for a revision number.
+const REVISION_RETRIES: usize = 5;
+
+/// One revision of a box configuration. Revisions are append-only, the
+/// highest one is the desired state of the box.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigRevision {
+    #[serde(rename = "_id")]
+    oid: ObjectId,
+    pub devid: String,
+    pub revision: i64,
+    pub schema: i32,
+    pub config: Value,
+    pub author: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime,
+    #[serde(rename = "appliedAt")]
+    pub applied_at: Option<DateTime>,
+}
+
+impl DbState {
+    pub async fn save_box_config(
+        &self,
+        devid: &str,
+        schema: i32,
+        config: Value,
+        author: &str,
+    ) -> Result<ConfigRevision, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ConfigRevision> = db.collection("device_configs");
+
+        // Concurrent writers race for the same revision number, the unique index picks one
+        // and the others try again with the next number.
+        let opts = IndexOptions::builder().unique(true).build();
+        let index = IndexModel::builder()
+            .keys(doc! {"devid": 1, "revision": -1})
+            .options(opts)
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        for _ in 0..REVISION_RETRIES {
+            let revision = match self.latest_box_config(devid).await? {
+                Some(r) => r.revision + 1,
+                None => 1,
+            };
+            let rev = ConfigRevision {
+                oid: ObjectId::new(),
+                devid: devid.to_owned(),
+                revision,
+                schema,
+                config: config.clone(),
+                author: author.to_owned(),
+                created_at: DateTime::now(),
+                applied_at: None,
+            };
+            match coll.insert_one(&rev).await {
+                Ok(_) => return Ok(rev),
+                Err(e) if is_duplicate_key(&e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(format!("Too many concurrent config writes for {devid}").into())
+    }
+
+    pub async fn latest_box_config(
+        &self,
+        devid: &str,
+    ) -> Result<Option<ConfigRevision>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ConfigRevision> = db.collection("device_configs");
+        let rev = coll
+            .find_one(doc! {"devid": devid})
+            .sort(doc! {"revision": -1})
+            .await?;
+        Ok(rev)
+    }
+
+    pub async fn box_config_history(
+        &self,
+        devid: &str,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<ConfigRevision>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ConfigRevision> = db.collection("device_configs");
+        let mut cursor = coll
+            .find(doc! {"devid": devid})
+            .sort(doc! {"revision": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut revs = Vec::new();
+        while cursor.advance().await? {
+            revs.push(cursor.deserialize_current()?);
+        }
+        Ok(revs)
+    }
+
+    pub async fn mark_config_applied(
+        &self,
+        devid: &str,
+        revision: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ConfigRevision> = db.collection("device_configs");
+        coll.update_one(
+            doc! {"devid": devid, "revision": revision, "appliedAt": null},
+            doc! {"$set": {"appliedAt": DateTime::now()}},
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, doc, oid::ObjectId, to_bson},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+
+use super::DbState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandStatus {
+    Pending,
+    Delivered,
+    Acked,
+    Failed,
+    Expired,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandDoc {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    pub devid: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub payload: Value,
+    pub priority: i32,
+    pub status: CommandStatus,
+    pub author: String,
+    pub result: Option<Value>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: DateTime,
+    #[serde(rename = "deliveredAt")]
+    pub delivered_at: Option<DateTime>,
+    #[serde(rename = "ackedAt")]
+    pub acked_at: Option<DateTime>,
+}
+
+impl DbState {
+    pub async fn queue_command(
+        &self,
+        devid: &str,
+        kind: &str,
+        payload: Value,
+        priority: i32,
+        ttl_secs: u64,
+        author: &str,
+    ) -> Result<CommandDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<CommandDoc> = db.collection("commands");
+        let index = IndexModel::builder()
+            .keys(doc! {"devid": 1, "status": 1, "priority": -1, "createdAt": 1})
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        let ttl_ms = i64::try_from(ttl_secs)
+            .ok()
+            .and_then(|s| s.checked_mul(1000))
+            .ok_or("TTL out of range")?;
+        let now = DateTime::now();
+        let cmd = CommandDoc {
+            oid: ObjectId::new(),
+            devid: devid.to_owned(),
+            kind: kind.to_owned(),
+            payload,
+            priority,
+            status: CommandStatus::Pending,
+            author: author.to_owned(),
+            result: None,
+            created_at: now,
+            expires_at: DateTime::from_millis(now.timestamp_millis().saturating_add(ttl_ms)),
+            delivered_at: None,
+            acked_at: None,
+        };
+        coll.insert_one(&cmd).await?;
+        Ok(cmd)
+    }
+
+    /// Put commands that were sent but never answered back in line, e.g. because the
+    /// box dropped off mid-delivery. Only safe while no delivery to `devid` is running.
+    pub async fn requeue_commands(&self, devid: &str) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<CommandDoc> = db.collection("commands");
+        let res = coll
+            .update_many(
+                doc! {"devid": devid, "status": to_bson(&CommandStatus::Delivered)?},
+                doc! {"$set": {"status": to_bson(&CommandStatus::Pending)?}},
+            )
+            .await?;
+        Ok(res.modified_count)
+    }
+
+    /// Pending commands of a device in delivery order. Stale ones are expired on the way.
+    pub async fn pending_commands(
+        &self,
+        devid: &str,
+    ) -> Result<Vec<CommandDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<CommandDoc> = db.collection("commands");
+        let expired = to_bson(&CommandStatus::Expired)?;
+        let pending = to_bson(&CommandStatus::Pending)?;
+        coll.update_many(
+            doc! {"devid": devid, "status": &pending, "expiresAt": {"$lte": DateTime::now()}},
+            doc! {"$set": {"status": expired}},
+        )
+        .await?;
+
+        let mut cursor = coll
+            .find(doc! {"devid": devid, "status": pending})
+            .sort(doc! {"priority": -1, "createdAt": 1})
+            .await?;
+        let mut cmds = Vec::new();
+        while cursor.advance().await? {
+            cmds.push(cursor.deserialize_current()?);
+        }
+        Ok(cmds)
+    }
+
+    /// Move a command between states. Returns `false` if it was not in `from`,
+    /// which lets concurrent deliveries skip commands someone else already took.
+    pub async fn transition_command(
+        &self,
+        cid: ObjectId,
+        from: CommandStatus,
+        to: CommandStatus,
+        result: Option<Value>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<CommandDoc> = db.collection("commands");
+        let mut set = doc! {"status": to_bson(&to)?};
+        match to {
+            CommandStatus::Delivered => {
+                set.insert("deliveredAt", DateTime::now());
+            }
+            CommandStatus::Acked | CommandStatus::Failed => {
+                set.insert("ackedAt", DateTime::now());
+                set.insert("result", to_bson(&result)?);
+            }
+            _ => {}
+        }
+        let res = coll
+            .update_one(
+                doc! {"_id": cid, "status": to_bson(&from)?},
+                doc! {"$set": set},
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    pub async fn get_command(
+        &self,
+        devid: &str,
+        cid: ObjectId,
+    ) -> Result<CommandDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<CommandDoc> = db.collection("commands");
+        match coll.find_one(doc! {"_id": cid, "devid": devid}).await? {
+            Some(c) => Ok(c),
+            None => Err("No command found".into()),
+        }
+    }
+
+    pub async fn device_commands(
+        &self,
+        devid: &str,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<CommandDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<CommandDoc> = db.collection("commands");
+        let mut cursor = coll
+            .find(doc! {"devid": devid})
+            .sort(doc! {"createdAt": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut cmds = Vec::new();
+        while cursor.advance().await? {
+            cmds.push(cursor.deserialize_current()?);
+        }
+        Ok(cmds)
+    }
+}
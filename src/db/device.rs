@@ -0,0 +1,147 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use super::{DbState, GroupDoc, encode_oid};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceDoc {
+    #[serde(rename = "_id")]
+    oid: ObjectId,
+    pub devid: String,
+    pub owner: ObjectId,
+    pub name: String,
+    #[serde(rename = "claimedAt")]
+    pub claimed_at: DateTime,
+    pub model: Option<String>,
+    pub firmware: Option<String>,
+    /// Users the owner granted viewing, next to the viewers of its groups.
+    #[serde(default)]
+    pub viewers: Vec<ObjectId>,
+}
+
+impl DbState {
+    /// Bind a freshly claimed box to `owner` and allocate its permanent device id.
+    pub async fn bind_device(
+        &self,
+        owner: &ObjectId,
+        name: &str,
+    ) -> Result<DeviceDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<DeviceDoc> = db.collection("devices");
+
+        let opts = IndexOptions::builder().unique(true).build();
+        let index = IndexModel::builder()
+            .keys(doc! {"devid": 1})
+            .options(opts)
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        let oid = ObjectId::new();
+        let device = DeviceDoc {
+            oid,
+            devid: encode_oid(oid),
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            claimed_at: DateTime::now(),
+            model: None,
+            firmware: None,
+            viewers: Vec::new(),
+        };
+        coll.insert_one(&device).await?;
+        Ok(device)
+    }
+
+    /// Undo `bind_device` when the box could not be handed its credentials.
+    pub async fn unbind_device(&self, devid: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<DeviceDoc> = db.collection("devices");
+        coll.delete_one(doc! {"devid": devid}).await?;
+        Ok(())
+    }
+
+    pub async fn get_device(&self, devid: &str) -> Result<DeviceDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<DeviceDoc> = db.collection("devices");
+        match coll.find_one(doc! {"devid": devid}).await? {
+            Some(d) => Ok(d),
+            None => Err("No device found".into()),
+        }
+    }
+
+    /// Record the hardware model and firmware version a box reported.
+    pub async fn set_device_firmware(
+        &self,
+        devid: &str,
+        model: &str,
+        firmware: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<DeviceDoc> = db.collection("devices");
+        coll.update_one(
+            doc! {"devid": devid},
+            doc! {"$set": {"model": model, "firmware": firmware}},
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_devices(
+        &self,
+        devids: &[String],
+    ) -> Result<Vec<DeviceDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<DeviceDoc> = db.collection("devices");
+        let mut cursor = coll.find(doc! {"devid": {"$in": devids}}).await?;
+        let mut devices = Vec::new();
+        while cursor.advance().await? {
+            devices.push(cursor.deserialize_current()?);
+        }
+        Ok(devices)
+    }
+
+    /// Grant or revoke viewing of `devid` for other users.
+    pub async fn update_device_viewers(
+        &self,
+        devid: &str,
+        add: &[ObjectId],
+        remove: &[ObjectId],
+    ) -> Result<DeviceDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<DeviceDoc> = db.collection("devices");
+        // A single update can't $addToSet and $pull on the same field.
+        coll.update_one(
+            doc! {"devid": devid},
+            doc! {"$addToSet": {"viewers": {"$each": add}}},
+        )
+        .await?;
+        coll.update_one(
+            doc! {"devid": devid},
+            doc! {"$pull": {"viewers": {"$in": remove}}},
+        )
+        .await?;
+        self.get_device(devid).await
+    }
+
+    /// Whether `uid` may watch `device`: its owner, a granted viewer, or a viewer
+    /// of one of the owner's groups the device is in.
+    pub async fn may_watch(
+        &self,
+        device: &DeviceDoc,
+        uid: &ObjectId,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        if device.owner == *uid || device.viewers.contains(uid) {
+            return Ok(true);
+        }
+        let db = self.db()?;
+        let coll: Collection<GroupDoc> = db.collection("device_groups");
+        let groups = coll
+            .count_documents(doc! {"owner": device.owner, "devices": &device.devid, "viewers": uid})
+            .await?;
+        Ok(groups > 0)
+    }
+}
@@ -0,0 +1,254 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, doc, oid::ObjectId},
+    options::{IndexOptions, ReturnDocument},
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use super::DbState;
+
+/// A private conversation between two users, keyed by their sorted names.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConversationDoc {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    key: String,
+    pub participants: Vec<String>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime,
+    #[serde(rename = "lastMessageAt")]
+    pub last_message_at: Option<DateTime>,
+}
+
+impl ConversationDoc {
+    pub fn has(&self, user: &str) -> bool {
+        self.participants.iter().any(|p| p == user)
+    }
+
+    /// The participant that is not `user`.
+    pub fn peer(&self, user: &str) -> Option<&str> {
+        self.participants
+            .iter()
+            .find(|p| *p != user)
+            .map(String::as_str)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageDoc {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    pub conversation: ObjectId,
+    pub sender: String,
+    pub recipient: String,
+    /// Plain text, or base64 ciphertext the server never decrypts when `encrypted`.
+    pub body: String,
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Opaque session setup data of encrypted messages, relayed as is.
+    pub header: Option<String>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime,
+    #[serde(rename = "deliveredAt")]
+    pub delivered_at: Option<DateTime>,
+}
+
+impl DbState {
+    /// The conversation between `a` and `b`, created on first use.
+    pub async fn conversation(
+        &self,
+        a: &str,
+        b: &str,
+    ) -> Result<ConversationDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ConversationDoc> = db.collection("conversations");
+        let opts = IndexOptions::builder().unique(true).build();
+        let index1 = IndexModel::builder()
+            .keys(doc! {"key": 1})
+            .options(opts)
+            .build();
+        let index2 = IndexModel::builder()
+            .keys(doc! {"participants": 1, "lastMessageAt": -1})
+            .build();
+        let _idx = coll.create_indexes([index1, index2]).await?;
+
+        let mut participants = vec![a.to_owned(), b.to_owned()];
+        participants.sort();
+        let key = participants.join("\n");
+        let conv = coll
+            .find_one_and_update(
+                doc! {"key": &key},
+                doc! {"$setOnInsert": {
+                    "_id": ObjectId::new(),
+                    "key": &key,
+                    "participants": &participants,
+                    "createdAt": DateTime::now(),
+                    "lastMessageAt": null,
+                }},
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?;
+        conv.ok_or("Failed to open conversation".into())
+    }
+
+    pub async fn get_conversation(
+        &self,
+        cid: ObjectId,
+    ) -> Result<ConversationDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ConversationDoc> = db.collection("conversations");
+        match coll.find_one(doc! {"_id": cid}).await? {
+            Some(c) => Ok(c),
+            None => Err("No conversation found".into()),
+        }
+    }
+
+    pub async fn user_conversations(
+        &self,
+        user: &str,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<ConversationDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ConversationDoc> = db.collection("conversations");
+        let mut cursor = coll
+            .find(doc! {"participants": user})
+            .sort(doc! {"lastMessageAt": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut convs = Vec::new();
+        while cursor.advance().await? {
+            convs.push(cursor.deserialize_current()?);
+        }
+        Ok(convs)
+    }
+
+    /// Everyone `user` ever had a conversation with.
+    pub async fn conversation_peers(
+        &self,
+        user: &str,
+    ) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ConversationDoc> = db.collection("conversations");
+        let mut cursor = coll.find(doc! {"participants": user}).await?;
+        let mut peers = Vec::new();
+        while cursor.advance().await? {
+            let conv: ConversationDoc = cursor.deserialize_current()?;
+            peers.extend(conv.peer(user).map(str::to_owned));
+        }
+        Ok(peers)
+    }
+
+    pub async fn add_message(
+        &self,
+        conv: &ConversationDoc,
+        sender: &str,
+        body: &str,
+        encrypted: bool,
+        header: Option<&str>,
+    ) -> Result<MessageDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<MessageDoc> = db.collection("messages");
+        let index1 = IndexModel::builder()
+            .keys(doc! {"conversation": 1, "createdAt": -1})
+            .build();
+        let index2 = IndexModel::builder()
+            .keys(doc! {"recipient": 1, "deliveredAt": 1})
+            .build();
+        let _idx = coll.create_indexes([index1, index2]).await?;
+
+        let msg = MessageDoc {
+            oid: ObjectId::new(),
+            conversation: conv.oid,
+            sender: sender.to_owned(),
+            recipient: conv.peer(sender).unwrap_or(sender).to_owned(),
+            body: body.to_owned(),
+            encrypted,
+            header: header.map(str::to_owned),
+            created_at: DateTime::now(),
+            delivered_at: None,
+        };
+        coll.insert_one(&msg).await?;
+
+        let convs: Collection<ConversationDoc> = db.collection("conversations");
+        convs
+            .update_one(
+                doc! {"_id": conv.oid},
+                doc! {"$set": {"lastMessageAt": msg.created_at}},
+            )
+            .await?;
+        Ok(msg)
+    }
+
+    pub async fn get_message(
+        &self,
+        cid: ObjectId,
+        mid: ObjectId,
+    ) -> Result<MessageDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<MessageDoc> = db.collection("messages");
+        match coll
+            .find_one(doc! {"_id": mid, "conversation": cid})
+            .await?
+        {
+            Some(m) => Ok(m),
+            None => Err("No message found".into()),
+        }
+    }
+
+    pub async fn conversation_messages(
+        &self,
+        cid: ObjectId,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<MessageDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<MessageDoc> = db.collection("messages");
+        let mut cursor = coll
+            .find(doc! {"conversation": cid})
+            .sort(doc! {"createdAt": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut msgs = Vec::new();
+        while cursor.advance().await? {
+            msgs.push(cursor.deserialize_current()?);
+        }
+        Ok(msgs)
+    }
+
+    /// Messages sent to `user` while none of their sessions was online, oldest first.
+    pub async fn undelivered_messages(
+        &self,
+        user: &str,
+    ) -> Result<Vec<MessageDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<MessageDoc> = db.collection("messages");
+        let mut cursor = coll
+            .find(doc! {"recipient": user, "deliveredAt": null})
+            .sort(doc! {"createdAt": 1})
+            .await?;
+        let mut msgs = Vec::new();
+        while cursor.advance().await? {
+            msgs.push(cursor.deserialize_current()?);
+        }
+        Ok(msgs)
+    }
+
+    pub async fn mark_delivered(
+        &self,
+        mids: &[ObjectId],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<MessageDoc> = db.collection("messages");
+        coll.update_many(
+            doc! {"_id": {"$in": mids}, "deliveredAt": null},
+            doc! {"$set": {"deliveredAt": DateTime::now()}},
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use super::DbState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupKind {
+    Site,
+    Customer,
+    Label,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupDoc {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    pub owner: ObjectId,
+    pub name: String,
+    pub kind: GroupKind,
+    pub devices: Vec<String>,
+    /// Users who may watch every device in the group.
+    #[serde(default)]
+    pub viewers: Vec<ObjectId>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime,
+}
+
+impl DbState {
+    pub async fn create_group(
+        &self,
+        owner: &ObjectId,
+        name: &str,
+        kind: GroupKind,
+        devices: Vec<String>,
+    ) -> Result<GroupDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<GroupDoc> = db.collection("device_groups");
+        let opts = IndexOptions::builder().unique(true).build();
+        let index = IndexModel::builder()
+            .keys(doc! {"owner": 1, "name": 1})
+            .options(opts)
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        let group = GroupDoc {
+            oid: ObjectId::new(),
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            kind,
+            devices,
+            viewers: Vec::new(),
+            created_at: DateTime::now(),
+        };
+        coll.insert_one(&group).await?;
+        Ok(group)
+    }
+
+    pub async fn get_group(
+        &self,
+        owner: &ObjectId,
+        gid: ObjectId,
+    ) -> Result<GroupDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<GroupDoc> = db.collection("device_groups");
+        match coll.find_one(doc! {"_id": gid, "owner": owner}).await? {
+            Some(g) => Ok(g),
+            None => Err("No group found".into()),
+        }
+    }
+
+    pub async fn list_groups(
+        &self,
+        owner: &ObjectId,
+    ) -> Result<Vec<GroupDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<GroupDoc> = db.collection("device_groups");
+        let mut cursor = coll
+            .find(doc! {"owner": owner})
+            .sort(doc! {"name": 1})
+            .await?;
+        let mut groups = Vec::new();
+        while cursor.advance().await? {
+            groups.push(cursor.deserialize_current()?);
+        }
+        Ok(groups)
+    }
+
+    pub async fn update_group_devices(
+        &self,
+        owner: &ObjectId,
+        gid: ObjectId,
+        add: &[String],
+        remove: &[String],
+    ) -> Result<GroupDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<GroupDoc> = db.collection("device_groups");
+        // A single update can't $addToSet and $pull on the same field.
+        coll.update_one(
+            doc! {"_id": gid, "owner": owner},
+            doc! {"$addToSet": {"devices": {"$each": add}}},
+        )
+        .await?;
+        coll.update_one(
+            doc! {"_id": gid, "owner": owner},
+            doc! {"$pull": {"devices": {"$in": remove}}},
+        )
+        .await?;
+        self.get_group(owner, gid).await
+    }
+
+    pub async fn update_group_viewers(
+        &self,
+        owner: &ObjectId,
+        gid: ObjectId,
+        add: &[ObjectId],
+        remove: &[ObjectId],
+    ) -> Result<GroupDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<GroupDoc> = db.collection("device_groups");
+        coll.update_one(
+            doc! {"_id": gid, "owner": owner},
+            doc! {"$addToSet": {"viewers": {"$each": add}}},
+        )
+        .await?;
+        coll.update_one(
+            doc! {"_id": gid, "owner": owner},
+            doc! {"$pull": {"viewers": {"$in": remove}}},
+        )
+        .await?;
+        self.get_group(owner, gid).await
+    }
+
+    pub async fn delete_group(
+        &self,
+        owner: &ObjectId,
+        gid: ObjectId,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<GroupDoc> = db.collection("device_groups");
+        let res = coll.delete_one(doc! {"_id": gid, "owner": owner}).await?;
+        if res.deleted_count == 0 {
+            return Err("No group found".into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, doc, to_bson},
+    options::{IndexOptions, ReturnDocument},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use super::DbState;
+
+// Bundles a user may fetch per window, of one peer and of everyone together.
+// Each fetch burns a one-time prekey of the target.
+const BUNDLES_PER_PEER: u32 = 3;
+const BUNDLES_PER_USER: u32 = 30;
+const BUNDLE_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Public half of a prekey. The server never sees private keys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PreKey {
+    pub id: u32,
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignedPreKey {
+    pub id: u32,
+    pub key: String,
+    pub signature: String,
+}
+
+/// Public keys a user published for end-to-end encrypted messages.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyBundle {
+    pub user: String,
+    #[serde(rename = "identityKey")]
+    pub identity_key: String,
+    #[serde(rename = "signedPreKey")]
+    pub signed_prekey: SignedPreKey,
+    #[serde(rename = "oneTimePreKeys")]
+    pub one_time_prekeys: Vec<PreKey>,
+    #[serde(rename = "identityChangedAt")]
+    pub identity_changed_at: DateTime,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime,
+}
+
+const MAX_PREKEYS: usize = 200;
+
+impl DbState {
+    /// Publish identity and signed prekey. Returns whether the identity key changed.
+    pub async fn publish_keys(
+        &self,
+        user: &str,
+        identity_key: &str,
+        signed_prekey: &SignedPreKey,
+        one_time_prekeys: &[PreKey],
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<KeyBundle> = db.collection("user_keys");
+        let opts = IndexOptions::builder().unique(true).build();
+        let index = IndexModel::builder()
+            .keys(doc! {"user": 1})
+            .options(opts)
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        let now = DateTime::now();
+        let previous = coll.find_one(doc! {"user": user}).await?;
+        let changed = previous
+            .as_ref()
+            .is_some_and(|p| p.identity_key != identity_key);
+        // A new identity invalidates every prekey signed by the old one.
+        let (prekeys, identity_changed_at) = match previous {
+            Some(p) if !changed => (p.one_time_prekeys, p.identity_changed_at),
+            _ => (Vec::new(), now),
+        };
+        let bundle = KeyBundle {
+            user: user.to_owned(),
+            identity_key: identity_key.to_owned(),
+            signed_prekey: signed_prekey.clone(),
+            one_time_prekeys: merge_prekeys(prekeys, one_time_prekeys),
+            identity_changed_at,
+            updated_at: now,
+        };
+        coll.replace_one(doc! {"user": user}, &bundle)
+            .upsert(true)
+            .await?;
+        Ok(changed)
+    }
+
+    pub async fn add_prekeys(
+        &self,
+        user: &str,
+        prekeys: &[PreKey],
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<KeyBundle> = db.collection("user_keys");
+        let bundle = coll
+            .find_one(doc! {"user": user})
+            .await?
+            .ok_or("No keys published")?;
+        let merged = merge_prekeys(bundle.one_time_prekeys, prekeys);
+        let count = merged.len();
+        coll.update_one(
+            doc! {"user": user},
+            doc! {"$set": {"oneTimePreKeys": to_bson(&merged)?, "updatedAt": DateTime::now()}},
+        )
+        .await?;
+        Ok(count)
+    }
+
+    /// Count a bundle fetch of `requester` for `target`. `false` once either
+    /// limit of the current window is used up.
+    pub fn may_fetch_bundle(&self, requester: &str, target: &str) -> bool {
+        let mut claims = self.bundle_claims.lock().unwrap_or_else(|e| e.into_inner());
+        claims.retain(|_, (_, since)| since.elapsed() < BUNDLE_WINDOW);
+        let limits = [
+            (requester.to_owned(), BUNDLES_PER_USER),
+            (format!("{requester}\n{target}"), BUNDLES_PER_PEER),
+        ];
+        if limits
+            .iter()
+            .any(|(key, max)| claims.get(key).is_some_and(|(n, _)| n >= max))
+        {
+            return false;
+        }
+        for (key, _) in limits {
+            claims.entry(key).or_insert((0, Instant::now())).0 += 1;
+        }
+        true
+    }
+
+    /// Fetch the bundle of `user` for starting a session. One one-time prekey
+    /// is handed out and removed, so no two senders get the same one.
+    pub async fn claim_key_bundle(
+        &self,
+        user: &str,
+    ) -> Result<KeyBundle, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<KeyBundle> = db.collection("user_keys");
+        let before = coll
+            .find_one_and_update(doc! {"user": user}, doc! {"$pop": {"oneTimePreKeys": -1}})
+            .return_document(ReturnDocument::Before)
+            .await?
+            .ok_or("No keys published")?;
+        // `$pop -1` removed the first prekey, which is the one handed out.
+        let mut bundle = before;
+        bundle.one_time_prekeys.truncate(1);
+        Ok(bundle)
+    }
+
+    pub async fn prekey_count(&self, user: &str) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<KeyBundle> = db.collection("user_keys");
+        let bundle = coll.find_one(doc! {"user": user}).await?;
+        Ok(bundle.map(|b| b.one_time_prekeys.len()).unwrap_or(0))
+    }
+}
+
+// Add `new` prekeys, replacing ones with the same id, up to `MAX_PREKEYS`.
+fn merge_prekeys(mut current: Vec<PreKey>, new: &[PreKey]) -> Vec<PreKey> {
+    current.retain(|k| !new.iter().any(|n| n.id == k.id));
+    current.extend(new.iter().cloned());
+    let excess = current.len().saturating_sub(MAX_PREKEYS);
+    current.drain(..excess);
+    current
+}
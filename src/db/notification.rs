@@ -0,0 +1,120 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId},
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use super::DbState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationKind {
+    Reply,
+    Quote,
+    Mention,
+}
+
+/// Tells `user` that a post concerns them. The author is left out on purpose.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationDoc {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    pub user: ObjectId,
+    pub kind: NotificationKind,
+    pub topic: ObjectId,
+    pub post: ObjectId,
+    pub read: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime,
+}
+
+impl NotificationDoc {
+    pub fn new(user: ObjectId, kind: NotificationKind, topic: ObjectId, post: ObjectId) -> Self {
+        Self {
+            oid: ObjectId::new(),
+            user,
+            kind,
+            topic,
+            post,
+            read: false,
+            created_at: DateTime::now(),
+        }
+    }
+}
+
+impl DbState {
+    pub async fn add_notifications(
+        &self,
+        notes: &[NotificationDoc],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if notes.is_empty() {
+            return Ok(());
+        }
+        let db = self.db()?;
+        let coll: Collection<NotificationDoc> = db.collection("notifications");
+        let index = IndexModel::builder()
+            .keys(doc! {"user": 1, "read": 1, "createdAt": -1})
+            .build();
+        let _idx = coll.create_index(index).await?;
+        coll.insert_many(notes).await?;
+        Ok(())
+    }
+
+    pub async fn user_notifications(
+        &self,
+        user: ObjectId,
+        unread_only: bool,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<NotificationDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<NotificationDoc> = db.collection("notifications");
+        let mut filter = doc! {"user": user};
+        if unread_only {
+            filter.insert("read", false);
+        }
+        let mut cursor = coll
+            .find(filter)
+            .sort(doc! {"createdAt": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut notes = Vec::new();
+        while cursor.advance().await? {
+            notes.push(cursor.deserialize_current()?);
+        }
+        Ok(notes)
+    }
+
+    pub async fn unread_notifications(
+        &self,
+        user: ObjectId,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<NotificationDoc> = db.collection("notifications");
+        let count = coll
+            .count_documents(doc! {"user": user, "read": false})
+            .await?;
+        Ok(count)
+    }
+
+    /// Mark one notification read, or all of them when `nid` is `None`.
+    /// Returns how many changed.
+    pub async fn mark_notifications_read(
+        &self,
+        user: ObjectId,
+        nid: Option<ObjectId>,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<NotificationDoc> = db.collection("notifications");
+        let mut filter: Document = doc! {"user": user, "read": false};
+        if let Some(nid) = nid {
+            filter.insert("_id", nid);
+        }
+        let res = coll
+            .update_many(filter, doc! {"$set": {"read": true}})
+            .await?;
+        Ok(res.modified_count)
+    }
+}
@@ -0,0 +1,214 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, doc, oid::ObjectId, to_bson},
+    options::ReturnDocument,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, error::Error};
+
+use super::{Attachment, DbState, Tally};
+use crate::markup::{Rendered, render};
+
+/// A reply in a topic. `quotes` are the posts it answers to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PostDoc {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    pub topic: ObjectId,
+    pub author: ObjectId,
+    #[serde(default)]
+    pub pseudonym: String,
+    pub content: String,
+    /// HTML of `content`, missing on posts written before rendering existed.
+    pub rendered: Option<Rendered>,
+    pub quotes: Vec<ObjectId>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(default)]
+    pub votes: Tally,
+    #[serde(default)]
+    pub reactions: HashMap<String, i64>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime,
+    #[serde(rename = "editedAt")]
+    pub edited_at: Option<DateTime>,
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: Option<DateTime>,
+}
+
+/// Poster id of `user` in one topic. Stable within the topic, unlinkable across topics.
+pub fn pseudonym(secret: &str, user: &str, topic: ObjectId) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(secret.as_bytes());
+    hasher.update(topic.bytes());
+    hasher.update(user.as_bytes());
+    format!("ID:{}", &hex::encode(hasher.finalize())[..8])
+}
+
+// A post notifies at most this many mentioned users, later mentions stay plain text.
+const MAX_MENTIONS: usize = 10;
+
+/// The first distinct names mentioned as `@name` in a post, up to `MAX_MENTIONS`.
+pub fn mentions(content: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let words = content
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '@'))
+        .filter_map(|w| w.strip_prefix('@'))
+        .filter(|n| !n.is_empty() && !n.contains('@'));
+    for name in words {
+        if names.len() == MAX_MENTIONS {
+            break;
+        }
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_owned());
+        }
+    }
+    names
+}
+
+impl DbState {
+    pub async fn new_post(
+        &self,
+        topic: ObjectId,
+        author: ObjectId,
+        pseudonym: String,
+        content: &str,
+        quotes: Vec<ObjectId>,
+    ) -> Result<PostDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<PostDoc> = db.collection("posts");
+        let index = IndexModel::builder()
+            .keys(doc! {"topic": 1, "createdAt": 1})
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        let post = PostDoc {
+            oid: ObjectId::new(),
+            topic,
+            author,
+            pseudonym,
+            content: content.to_owned(),
+            rendered: Some(render(content)),
+            quotes,
+            attachments: Vec::new(),
+            votes: Tally::default(),
+            reactions: HashMap::new(),
+            created_at: DateTime::now(),
+            edited_at: None,
+            deleted_at: None,
+        };
+        coll.insert_one(&post).await?;
+        Ok(post)
+    }
+
+    /// Live posts of `topic`, leaving out those of `hidden` authors.
+    pub async fn topic_posts(
+        &self,
+        topic: ObjectId,
+        hidden: &[ObjectId],
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<PostDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<PostDoc> = db.collection("posts");
+        let mut cursor = coll
+            .find(doc! {"topic": topic, "deletedAt": null, "author": {"$nin": hidden}})
+            .sort(doc! {"createdAt": 1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut posts = Vec::new();
+        while cursor.advance().await? {
+            posts.push(cursor.deserialize_current()?);
+        }
+        Ok(posts)
+    }
+
+    /// Posts of `topic` among `pids`. Quotes of posts elsewhere are ignored.
+    pub async fn find_posts(
+        &self,
+        topic: ObjectId,
+        pids: &[ObjectId],
+    ) -> Result<Vec<PostDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<PostDoc> = db.collection("posts");
+        let mut cursor = coll
+            .find(doc! {"topic": topic, "_id": {"$in": pids}, "deletedAt": null})
+            .await?;
+        let mut posts = Vec::new();
+        while cursor.advance().await? {
+            posts.push(cursor.deserialize_current()?);
+        }
+        Ok(posts)
+    }
+
+    pub async fn get_post(
+        &self,
+        topic: ObjectId,
+        pid: ObjectId,
+    ) -> Result<PostDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<PostDoc> = db.collection("posts");
+        match coll
+            .find_one(doc! {"_id": pid, "topic": topic, "deletedAt": null})
+            .await?
+        {
+            Some(p) => Ok(p),
+            None => Err("No post found".into()),
+        }
+    }
+
+    pub async fn edit_post(
+        &self,
+        pid: ObjectId,
+        content: &str,
+    ) -> Result<PostDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<PostDoc> = db.collection("posts");
+        let post = coll
+            .find_one_and_update(
+                doc! {"_id": pid, "deletedAt": null},
+                doc! {"$set": {
+                    "content": content,
+                    "rendered": to_bson(&render(content))?,
+                    "editedAt": DateTime::now(),
+                }},
+            )
+            .return_document(ReturnDocument::After)
+            .await?;
+        post.ok_or("No post found".into())
+    }
+
+    pub async fn set_post_rendered(
+        &self,
+        pid: ObjectId,
+        rendered: &Rendered,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<PostDoc> = db.collection("posts");
+        coll.update_one(
+            doc! {"_id": pid},
+            doc! {"$set": {"rendered": to_bson(rendered)?}},
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Deleted posts stay around so quotes of them can still be resolved.
+    pub async fn delete_post(
+        &self,
+        pid: ObjectId,
+    ) -> Result<PostDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<PostDoc> = db.collection("posts");
+        let post = coll
+            .find_one_and_update(
+                doc! {"_id": pid, "deletedAt": null},
+                doc! {"$set": {"deletedAt": DateTime::now()}},
+            )
+            .return_document(ReturnDocument::After)
+            .await?;
+        post.ok_or("No post found".into())
+    }
+}
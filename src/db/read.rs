@@ -0,0 +1,156 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId, to_bson},
+    options::{IndexOptions, ReturnDocument},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error};
+
+use super::{DbState, MessageDoc, PostDoc};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadTarget {
+    Topic,
+    Conversation,
+}
+
+/// Last post or message `user` has read in a topic or conversation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadMarker {
+    pub user: ObjectId,
+    pub kind: ReadTarget,
+    pub target: ObjectId,
+    #[serde(rename = "lastRead")]
+    pub last_read: ObjectId,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime,
+}
+
+impl DbState {
+    /// Move the marker forward to `last`. Returns `false` if it already was at or past it.
+    pub async fn set_read_marker(
+        &self,
+        user: ObjectId,
+        kind: ReadTarget,
+        target: ObjectId,
+        last: ObjectId,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ReadMarker> = db.collection("read_markers");
+        let opts = IndexOptions::builder().unique(true).build();
+        let index = IndexModel::builder()
+            .keys(doc! {"user": 1, "target": 1})
+            .options(opts)
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        // Object ids grow over time, so `$max` never moves a marker backwards.
+        let before = coll
+            .find_one_and_update(
+                doc! {"user": user, "target": target},
+                doc! {
+                    "$max": {"lastRead": last},
+                    "$set": {"kind": to_bson(&kind)?, "updatedAt": DateTime::now()},
+                },
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::Before)
+            .await?;
+        Ok(before.is_none_or(|m| m.last_read < last))
+    }
+
+    /// Markers of `user` among `targets`, by target.
+    pub async fn read_markers(
+        &self,
+        user: ObjectId,
+        targets: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, ObjectId>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ReadMarker> = db.collection("read_markers");
+        let mut cursor = coll
+            .find(doc! {"user": user, "target": {"$in": targets}})
+            .await?;
+        let mut markers = HashMap::new();
+        while cursor.advance().await? {
+            let m: ReadMarker = cursor.deserialize_current()?;
+            markers.insert(m.target, m.last_read);
+        }
+        Ok(markers)
+    }
+
+    /// Live posts of others after the marker of `reader`, per topic. Topics
+    /// without a marker count every post.
+    pub async fn unread_posts(
+        &self,
+        topics: &[ObjectId],
+        reader: ObjectId,
+        markers: &HashMap<ObjectId, ObjectId>,
+    ) -> Result<HashMap<ObjectId, u64>, Box<dyn Error + Send + Sync>> {
+        if topics.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let db = self.db()?;
+        let coll: Collection<PostDoc> = db.collection("posts");
+        let filter = doc! {
+            "$or": after_markers("topic", topics, markers),
+            "author": {"$ne": reader},
+            "deletedAt": null,
+        };
+        count_per(&coll, filter, "$topic").await
+    }
+
+    /// Messages to `reader` after their marker, per conversation.
+    pub async fn unread_messages(
+        &self,
+        conversations: &[ObjectId],
+        reader: &str,
+        markers: &HashMap<ObjectId, ObjectId>,
+    ) -> Result<HashMap<ObjectId, u64>, Box<dyn Error + Send + Sync>> {
+        if conversations.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let db = self.db()?;
+        let coll: Collection<MessageDoc> = db.collection("messages");
+        let filter = doc! {
+            "$or": after_markers("conversation", conversations, markers),
+            "recipient": reader,
+        };
+        count_per(&coll, filter, "$conversation").await
+    }
+}
+
+// One clause per target, matching what came after its marker (or all of it).
+fn after_markers(
+    field: &str,
+    targets: &[ObjectId],
+    markers: &HashMap<ObjectId, ObjectId>,
+) -> Vec<Document> {
+    targets
+        .iter()
+        .map(|t| match markers.get(t) {
+            Some(last) => doc! {field: t, "_id": {"$gt": last}},
+            None => doc! {field: t},
+        })
+        .collect()
+}
+
+// Count what matches `filter` in a single pass, grouped by the id at `key`.
+async fn count_per<T: Send + Sync>(
+    coll: &Collection<T>,
+    filter: Document,
+    key: &str,
+) -> Result<HashMap<ObjectId, u64>, Box<dyn Error + Send + Sync>> {
+    let pipeline = [
+        doc! {"$match": filter},
+        doc! {"$group": {"_id": key, "count": {"$sum": 1}}},
+    ];
+    let mut cursor = coll.aggregate(pipeline).await?;
+    let mut counts = HashMap::new();
+    while cursor.advance().await? {
+        let d: Document = cursor.deserialize_current()?;
+        let count = d.get_i32("count").map(i64::from).or(d.get_i64("count"))?;
+        counts.insert(d.get_object_id("_id")?, u64::try_from(count)?);
+    }
+    Ok(counts)
+}
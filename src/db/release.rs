@@ -0,0 +1,367 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, doc, oid::ObjectId, to_bson},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, error::Error};
+
+use super::{DbState, DeviceDoc, GroupDoc};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseStatus {
+    Draft,
+    Rolling,
+    Halted,
+}
+
+/// Which devices are offered a release. A device qualifies through any rule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rollout {
+    pub percentage: u8,
+    pub groups: Vec<ObjectId>,
+    pub canary: Vec<String>,
+    /// Share of failed reports (0.0 - 1.0) that halts the rollout.
+    #[serde(rename = "failureThreshold")]
+    pub failure_threshold: f64,
+    /// Failures are only judged once this many devices reported a result.
+    #[serde(rename = "minReports")]
+    pub min_reports: u32,
+}
+
+impl Default for Rollout {
+    fn default() -> Self {
+        Self {
+            percentage: 0,
+            groups: Vec::new(),
+            canary: Vec::new(),
+            failure_threshold: 0.2,
+            min_reports: 5,
+        }
+    }
+}
+
+impl Rollout {
+    /// `group_members` holds the devices of every group in `self.groups`.
+    pub fn includes(&self, rid: &ObjectId, devid: &str, group_members: &HashSet<String>) -> bool {
+        if self.canary.iter().any(|c| c == devid) || group_members.contains(devid) {
+            return true;
+        }
+        // Stable per release, so a device stays in (or out of) the same percentage slice.
+        let digest = Sha256::new()
+            .chain_update(rid.bytes())
+            .chain_update(devid.as_bytes())
+            .finalize();
+        let bucket = u16::from_be_bytes([digest[0], digest[1]]) % 100;
+        bucket < self.percentage as u16
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReleaseDoc {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    pub owner: ObjectId,
+    pub version: String,
+    pub model: String,
+    pub checksum: String,
+    pub size: i64,
+    pub status: ReleaseStatus,
+    pub rollout: Rollout,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime,
+    #[serde(rename = "haltedAt")]
+    pub halted_at: Option<DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OtaState {
+    Offered,
+    Downloading,
+    Installing,
+    Success,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OtaReport {
+    pub release: ObjectId,
+    pub devid: String,
+    pub state: OtaState,
+    pub progress: Option<u8>,
+    pub error: Option<String>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime,
+}
+
+impl DbState {
+    pub async fn create_release(
+        &self,
+        oid: ObjectId,
+        owner: &ObjectId,
+        version: &str,
+        model: &str,
+        checksum: &str,
+        size: i64,
+    ) -> Result<ReleaseDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ReleaseDoc> = db.collection("releases");
+        let index = IndexModel::builder()
+            .keys(doc! {"model": 1, "status": 1, "createdAt": -1})
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        let release = ReleaseDoc {
+            oid,
+            owner: owner.to_owned(),
+            version: version.to_owned(),
+            model: model.to_owned(),
+            checksum: checksum.to_owned(),
+            size,
+            status: ReleaseStatus::Draft,
+            rollout: Rollout::default(),
+            created_at: DateTime::now(),
+            halted_at: None,
+        };
+        coll.insert_one(&release).await?;
+        Ok(release)
+    }
+
+    pub async fn get_release(
+        &self,
+        rid: ObjectId,
+    ) -> Result<ReleaseDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ReleaseDoc> = db.collection("releases");
+        match coll.find_one(doc! {"_id": rid}).await? {
+            Some(r) => Ok(r),
+            None => Err("No release found".into()),
+        }
+    }
+
+    pub async fn list_releases(
+        &self,
+        owner: &ObjectId,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<ReleaseDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ReleaseDoc> = db.collection("releases");
+        let mut cursor = coll
+            .find(doc! {"owner": owner})
+            .sort(doc! {"createdAt": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut releases = Vec::new();
+        while cursor.advance().await? {
+            releases.push(cursor.deserialize_current()?);
+        }
+        Ok(releases)
+    }
+
+    /// Newest-first releases of `owner` currently rolling out for a hardware model.
+    pub async fn rolling_releases(
+        &self,
+        owner: &ObjectId,
+        model: &str,
+    ) -> Result<Vec<ReleaseDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ReleaseDoc> = db.collection("releases");
+        let rolling = to_bson(&ReleaseStatus::Rolling)?;
+        let mut cursor = coll
+            .find(doc! {"owner": owner, "model": model, "status": rolling})
+            .sort(doc! {"createdAt": -1})
+            .await?;
+        let mut releases = Vec::new();
+        while cursor.advance().await? {
+            releases.push(cursor.deserialize_current()?);
+        }
+        Ok(releases)
+    }
+
+    pub async fn set_rollout(
+        &self,
+        rid: ObjectId,
+        rollout: &Rollout,
+        status: ReleaseStatus,
+    ) -> Result<ReleaseDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ReleaseDoc> = db.collection("releases");
+        coll.update_one(
+            doc! {"_id": rid},
+            doc! {"$set": {"rollout": to_bson(rollout)?, "status": to_bson(&status)?, "haltedAt": null}},
+        )
+        .await?;
+        self.get_release(rid).await
+    }
+
+    /// Halt a rolling release. Returns `false` if it was not rolling anymore.
+    pub async fn halt_release(&self, rid: ObjectId) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<ReleaseDoc> = db.collection("releases");
+        let res = coll
+            .update_one(
+                doc! {"_id": rid, "status": to_bson(&ReleaseStatus::Rolling)?},
+                doc! {"$set": {"status": to_bson(&ReleaseStatus::Halted)?, "haltedAt": DateTime::now()}},
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    /// Devices in the groups `gids` that belong to `owner`.
+    pub async fn group_members(
+        &self,
+        owner: &ObjectId,
+        gids: &[ObjectId],
+    ) -> Result<HashSet<String>, Box<dyn Error + Send + Sync>> {
+        if gids.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let db = self.db()?;
+        let coll: Collection<GroupDoc> = db.collection("device_groups");
+        let mut cursor = coll
+            .find(doc! {"_id": {"$in": gids}, "owner": owner})
+            .await?;
+        let mut members = HashSet::new();
+        while cursor.advance().await? {
+            members.extend(cursor.deserialize_current()?.devices);
+        }
+        Ok(members)
+    }
+
+    /// Whether `device` may be offered `rel`: it has the same owner and model and
+    /// falls inside the rollout.
+    pub async fn offers_release(
+        &self,
+        rel: &ReleaseDoc,
+        device: &DeviceDoc,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        if device.owner != rel.owner || device.model.as_deref() != Some(rel.model.as_str()) {
+            return Ok(false);
+        }
+        let members = self.group_members(&rel.owner, &rel.rollout.groups).await?;
+        Ok(rel.rollout.includes(&rel.oid, &device.devid, &members))
+    }
+
+    pub async fn upsert_report(
+        &self,
+        report: &OtaReport,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<OtaReport> = db.collection("ota_reports");
+        let index = IndexModel::builder()
+            .keys(doc! {"release": 1, "devid": 1})
+            .build();
+        let _idx = coll.create_index(index).await?;
+        coll.replace_one(
+            doc! {"release": report.release, "devid": &report.devid},
+            report,
+        )
+        .upsert(true)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn release_reports(
+        &self,
+        rid: ObjectId,
+    ) -> Result<Vec<OtaReport>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<OtaReport> = db.collection("ota_reports");
+        let mut cursor = coll
+            .find(doc! {"release": rid})
+            .sort(doc! {"updatedAt": -1})
+            .await?;
+        let mut reports = Vec::new();
+        while cursor.advance().await? {
+            reports.push(cursor.deserialize_current()?);
+        }
+        Ok(reports)
+    }
+
+    /// Finished (succeeded or failed) and failed report counts of a release.
+    pub async fn report_counts(
+        &self,
+        rid: ObjectId,
+    ) -> Result<(u64, u64), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<OtaReport> = db.collection("ota_reports");
+        let success = to_bson(&OtaState::Success)?;
+        let failed = to_bson(&OtaState::Failed)?;
+        let done = coll
+            .count_documents(doc! {"release": rid, "state": {"$in": [success, failed.clone()]}})
+            .await?;
+        let failed = coll
+            .count_documents(doc! {"release": rid, "state": failed})
+            .await?;
+        Ok((done, failed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices() -> impl Iterator<Item = String> {
+        (0..1000).map(|i| format!("box-{i}"))
+    }
+
+    #[test]
+    fn canary_and_groups_always_qualify() {
+        let rid = ObjectId::new();
+        let rollout = Rollout {
+            canary: vec!["canary".into()],
+            ..Rollout::default()
+        };
+        let members = HashSet::from(["grouped".to_string()]);
+        assert!(rollout.includes(&rid, "canary", &members));
+        assert!(rollout.includes(&rid, "grouped", &members));
+        assert!(!rollout.includes(&rid, "other", &members));
+    }
+
+    #[test]
+    fn percentage_bounds() {
+        let rid = ObjectId::new();
+        let none = HashSet::new();
+        let mut rollout = Rollout::default();
+        assert!(devices().all(|d| !rollout.includes(&rid, &d, &none)));
+        rollout.percentage = 100;
+        assert!(devices().all(|d| rollout.includes(&rid, &d, &none)));
+    }
+
+    #[test]
+    fn percentage_slice_is_stable_and_grows() {
+        let rid = ObjectId::new();
+        let none = HashSet::new();
+        let at = |percentage| {
+            let rollout = Rollout {
+                percentage,
+                ..Rollout::default()
+            };
+            devices()
+                .filter(|d| rollout.includes(&rid, d, &none))
+                .collect::<HashSet<_>>()
+        };
+        let half = at(50);
+        assert_eq!(half, at(50));
+        assert!((400..600).contains(&half.len()), "{}", half.len());
+        assert!(at(10).is_subset(&half));
+        assert!(half.is_subset(&at(90)));
+    }
+
+    #[test]
+    fn slices_differ_per_release() {
+        let none = HashSet::new();
+        let rollout = Rollout {
+            percentage: 50,
+            ..Rollout::default()
+        };
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        assert!(
+            devices().any(|d| rollout.includes(&a, &d, &none) != rollout.includes(&b, &d, &none))
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, from_document, oid::ObjectId},
+    options::IndexOptions,
+};
+use std::error::Error;
+
+use super::{DbState, PostDoc, topic::TopicDoc};
+
+/// Restrictions applied on top of the text query.
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilter {
+    pub channel: Option<ObjectId>,
+    pub pseudonym: Option<String>,
+    pub from: Option<DateTime>,
+    pub to: Option<DateTime>,
+    pub tags: Vec<String>,
+    pub by_date: bool,
+    /// Authors whose content must not show up, i.e. shadowbanned users.
+    pub hidden: Vec<ObjectId>,
+}
+
+impl SearchFilter {
+    fn created(&self) -> Option<Document> {
+        let mut range = Document::new();
+        if let Some(from) = self.from {
+            range.insert("$gte", from);
+        }
+        if let Some(to) = self.to {
+            range.insert("$lte", to);
+        }
+        (!range.is_empty()).then_some(range)
+    }
+
+    fn query(&self, q: &str) -> Document {
+        let mut query = doc! {"$text": {"$search": q}};
+        if let Some(created) = self.created() {
+            query.insert("createdAt", created);
+        }
+        if !self.hidden.is_empty() {
+            query.insert("author", doc! {"$nin": &self.hidden});
+        }
+        query
+    }
+
+    fn sort(&self) -> Document {
+        if self.by_date {
+            doc! {"createdAt": -1}
+        } else {
+            doc! {"score": {"$meta": "textScore"}, "createdAt": -1}
+        }
+    }
+}
+
+impl DbState {
+    pub async fn init_search(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let topics: Collection<Document> = db.collection("topics");
+        let opts = IndexOptions::builder()
+            .weights(doc! {"title": 3, "content": 1})
+            .build();
+        let index = IndexModel::builder()
+            .keys(doc! {"title": "text", "content": "text"})
+            .options(opts)
+            .build();
+        let _idx = topics.create_index(index).await?;
+
+        let posts: Collection<Document> = db.collection("posts");
+        let index = IndexModel::builder().keys(doc! {"content": "text"}).build();
+        let _idx = posts.create_index(index).await?;
+        Ok(())
+    }
+
+    /// Best `limit` topics matching `q`, with their text score.
+    pub async fn search_topics(
+        &self,
+        q: &str,
+        filter: &SearchFilter,
+        limit: i64,
+    ) -> Result<Vec<(TopicDoc, f64)>, Box<dyn Error + Send + Sync>> {
+        // Pseudonyms only exist on posts.
+        if filter.pseudonym.is_some() {
+            return Ok(Vec::new());
+        }
+        let mut query = filter.query(q);
+        if let Some(channel) = filter.channel {
+            query.insert("channel", channel);
+        }
+        if !filter.tags.is_empty() {
+            query.insert("tags", doc! {"$all": &filter.tags});
+        }
+        self.scored("topics", query, filter.sort(), &[], limit)
+            .await
+    }
+
+    /// Best `limit` live posts matching `q`, with their text score.
+    pub async fn search_posts(
+        &self,
+        q: &str,
+        filter: &SearchFilter,
+        limit: i64,
+    ) -> Result<Vec<(PostDoc, f64)>, Box<dyn Error + Send + Sync>> {
+        let mut query = filter.query(q);
+        query.insert("deletedAt", None::<DateTime>);
+        if let Some(pseudonym) = &filter.pseudonym {
+            query.insert("pseudonym", pseudonym);
+        }
+        // Posts only know their topic, so channel and tags go through it.
+        if filter.channel.is_some() || !filter.tags.is_empty() {
+            let mut topics = Document::new();
+            if let Some(channel) = filter.channel {
+                topics.insert("channel", channel);
+            }
+            if !filter.tags.is_empty() {
+                topics.insert("tags", doc! {"$all": &filter.tags});
+            }
+            let db = self.db()?;
+            let coll: Collection<Document> = db.collection("topics");
+            let tids: Vec<ObjectId> = coll
+                .distinct("_id", topics)
+                .await?
+                .into_iter()
+                .filter_map(|t| t.as_object_id())
+                .collect();
+            query.insert("topic", doc! {"$in": tids});
+        }
+        // Deleting a topic leaves its posts behind, they must not turn up on their own.
+        let live_topic = [
+            doc! {"$lookup": {
+                "from": "topics",
+                "localField": "topic",
+                "foreignField": "_id",
+                "pipeline": [{"$project": {"_id": 1}}],
+                "as": "liveTopic",
+            }},
+            doc! {"$match": {"liveTopic": {"$ne": []}}},
+            doc! {"$unset": "liveTopic"},
+        ];
+        self.scored("posts", query, filter.sort(), &live_topic, limit)
+            .await
+    }
+
+    // Matches of `query` best first, with `stages` run on the sorted matches
+    // before the limit is applied.
+    async fn scored<T: serde::de::DeserializeOwned>(
+        &self,
+        collection: &str,
+        query: Document,
+        sort: Document,
+        stages: &[Document],
+        limit: i64,
+    ) -> Result<Vec<(T, f64)>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<Document> = db.collection(collection);
+        let mut pipeline = vec![
+            doc! {"$match": query},
+            doc! {"$addFields": {"score": {"$meta": "textScore"}}},
+            doc! {"$sort": sort},
+        ];
+        pipeline.extend_from_slice(stages);
+        pipeline.push(doc! {"$limit": limit});
+        let mut cursor = coll.aggregate(pipeline).await?;
+        let mut found = Vec::new();
+        while cursor.advance().await? {
+            let mut d: Document = cursor.deserialize_current()?;
+            let score = d.remove("score").and_then(|s| s.as_f64()).unwrap_or(0.0);
+            found.push((from_document(d)?, score));
+        }
+        Ok(found)
+    }
+}
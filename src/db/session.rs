@@ -0,0 +1,132 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId, to_bson},
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use super::DbState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EndReason {
+    Hangup,
+    Full,
+    Leave,
+    Disconnect,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionDoc {
+    #[serde(rename = "_id")]
+    pub oid: ObjectId,
+    pub devid: String,
+    pub viewer: String,
+    pub uid: Option<ObjectId>,
+    pub user: Option<String>,
+    #[serde(rename = "startedAt")]
+    pub started_at: DateTime,
+    #[serde(rename = "endedAt")]
+    pub ended_at: Option<DateTime>,
+    #[serde(rename = "endReason")]
+    pub end_reason: Option<EndReason>,
+    pub speech: bool,
+}
+
+impl DbState {
+    pub async fn start_session(
+        &self,
+        devid: &str,
+        viewer: &str,
+        user: Option<(ObjectId, String)>,
+    ) -> Result<ObjectId, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<SessionDoc> = db.collection("call_sessions");
+        let index1 = IndexModel::builder()
+            .keys(doc! {"devid": 1, "startedAt": -1})
+            .build();
+        let index2 = IndexModel::builder()
+            .keys(doc! {"uid": 1, "startedAt": -1})
+            .build();
+        let _idx = coll.create_indexes([index1, index2]).await?;
+
+        let (uid, user) = user.unzip();
+        let session = SessionDoc {
+            oid: ObjectId::new(),
+            devid: devid.to_owned(),
+            viewer: viewer.to_owned(),
+            uid,
+            user,
+            started_at: DateTime::now(),
+            ended_at: None,
+            end_reason: None,
+            speech: false,
+        };
+        coll.insert_one(&session).await?;
+        Ok(session.oid)
+    }
+
+    /// Close a session. Sessions that were already closed keep their first end reason.
+    pub async fn end_session(
+        &self,
+        sid: ObjectId,
+        reason: EndReason,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<SessionDoc> = db.collection("call_sessions");
+        let reason = to_bson(&reason)?;
+        coll.update_one(
+            doc! {"_id": sid, "endedAt": null},
+            doc! {"$set": {"endedAt": DateTime::now(), "endReason": reason}},
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn mark_speech(&self, sid: ObjectId) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<SessionDoc> = db.collection("call_sessions");
+        coll.update_one(doc! {"_id": sid}, doc! {"$set": {"speech": true}})
+            .await?;
+        Ok(())
+    }
+
+    pub async fn device_sessions(
+        &self,
+        devid: &str,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<SessionDoc>, Box<dyn Error + Send + Sync>> {
+        self.find_sessions(doc! {"devid": devid}, limit, skip).await
+    }
+
+    pub async fn user_sessions(
+        &self,
+        uid: ObjectId,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<SessionDoc>, Box<dyn Error + Send + Sync>> {
+        self.find_sessions(doc! {"uid": uid}, limit, skip).await
+    }
+
+    async fn find_sessions(
+        &self,
+        filter: Document,
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<SessionDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<SessionDoc> = db.collection("call_sessions");
+        let mut cursor = coll
+            .find(filter)
+            .sort(doc! {"startedAt": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut sessions = Vec::new();
+        while cursor.advance().await? {
+            sessions.push(cursor.deserialize_current()?);
+        }
+        Ok(sessions)
+    }
+}
@@ -0,0 +1,190 @@
+use mongodb::{
+    Collection,
+    bson::{Bson, DateTime, Document, doc},
+    options::{TimeseriesGranularity, TimeseriesOptions},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error};
+
+use super::DbState;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sample {
+    pub ts: DateTime,
+    pub devid: String,
+    pub metrics: HashMap<String, f64>,
+}
+
+/// Aggregated values of one metric over one bucket.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Stats {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Bucket {
+    pub ts: i64,
+    pub metrics: HashMap<String, Stats>,
+}
+
+const MAX_METRICS: usize = 32;
+
+/// Metric names end up in field paths, so keep them to plain identifiers.
+pub fn valid_metric(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Sample {
+    pub fn new(
+        devid: &str,
+        ts: Option<i64>,
+        metrics: HashMap<String, f64>,
+    ) -> Result<Self, String> {
+        if metrics.is_empty() || metrics.len() > MAX_METRICS {
+            return Err(format!("Expected 1 to {MAX_METRICS} metrics"));
+        }
+        if let Some(bad) = metrics.keys().find(|k| !valid_metric(k)) {
+            return Err(format!("Invalid metric name: {bad}"));
+        }
+        if metrics.values().any(|v| !v.is_finite()) {
+            return Err("Metric values must be finite".to_string());
+        }
+        Ok(Self {
+            ts: ts.map(DateTime::from_millis).unwrap_or(DateTime::now()),
+            devid: devid.to_owned(),
+            metrics,
+        })
+    }
+}
+
+impl DbState {
+    /// Create the time-series collection on first start. Retention and
+    /// granularity are fixed at creation, later config changes need a manual `collMod`.
+    pub async fn init_telemetry(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let existing = db
+            .list_collection_names()
+            .filter(doc! {"name": "telemetry"})
+            .await?;
+        if !existing.is_empty() {
+            return Ok(());
+        }
+
+        let granularity = match self.config.telemetry_granularity().as_deref() {
+            Some("minutes") => TimeseriesGranularity::Minutes,
+            Some("hours") => TimeseriesGranularity::Hours,
+            _ => TimeseriesGranularity::Seconds,
+        };
+        let opts = TimeseriesOptions::builder()
+            .time_field("ts".to_string())
+            .meta_field(Some("devid".to_string()))
+            .granularity(Some(granularity))
+            .build();
+        db.create_collection("telemetry")
+            .timeseries(opts)
+            .expire_after_seconds(self.config.telemetry_retention())
+            .await?;
+        Ok(())
+    }
+
+    /// Store a sample unless the device already reported within the sample interval.
+    /// Returns whether the sample was kept.
+    pub async fn add_sample(&self, sample: &Sample) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let interval = self.config.telemetry_sample_interval().as_millis() as i64;
+        let ts = sample.ts.timestamp_millis();
+        {
+            let mut last = self
+                .last_samples
+                .lock()
+                .map_err(|_| "Telemetry state poisoned")?;
+            match last.get(&sample.devid) {
+                Some(prev) if ts - prev < interval && ts >= *prev => return Ok(false),
+                _ => {
+                    last.insert(sample.devid.to_owned(), ts);
+                }
+            }
+        }
+
+        let db = self.db()?;
+        let coll: Collection<Sample> = db.collection("telemetry");
+        coll.insert_one(sample).await?;
+        Ok(true)
+    }
+
+    /// Min/max/avg of `metrics` per `bucket_secs` wide bucket in `[from, to)`.
+    pub async fn telemetry_buckets(
+        &self,
+        devid: &str,
+        from: DateTime,
+        to: DateTime,
+        bucket_secs: u64,
+        metrics: &[String],
+    ) -> Result<Vec<Bucket>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<Sample> = db.collection("telemetry");
+
+        let mut group = doc! {
+            "_id": {"$dateTrunc": {"date": "$ts", "unit": "second", "binSize": bucket_secs as i64}},
+        };
+        for (i, m) in metrics.iter().enumerate() {
+            let field = format!("$metrics.{m}");
+            group.insert(format!("min{i}"), doc! {"$min": &field});
+            group.insert(format!("max{i}"), doc! {"$max": &field});
+            group.insert(format!("avg{i}"), doc! {"$avg": &field});
+        }
+        let pipeline = [
+            doc! {"$match": {"devid": devid, "ts": {"$gte": from, "$lt": to}}},
+            doc! {"$group": group},
+            doc! {"$sort": {"_id": 1}},
+        ];
+
+        let mut cursor = coll.aggregate(pipeline).await?;
+        let mut buckets = Vec::new();
+        while cursor.advance().await? {
+            let d: Document = cursor.deserialize_current()?;
+            let ts = d.get_datetime("_id")?.timestamp_millis();
+            let stats = metrics
+                .iter()
+                .enumerate()
+                .map(|(i, m)| {
+                    let get = |k: String| d.get(k).and_then(Bson::as_f64);
+                    let stats = Stats {
+                        min: get(format!("min{i}")),
+                        max: get(format!("max{i}")),
+                        avg: get(format!("avg{i}")),
+                    };
+                    (m.to_owned(), stats)
+                })
+                .collect();
+            buckets.push(Bucket { ts, metrics: stats });
+        }
+        Ok(buckets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metric_names() {
+        for name in ["cpu", "temp_c", "Fan2", &"x".repeat(64)] {
+            assert!(valid_metric(name), "{name}");
+        }
+        for name in [
+            "",
+            "cpu.load",
+            "$gt",
+            "a b",
+            "temp-c",
+            "température",
+            &"x".repeat(65),
+        ] {
+            assert!(!valid_metric(name), "{name}");
+        }
+    }
+}
@@ -1,11 +1,13 @@
 use mongodb::{
-    Collection,
-    bson::{DateTime, Document, doc, oid::ObjectId},
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId, to_bson},
+    options::ReturnDocument,
 };
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::{collections::HashMap, error::Error};
 
-use super::DbState;
+use super::{Attachment, DbState, Tally};
+use crate::markup::{Rendered, render};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TopicDoc {
@@ -15,15 +17,46 @@ pub struct TopicDoc {
     pub author: ObjectId,
     pub channel: ObjectId,
     pub content: String,
+    pub rendered: Option<Rendered>,
     // #[serde(
     //     serialize_with = "serialize_i64_as_bson_datetime",
     //     rename = "createdAt"
     // )]
     #[serde(rename = "createdAt")]
     pub created_at: DateTime,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(default)]
+    pub votes: Tally,
+    #[serde(default)]
+    pub reactions: HashMap<String, i64>,
+}
+
+/// Lowercase, `#`-less, words joined by `-`. `None` if nothing usable is left.
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let words: Vec<String> = tag
+        .trim()
+        .trim_start_matches('#')
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let tag = words.join("-");
+    (!tag.is_empty() && tag.chars().count() <= MAX_TAG_LEN).then_some(tag)
+}
+
+const MAX_TAG_LEN: usize = 32;
+
+impl TopicDoc {
+    pub fn tid(&self) -> ObjectId {
+        self.oid
+    }
 }
 
 impl DbState {
+    #[allow(dead_code)]
     pub async fn new_topic(
         &self,
         title: &str,
@@ -32,7 +65,8 @@ impl DbState {
     ) -> Result<ObjectId, Box<dyn Error + Send + Sync>> {
         let db = self.db()?;
         let coll: Collection<Document> = db.collection("topics");
-        let doc = doc! {"title": title, "author": author, "content": content, "createdAt": DateTime::now()};
+        let rendered = to_bson(&render(content))?;
+        let doc = doc! {"title": title, "author": author, "content": content, "rendered": rendered, "createdAt": DateTime::now()};
         let res = coll.insert_one(doc).await?;
 
         let topicoid = match res.inserted_id.as_object_id() {
@@ -42,6 +76,7 @@ impl DbState {
         Ok(topicoid)
     }
 
+    #[allow(dead_code)]
     pub async fn delete_topic(&self, tid: ObjectId) -> Result<(), Box<dyn Error + Send + Sync>> {
         let db = self.db()?;
         let coll: Collection<TopicDoc> = db.collection("topics");
@@ -49,6 +84,123 @@ impl DbState {
         Ok(())
     }
 
+    /// Newest topics of `channel`, leaving out those of `hidden` authors.
+    pub async fn channel_topics(
+        &self,
+        channel: ObjectId,
+        hidden: &[ObjectId],
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<TopicDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<TopicDoc> = db.collection("topics");
+        let mut cursor = coll
+            .find(doc! {"channel": channel, "author": {"$nin": hidden}})
+            .sort(doc! {"createdAt": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut topics = Vec::new();
+        while cursor.advance().await? {
+            topics.push(cursor.deserialize_current()?);
+        }
+        Ok(topics)
+    }
+
+    /// Channels only exist through their topics.
+    pub async fn channel_exists(
+        &self,
+        channel: ObjectId,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<TopicDoc> = db.collection("topics");
+        Ok(coll.find_one(doc! {"channel": channel}).await?.is_some())
+    }
+
+    pub async fn set_topic_tags(
+        &self,
+        tid: ObjectId,
+        tags: &[String],
+    ) -> Result<TopicDoc, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<TopicDoc> = db.collection("topics");
+        let index = IndexModel::builder()
+            .keys(doc! {"tags": 1, "createdAt": -1})
+            .build();
+        let _idx = coll.create_index(index).await?;
+        let topic = coll
+            .find_one_and_update(doc! {"_id": tid}, doc! {"$set": {"tags": tags}})
+            .return_document(ReturnDocument::After)
+            .await?;
+        topic.ok_or("No topic found".into())
+    }
+
+    /// Newest topics carrying `tag`, leaving out those of `hidden` authors.
+    pub async fn tagged_topics(
+        &self,
+        tag: &str,
+        hidden: &[ObjectId],
+        limit: i64,
+        skip: u64,
+    ) -> Result<Vec<TopicDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<TopicDoc> = db.collection("topics");
+        let mut cursor = coll
+            .find(doc! {"tags": tag, "author": {"$nin": hidden}})
+            .sort(doc! {"createdAt": -1})
+            .skip(skip)
+            .limit(limit)
+            .await?;
+        let mut topics = Vec::new();
+        while cursor.advance().await? {
+            topics.push(cursor.deserialize_current()?);
+        }
+        Ok(topics)
+    }
+
+    /// Most used tags starting with `prefix`, which must already be normalized.
+    pub async fn tag_counts(
+        &self,
+        prefix: &str,
+        limit: i64,
+    ) -> Result<Vec<(String, i64)>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<TopicDoc> = db.collection("topics");
+        // Normalized tags hold no regex metacharacters.
+        let pattern = format!("^{prefix}");
+        let pipeline = [
+            doc! {"$match": {"tags": {"$regex": &pattern}}},
+            doc! {"$unwind": "$tags"},
+            doc! {"$match": {"tags": {"$regex": &pattern}}},
+            doc! {"$group": {"_id": "$tags", "count": {"$sum": 1}}},
+            doc! {"$sort": {"count": -1, "_id": 1}},
+            doc! {"$limit": limit},
+        ];
+        let mut cursor = coll.aggregate(pipeline).await?;
+        let mut counts = Vec::new();
+        while cursor.advance().await? {
+            let d: Document = cursor.deserialize_current()?;
+            let count = d.get_i32("count").map(i64::from).or(d.get_i64("count"))?;
+            counts.push((d.get_str("_id")?.to_owned(), count));
+        }
+        Ok(counts)
+    }
+
+    pub async fn set_topic_rendered(
+        &self,
+        tid: ObjectId,
+        rendered: &Rendered,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<TopicDoc> = db.collection("topics");
+        coll.update_one(
+            doc! {"_id": tid},
+            doc! {"$set": {"rendered": to_bson(rendered)?}},
+        )
+        .await?;
+        Ok(())
+    }
+
     pub async fn get_topic(&self, tid: ObjectId) -> Result<TopicDoc, Box<dyn Error + Send + Sync>> {
         let db = self.db()?;
         let coll: Collection<TopicDoc> = db.collection("topics");
@@ -59,3 +211,24 @@ impl DbState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_normalized() {
+        assert_eq!(normalize_tag("Rust").as_deref(), Some("rust"));
+        assert_eq!(normalize_tag("  #Home Lab ").as_deref(), Some("home-lab"));
+        assert_eq!(normalize_tag("c++/wasm!").as_deref(), Some("c-wasm"));
+        assert_eq!(normalize_tag("Ünïcode_Tag").as_deref(), Some("ünïcode-tag"));
+    }
+
+    #[test]
+    fn empty_and_long_tags_are_refused() {
+        assert_eq!(normalize_tag(""), None);
+        assert_eq!(normalize_tag(" # !! "), None);
+        assert!(normalize_tag(&"é".repeat(MAX_TAG_LEN)).is_some());
+        assert_eq!(normalize_tag(&"a".repeat(MAX_TAG_LEN + 1)), None);
+    }
+}
@@ -4,7 +4,7 @@ use super::DbState;
 use bcrypt::{DEFAULT_COST, hash, verify};
 use mongodb::{
     Collection, IndexModel,
-    bson::{Document, doc, oid::ObjectId},
+    bson::{DateTime, Document, doc, oid::ObjectId, to_bson},
     options::IndexOptions,
 };
 use serde::{Deserialize, Serialize};
@@ -22,7 +22,7 @@ impl DbState {
         let coll: Collection<Document> = db.collection("users");
         let user_doc = coll.find_one(doc! {"email": &email}).await?;
 
-        if let Some(_) = user_doc {
+        if user_doc.is_some() {
             return Err("Already existed".into());
         }
 
@@ -95,6 +95,112 @@ impl DbState {
             Err(_) => Err("User doc query error".into()),
         }
     }
+
+    pub async fn find_users(
+        &self,
+        names: &[String],
+    ) -> Result<Vec<UserDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<UserDoc> = db.collection("users");
+        let mut cursor = coll.find(doc! {"name": {"$in": names}}).await?;
+        let mut users = Vec::new();
+        while cursor.advance().await? {
+            users.push(cursor.deserialize_current()?);
+        }
+        Ok(users)
+    }
+
+    pub async fn get_users(
+        &self,
+        uids: &[ObjectId],
+    ) -> Result<Vec<UserDoc>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<UserDoc> = db.collection("users");
+        let mut cursor = coll.find(doc! {"_id": {"$in": uids}}).await?;
+        let mut users = Vec::new();
+        while cursor.advance().await? {
+            users.push(cursor.deserialize_current()?);
+        }
+        Ok(users)
+    }
+
+    /// Ids of shadowbanned users other than `viewer`.
+    pub async fn shadowbanned_users(
+        &self,
+        viewer: Option<ObjectId>,
+    ) -> Result<Vec<ObjectId>, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<UserDoc> = db.collection("users");
+        let mut cursor = coll.find(doc! {"shadowbanned": true}).await?;
+        let mut banned = Vec::new();
+        while cursor.advance().await? {
+            let u: UserDoc = cursor.deserialize_current()?;
+            if Some(u.oid) != viewer {
+                banned.push(u.oid);
+            }
+        }
+        Ok(banned)
+    }
+
+    /// Status the user picked last time, `online` for users who never chose one.
+    pub async fn user_status(
+        &self,
+        uid: ObjectId,
+    ) -> Result<PresenceStatus, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<UserDoc> = db.collection("users");
+        let user = coll.find_one(doc! {"_id": uid}).await?;
+        Ok(user.and_then(|u| u.status).unwrap_or_default())
+    }
+
+    pub async fn set_user_status(
+        &self,
+        uid: ObjectId,
+        status: PresenceStatus,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<UserDoc> = db.collection("users");
+        coll.update_one(
+            doc! {"_id": uid},
+            doc! {"$set": {"status": to_bson(&status)?}},
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_read_receipts(
+        &self,
+        uid: ObjectId,
+        enabled: bool,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<UserDoc> = db.collection("users");
+        coll.update_one(doc! {"_id": uid}, doc! {"$set": {"readReceipts": enabled}})
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_last_seen(&self, uid: ObjectId) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<UserDoc> = db.collection("users");
+        coll.update_one(
+            doc! {"_id": uid},
+            doc! {"$set": {"lastSeen": DateTime::now()}},
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// What a user chose to show others while online.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    Invisible,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -104,4 +210,17 @@ pub struct UserDoc {
     password: String,
     #[serde(rename = "_id")]
     oid: ObjectId,
+    #[serde(rename = "lastSeen")]
+    pub last_seen: Option<DateTime>,
+    pub status: Option<PresenceStatus>,
+    #[serde(rename = "readReceipts")]
+    pub read_receipts: Option<bool>,
+    /// Set by moderators. Their content is hidden from everyone but themselves.
+    pub shadowbanned: Option<bool>,
+}
+
+impl UserDoc {
+    pub fn uid(&self) -> ObjectId {
+        self.oid
+    }
 }
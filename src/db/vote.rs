@@ -0,0 +1,249 @@
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId},
+    options::{IndexOptions, ReturnDocument},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error};
+use tracing::warn;
+
+use super::{DbState, is_duplicate_key};
+
+// Concurrent first votes of one user race on the unique index; the loser retries.
+const UPSERT_ATTEMPTS: usize = 3;
+
+/// Up and down votes of a topic or post, kept on the document itself.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Tally {
+    pub up: i64,
+    pub down: i64,
+}
+
+/// Aggregated counters as stored on a topic or post.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Counts {
+    #[serde(default)]
+    pub votes: Tally,
+    #[serde(default)]
+    pub reactions: HashMap<String, i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoteTarget {
+    Topic,
+    Post,
+}
+
+impl VoteTarget {
+    fn collection(self) -> &'static str {
+        match self {
+            Self::Topic => "topics",
+            Self::Post => "posts",
+        }
+    }
+}
+
+// Counters of the matched items, recomputed from `votes` and `reactions` and merged back.
+fn recount_pipeline(kind: VoteTarget, filter: Document) -> Vec<Document> {
+    let tally = |value: i32| {
+        doc! {"$sum": {"$map": {
+            "input": "$tally",
+            "in": {"$cond": [{"$eq": ["$$this._id", value]}, "$$this.n", 0]},
+        }}}
+    };
+    vec![
+        doc! {"$match": filter},
+        doc! {"$lookup": {
+            "from": "votes",
+            "let": {"target": "$_id"},
+            "pipeline": [
+                {"$match": {"$expr": {"$eq": ["$target", "$$target"]}}},
+                {"$group": {"_id": "$value", "n": {"$sum": 1}}},
+            ],
+            "as": "tally",
+        }},
+        doc! {"$lookup": {
+            "from": "reactions",
+            "let": {"target": "$_id"},
+            "pipeline": [
+                {"$match": {"$expr": {"$eq": ["$target", "$$target"]}}},
+                {"$group": {"_id": "$emoji", "n": {"$sum": 1}}},
+                {"$project": {"_id": 0, "k": "$_id", "v": "$n"}},
+            ],
+            "as": "reacted",
+        }},
+        doc! {"$project": {
+            "votes": {"up": tally(1), "down": tally(-1)},
+            "reactions": {"$arrayToObject": "$reacted"},
+        }},
+        doc! {"$merge": {
+            "into": kind.collection(),
+            "on": "_id",
+            "whenMatched": "merge",
+            "whenNotMatched": "discard",
+        }},
+    ]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct VoteDoc {
+    user: ObjectId,
+    target: ObjectId,
+    value: i32,
+    #[serde(rename = "updatedAt")]
+    updated_at: DateTime,
+}
+
+impl DbState {
+    /// Set the vote of `user` on an item to 1, -1 or 0 (withdrawn) and return its counters.
+    pub async fn cast_vote(
+        &self,
+        user: ObjectId,
+        kind: VoteTarget,
+        target: ObjectId,
+        value: i32,
+    ) -> Result<Counts, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<VoteDoc> = db.collection("votes");
+        let opts = IndexOptions::builder().unique(true).build();
+        let index = IndexModel::builder()
+            .keys(doc! {"user": 1, "target": 1})
+            .options(opts)
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        let filter = doc! {"user": user, "target": target};
+        let previous = if value == 0 {
+            coll.find_one_and_delete(filter).await?
+        } else {
+            let mut attempt = 0;
+            loop {
+                let res = coll
+                    .find_one_and_update(
+                        filter.clone(),
+                        doc! {"$set": {"value": value, "updatedAt": DateTime::now()}},
+                    )
+                    .upsert(true)
+                    .return_document(ReturnDocument::Before)
+                    .await;
+                attempt += 1;
+                match res {
+                    Ok(previous) => break previous,
+                    Err(e) if is_duplicate_key(&e) && attempt < UPSERT_ATTEMPTS => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        };
+        // Counters move by the difference to the previous vote, so repeats change nothing.
+        let old = previous.map(|v| v.value).unwrap_or(0);
+        let up = i64::from(value == 1) - i64::from(old == 1);
+        let down = i64::from(value == -1) - i64::from(old == -1);
+        self.bump_counts(kind, target, doc! {"votes.up": up, "votes.down": down})
+            .await
+    }
+
+    /// Add or take back the `emoji` reaction of `user` and return the item's counters.
+    pub async fn react(
+        &self,
+        user: ObjectId,
+        kind: VoteTarget,
+        target: ObjectId,
+        emoji: &str,
+        active: bool,
+    ) -> Result<Counts, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<Document> = db.collection("reactions");
+        let opts = IndexOptions::builder().unique(true).build();
+        let index = IndexModel::builder()
+            .keys(doc! {"user": 1, "target": 1, "emoji": 1})
+            .options(opts)
+            .build();
+        let _idx = coll.create_index(index).await?;
+
+        let filter = doc! {"user": user, "target": target, "emoji": emoji};
+        let changed = if active {
+            let res = coll
+                .update_one(
+                    filter,
+                    doc! {"$setOnInsert": {"createdAt": DateTime::now()}},
+                )
+                .upsert(true)
+                .await;
+            match res {
+                Ok(res) => res.upserted_id.is_some(),
+                // A concurrent request inserted the same reaction first, so this one changed nothing.
+                Err(e) if is_duplicate_key(&e) => false,
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            coll.delete_one(filter).await?.deleted_count > 0
+        };
+        let delta = match (changed, active) {
+            (false, _) => 0,
+            (true, true) => 1,
+            (true, false) => -1,
+        };
+        self.bump_counts(kind, target, doc! {format!("reactions.{emoji}"): delta})
+            .await
+    }
+
+    // The vote is stored by now. If the counters cannot follow, rebuild them from
+    // the votes instead of leaving them off by one.
+    async fn bump_counts(
+        &self,
+        kind: VoteTarget,
+        target: ObjectId,
+        inc: Document,
+    ) -> Result<Counts, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<Counts> = db.collection(kind.collection());
+        let res = coll
+            .find_one_and_update(doc! {"_id": target}, doc! {"$inc": inc})
+            .projection(doc! {"votes": 1, "reactions": 1})
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(counts) => counts.ok_or("No item found".into()),
+            Err(e) => {
+                warn!("Failed to update counters of {target}, recounting: {e}");
+                self.recount(kind, target).await
+            }
+        }
+    }
+
+    /// Recompute the counters of one item from its votes and reactions.
+    pub async fn recount(
+        &self,
+        kind: VoteTarget,
+        target: ObjectId,
+    ) -> Result<Counts, Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        let coll: Collection<Counts> = db.collection(kind.collection());
+        coll.aggregate(recount_pipeline(kind, doc! {"_id": target}))
+            .await?;
+        let counts = coll
+            .find_one(doc! {"_id": target})
+            .projection(doc! {"votes": 1, "reactions": 1})
+            .await?;
+        counts.ok_or("No item found".into())
+    }
+
+    /// Index votes and reactions by item, then recompute the counters of every
+    /// item that has any, repairing drift left by writes cut short.
+    pub async fn init_votes(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let db = self.db()?;
+        for name in ["votes", "reactions"] {
+            let coll: Collection<Document> = db.collection(name);
+            let index = IndexModel::builder().keys(doc! {"target": 1}).build();
+            let _idx = coll.create_index(index).await?;
+        }
+        let counted =
+            doc! {"$or": [{"votes": {"$exists": true}}, {"reactions": {"$exists": true}}]};
+        for kind in [VoteTarget::Topic, VoteTarget::Post] {
+            let coll: Collection<Document> = db.collection(kind.collection());
+            coll.aggregate(recount_pipeline(kind, counted.clone()))
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -1,28 +1,59 @@
 mod api;
 mod config;
 mod db;
+mod markup;
+mod media;
 mod socketio;
+mod storage;
 
 use api::{
+    attachment::{attach_post, attach_topic, serve_file},
+    audit::{device_events, user_events},
     auth::{authorize, register},
-    discussion::topic,
+    boxconf::{config_history, get_config, put_config},
+    command::{command_status, list_commands, queue_command},
+    device::{claim, update_viewers},
+    discussion::{channel_topics, topic},
+    dm::{conversation_messages, list_conversations},
+    group::{
+        broadcast, create_group, delete_group, get_group, list_groups, presence, push_group_config,
+        update_group_viewers, update_members,
+    },
+    keys::{add_prekeys, key_bundle, prekey_count, publish_keys},
+    notification::{list_notifications, mark_all_read, mark_read, unread_count},
+    post::{create_post, delete_post, edit_post, list_posts},
+    read::{read_conversation, read_topic, set_receipts},
+    release::{artifact, get_release, list_releases, reports, rollout, upload},
+    search::search,
+    session::{device_sessions, user_sessions},
+    tag::{autocomplete, set_tags, tagged_topics},
+    telemetry::{ingest, query},
+    vote::{react_post, react_topic, vote_post, vote_topic},
 };
 use db::DbState;
 
-use axum::routing::{get, post};
+use axum::{
+    Extension,
+    extract::DefaultBodyLimit,
+    routing::{get, post, put},
+};
 
 use config::Config;
 use mongodb::Client;
 use socketioxide::SocketIo;
 use tower::ServiceBuilder;
 use tower_http::cors::{AllowOrigin, CorsLayer};
-use tracing::info;
+use tracing::{info, warn};
 
 use axum::http::{HeaderValue, request::Parts as RequestParts};
 use std::error::Error;
 use tracing_subscriber::fmt::time::ChronoLocal;
 
-use socketio::{OnlineDevs, OnlineUsers, on_connect};
+use socketio::{
+    OnlineDevs, OnlineUsers, RoomActivity, authenticate_legacy, authenticate_peer,
+    authenticate_user, on_box_connect, on_call_connect, on_chat_connect, on_connect,
+};
+use socketioxide::handler::ConnectHandler;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
@@ -38,23 +69,117 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     // Init mongodb connection
     let uri = config.mongo_uri().ok_or("mongodb uri not set")?;
     let mongo_client = Client::with_uri_str(uri).await?;
+    let onlinedevs = OnlineDevs::default().with_max_viewers(config.max_viewers());
+    let legacy_namespace = config.legacy_namespace();
     let db_state = DbState::new(config, mongo_client);
+    if let Err(e) = db_state.init_telemetry().await {
+        warn!("Failed to set up telemetry collection: {e}");
+    }
+    if let Err(e) = db_state.init_audit().await {
+        warn!("Failed to set up audit log: {e}");
+    }
+    if let Err(e) = db_state.init_search().await {
+        warn!("Failed to set up search indexes: {e}");
+    }
+    // Recounting reads every vote, so don't hold up startup for it.
+    let votes_state = db_state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = votes_state.init_votes().await {
+            warn!("Failed to recount votes: {e}");
+        }
+    });
 
     let (layer, io) = SocketIo::builder()
-        .with_state(OnlineDevs::default())
+        .with_state(onlinedevs.clone())
         .with_state(OnlineUsers::default())
+        .with_state(RoomActivity::default())
+        .with_state(db_state.clone())
         .build_layer();
 
-    io.ns("/", on_connect);
+    io.ns("/chat", on_chat_connect.with(authenticate_user));
+    io.ns("/box", on_box_connect.with(authenticate_peer));
+    io.ns("/call", on_call_connect.with(authenticate_peer));
+    if legacy_namespace {
+        io.ns("/", on_connect.with(authenticate_legacy));
+    }
 
     let app = axum::Router::new()
-        .with_state(io)
+        .with_state(io.clone())
         .route("/", get(|| async { "Hello, World!" }))
         .route("/auth", post(authorize))
         .route("/reg", post(register))
+        .route("/search", get(search))
+        .route("/c/{cid}/topics", get(channel_topics))
         .route("/t/{tid}", get(topic))
+        .route("/t/{tid}/read", put(read_topic))
+        .route("/t/{tid}/tags", put(set_tags))
+        .route("/tags", get(autocomplete))
+        .route("/tags/{tag}", get(tagged_topics))
+        .route("/t/{tid}/posts", get(list_posts).post(create_post))
+        .route("/t/{tid}/posts/{pid}", put(edit_post).delete(delete_post))
+        .route(
+            "/t/{tid}/attachments",
+            // The attachment size is enforced while reading the upload.
+            post(attach_topic).layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/t/{tid}/posts/{pid}/attachments",
+            post(attach_post).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/t/{tid}/vote", put(vote_topic))
+        .route("/t/{tid}/reactions", put(react_topic))
+        .route("/t/{tid}/posts/{pid}/vote", put(vote_post))
+        .route("/t/{tid}/posts/{pid}/reactions", put(react_post))
+        .route("/files/{key}", get(serve_file))
+        .route("/conversations", get(list_conversations))
+        .route("/conversations/{cid}/messages", get(conversation_messages))
+        .route("/conversations/{cid}/read", put(read_conversation))
+        .route("/receipts", put(set_receipts))
+        .route("/keys", put(publish_keys))
+        .route("/prekeys", get(prekey_count).post(add_prekeys))
+        .route("/keys/{user}", post(key_bundle))
+        .route("/notifications", get(list_notifications))
+        .route("/notifications/unread", get(unread_count))
+        .route("/notifications/read", put(mark_all_read))
+        .route("/notifications/{nid}/read", put(mark_read))
+        .route("/devices/claim", post(claim))
+        .route("/devices/{devid}/viewers", put(update_viewers))
+        .route("/devices/{devid}/sessions", get(device_sessions))
+        .route("/devices/{devid}/events", get(device_events))
+        .route("/devices/{devid}/config", get(get_config).put(put_config))
+        .route("/devices/{devid}/config/history", get(config_history))
+        .route(
+            "/devices/{devid}/commands",
+            get(list_commands).post(queue_command),
+        )
+        .route("/devices/{devid}/commands/{cid}", get(command_status))
+        .route("/devices/{devid}/telemetry", get(query))
+        .route("/telemetry", post(ingest))
+        .route("/groups", get(list_groups).post(create_group))
+        .route("/groups/{gid}", get(get_group).delete(delete_group))
+        .route("/groups/{gid}/devices", put(update_members))
+        .route("/groups/{gid}/viewers", put(update_group_viewers))
+        .route("/groups/{gid}/presence", get(presence))
+        .route("/groups/{gid}/message", post(broadcast))
+        .route("/groups/{gid}/config", post(push_group_config))
+        .route(
+            "/releases",
+            // The artifact size is enforced while streaming it to disk.
+            get(list_releases)
+                .post(upload)
+                .layer(DefaultBodyLimit::disable()),
+        )
+        .route("/releases/{rid}", get(get_release))
+        .route("/releases/{rid}/rollout", put(rollout))
+        .route("/releases/{rid}/reports", get(reports))
+        .route("/releases/{rid}/artifact", get(artifact))
+        .route("/sessions", get(user_sessions))
+        .route("/events", get(user_events))
         .layer(
             ServiceBuilder::new()
+                .layer(Extension(db_state.clone()))
+                .layer(Extension(io))
+                .layer(Extension(onlinedevs))
                 // Enable CORS policy
                 .layer(CorsLayer::new().allow_origin(AllowOrigin::predicate(
                     |origin: &HeaderValue, _request_parts: &RequestParts| {
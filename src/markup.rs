@@ -0,0 +1,271 @@
+use ammonia::Builder;
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag, TagEnd, TextMergeStream, html};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::LazyLock};
+
+/// Bump whenever the output changes, so cached HTML gets rendered again.
+pub const RENDER_VERSION: u32 = 2;
+
+// Length of an encoded post id.
+const QUOTE_ID_LEN: usize = 16;
+
+/// Sanitized HTML of a topic or post body, stored next to its source.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rendered {
+    pub html: String,
+    pub version: u32,
+}
+
+impl Rendered {
+    pub fn is_current(&self) -> bool {
+        self.version == RENDER_VERSION
+    }
+}
+
+static SANITIZER: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut b = Builder::empty();
+    b.add_tags([
+        "p", "br", "hr", "em", "strong", "del", "code", "pre", "ul", "ol", "li", "a", "span",
+    ])
+    .add_tag_attributes("a", ["href"])
+    .add_tag_attributes("ol", ["start"])
+    .add_allowed_classes("a", ["quotelink"])
+    .add_allowed_classes("span", ["greentext"])
+    .url_schemes(HashSet::from(["http", "https", "mailto"]))
+    .link_rel(Some("nofollow noopener noreferrer"));
+    b
+});
+
+/// Render a restricted Markdown subset plus `>>postid` quote links and `>greentext` lines.
+pub fn render(source: &str) -> Rendered {
+    let source = escape_quotes(source);
+    let parser = TextMergeStream::new(Parser::new_ext(&source, Options::ENABLE_STRIKETHROUGH));
+
+    let mut events = Vec::new();
+    let mut line_start = false;
+    let mut green = false;
+    let mut code = false;
+    for event in parser {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) | Event::End(TagEnd::CodeBlock) => {
+                code = matches!(event, Event::Start(_));
+                events.push(event);
+            }
+            // Code blocks are shown as typed, quote links included.
+            Event::Text(text) if code => events.push(Event::Text(text)),
+            Event::Start(Tag::Paragraph) | Event::Start(Tag::Item) => {
+                events.push(event);
+                line_start = true;
+                continue;
+            }
+            Event::SoftBreak
+            | Event::HardBreak
+            | Event::End(TagEnd::Paragraph)
+            | Event::End(TagEnd::Item) => {
+                if green {
+                    events.push(Event::Html("</span>".into()));
+                    green = false;
+                }
+                line_start = matches!(event, Event::SoftBreak | Event::HardBreak);
+                events.push(event);
+                continue;
+            }
+            Event::Text(text) => {
+                let quote = text.strip_prefix(">>").and_then(quote_id).is_some();
+                if line_start && text.starts_with('>') && !quote {
+                    events.push(Event::Html("<span class=\"greentext\">".into()));
+                    green = true;
+                }
+                quote_links(&text, &mut events);
+            }
+            // Raw HTML is shown as typed.
+            Event::Html(h) | Event::InlineHtml(h) => events.push(Event::Text(h)),
+            // Images are not embedded, attachments are the way to share them.
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => events.push(Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            })),
+            e => events.push(e),
+        }
+        line_start = false;
+    }
+    let events = events.into_iter().map(|e| match e {
+        Event::End(TagEnd::Image) => Event::End(TagEnd::Link),
+        e => e,
+    });
+
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    Rendered {
+        html: SANITIZER.clean(&out).to_string(),
+        version: RENDER_VERSION,
+    }
+}
+
+// `>` at the start of a line or list item is greentext or a quote link here, never a blockquote.
+fn escape_quotes(source: &str) -> String {
+    let mut fenced = false;
+    let mut out = String::with_capacity(source.len());
+    for line in source.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fenced = !fenced;
+        }
+        let indent = line.len() - trimmed.len();
+        let body = list_item(trimmed).unwrap_or(trimmed);
+        if !fenced && indent < 4 && body.starts_with('>') {
+            let at = line.len() - body.len();
+            out.push_str(&line[..at]);
+            out.push('\\');
+            out.push_str(body);
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// What follows a list marker (`-`, `*`, `+`, `1.` or `1)`) and its spaces.
+fn list_item(line: &str) -> Option<&str> {
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let rest = if digits > 0 {
+        line[digits..].strip_prefix(['.', ')'])?
+    } else {
+        line.strip_prefix(['-', '*', '+'])?
+    };
+    let body = rest.trim_start_matches(' ');
+    (body.len() < rest.len()).then_some(body)
+}
+
+fn is_id_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-' || c == b'_'
+}
+
+// The encoded post id `s` starts with, if any.
+fn quote_id(s: &str) -> Option<&str> {
+    let b = s.as_bytes();
+    let exact = b.len() >= QUOTE_ID_LEN
+        && b[..QUOTE_ID_LEN].iter().all(|c| is_id_char(*c))
+        && b.get(QUOTE_ID_LEN).is_none_or(|c| !is_id_char(*c));
+    exact.then(|| &s[..QUOTE_ID_LEN])
+}
+
+fn quote_links<'a>(text: &str, events: &mut Vec<Event<'a>>) {
+    let mut rest = text;
+    while let Some(at) = rest.find(">>") {
+        let Some(id) = quote_id(&rest[at + 2..]) else {
+            events.push(Event::Text(CowStr::from(rest[..at + 2].to_owned())));
+            rest = &rest[at + 2..];
+            continue;
+        };
+        if at > 0 {
+            events.push(Event::Text(CowStr::from(rest[..at].to_owned())));
+        }
+        events.push(Event::Html(
+            format!("<a class=\"quotelink\" href=\"#p{id}\">&gt;&gt;{id}</a>").into(),
+        ));
+        rest = &rest[at + 2 + QUOTE_ID_LEN..];
+    }
+    if !rest.is_empty() {
+        events.push(Event::Text(CowStr::from(rest.to_owned())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PID: &str = "ZmE0MzIxYjc4OWQw";
+
+    #[test]
+    fn raw_html_is_escaped() {
+        let html = render("<script>alert(1)</script> <img src=x onerror=alert(1)>").html;
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("<img"));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn unsafe_links_are_dropped() {
+        let html = render("[click](javascript:alert(1)) [ok](https://example.org)").html;
+        assert!(!html.contains("javascript:"));
+        assert!(html.contains("href=\"https://example.org\""));
+        assert!(html.contains("rel=\"nofollow noopener noreferrer\""));
+    }
+
+    #[test]
+    fn images_become_links() {
+        let html = render("![cat](https://example.org/cat.png)").html;
+        assert!(!html.contains("<img"));
+        assert!(html.contains("<a href=\"https://example.org/cat.png\""));
+    }
+
+    #[test]
+    fn greentext_lines() {
+        let html = render("hello\n>be me\n>>not an id").html;
+        assert!(html.contains("<span class=\"greentext\">&gt;be me</span>"));
+        assert!(html.contains("<span class=\"greentext\">&gt;&gt;not an id</span>"));
+        assert!(!html.contains("<blockquote"));
+        assert!(html.starts_with("<p>hello"));
+    }
+
+    #[test]
+    fn greentext_in_list_items() {
+        let html = render("- >implying").html;
+        assert!(html.contains("<li><span class=\"greentext\">&gt;implying</span></li>"));
+    }
+
+    #[test]
+    fn quote_links() {
+        let html = render(&format!(">>{PID} agreed, see >>{PID}")).html;
+        let link = format!("href=\"#p{PID}\"");
+        assert_eq!(html.matches(&link).count(), 2);
+        assert!(html.contains("class=\"quotelink\""));
+        assert!(html.contains(&format!("&gt;&gt;{PID}</a>")));
+        assert!(!html.contains("greentext"));
+    }
+
+    #[test]
+    fn quote_ids_need_the_exact_length() {
+        assert_eq!(quote_id(PID), Some(PID));
+        assert_eq!(quote_id(&format!("{PID} rest")), Some(PID));
+        assert_eq!(quote_id(&PID[1..]), None);
+        assert_eq!(quote_id(&format!("{PID}x")), None);
+        assert!(!render(&format!("text >>{PID}x")).html.contains("quotelink"));
+    }
+
+    #[test]
+    fn fenced_code_is_left_alone() {
+        let html = render(&format!("```\n>not green\n>>{PID}\n<b>bold</b>\n```")).html;
+        assert!(html.contains("<pre><code>"));
+        assert!(html.contains("&gt;not green"));
+        assert!(html.contains("&lt;b&gt;bold&lt;/b&gt;"));
+        assert!(!html.contains("greentext"));
+        assert!(!html.contains("quotelink"));
+    }
+
+    #[test]
+    fn inline_code_keeps_markup() {
+        let html = render("`<b>` and `>>x`").html;
+        assert!(html.contains("<code>&lt;b&gt;</code>"));
+        assert!(!html.contains("<b>"));
+    }
+
+    #[test]
+    fn rendered_version() {
+        assert!(render("x").is_current());
+        let old = Rendered {
+            html: String::new(),
+            version: RENDER_VERSION - 1,
+        };
+        assert!(!old.is_current());
+    }
+}
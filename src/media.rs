@@ -0,0 +1,365 @@
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use img_parts::{
+    Bytes, ImageEXIF,
+    jpeg::{Jpeg, markers},
+    png::Png,
+    webp::{CHUNK_XMP, WebP},
+};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+// Larger images are refused rather than decoded.
+const MAX_DIMENSION: u32 = 12000;
+
+/// Image types accepted as attachments, told apart by their magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+}
+
+impl Kind {
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        let kind = match data {
+            [0xFF, 0xD8, 0xFF, ..] => Self::Jpeg,
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Self::Png,
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Self::Gif,
+            [
+                b'R',
+                b'I',
+                b'F',
+                b'F',
+                _,
+                _,
+                _,
+                _,
+                b'W',
+                b'E',
+                b'B',
+                b'P',
+                ..,
+            ] => Self::WebP,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    pub fn from_ext(ext: &str) -> Option<Self> {
+        [Self::Jpeg, Self::Png, Self::Gif, Self::WebP]
+            .into_iter()
+            .find(|k| k.ext() == ext)
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+            Self::WebP => "image/webp",
+        }
+    }
+
+    pub fn ext(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::WebP => "webp",
+        }
+    }
+
+    fn format(self) -> ImageFormat {
+        match self {
+            Self::Jpeg => ImageFormat::Jpeg,
+            Self::Png => ImageFormat::Png,
+            Self::Gif => ImageFormat::Gif,
+            Self::WebP => ImageFormat::WebP,
+        }
+    }
+}
+
+/// An upload ready to be stored.
+pub struct Processed {
+    pub kind: Kind,
+    pub hash: String,
+    pub data: Vec<u8>,
+    pub dimensions: (u32, u32),
+    /// Encoded thumbnail and its type.
+    pub thumbnail: (Vec<u8>, Kind),
+}
+
+/// Check the type, strip metadata, hash the result and render a thumbnail.
+/// CPU bound, so run it off the async workers.
+pub fn process(data: Vec<u8>, thumb_size: u32) -> Result<Processed, String> {
+    let kind = Kind::sniff(&data).ok_or("Unsupported file type".to_string())?;
+    let data = strip_metadata(kind, data)?;
+    let hash = hex::encode(Sha256::digest(&data));
+
+    let img = decode(&data, kind.format())?;
+    let thumbnail = thumbnail(&img, kind, thumb_size)?;
+    Ok(Processed {
+        kind,
+        hash,
+        data,
+        dimensions: (img.width(), img.height()),
+        thumbnail,
+    })
+}
+
+// EXIF (GPS, camera serials), XMP, IPTC and comments go; pixels and color profiles stay.
+fn strip_metadata(kind: Kind, data: Vec<u8>) -> Result<Vec<u8>, String> {
+    let bytes = Bytes::from(data);
+    let invalid = |e: img_parts::Error| format!("Invalid image: {e}");
+    let stripped = match kind {
+        Kind::Jpeg => {
+            let mut jpeg = Jpeg::from_bytes(bytes).map_err(invalid)?;
+            for marker in [markers::APP1, markers::APP12, markers::APP13, markers::COM] {
+                jpeg.remove_segments_by_marker(marker);
+            }
+            jpeg.encoder().bytes()
+        }
+        Kind::Png => {
+            let mut png = Png::from_bytes(bytes).map_err(invalid)?;
+            png.set_exif(None);
+            for chunk in [*b"tEXt", *b"iTXt", *b"zTXt", *b"tIME"] {
+                png.remove_chunks_by_type(chunk);
+            }
+            png.encoder().bytes()
+        }
+        Kind::WebP => {
+            let mut webp = WebP::from_bytes(bytes).map_err(invalid)?;
+            webp.set_exif(None);
+            webp.remove_chunks_by_id(CHUNK_XMP);
+            webp.encoder().bytes()
+        }
+        Kind::Gif => return strip_gif(&bytes),
+    };
+    Ok(stripped.to_vec())
+}
+
+// Size of the color table a GIF descriptor's packed `flags` announce.
+fn color_table(flags: u8) -> usize {
+    if flags & 0x80 != 0 {
+        3 << ((flags & 0x07) + 1)
+    } else {
+        0
+    }
+}
+
+// Loop counts (`NETSCAPE2.0`, `ANIMEXTS1.0`) are the only application data kept.
+const GIF_LOOP_APPS: [&[u8; 11]; 2] = [b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+
+// Copy a GIF block by block, leaving out comment and application extensions,
+// which is where XMP and free-form text live.
+fn strip_gif(data: &[u8]) -> Result<Vec<u8>, String> {
+    let truncated = || "Invalid image: truncated GIF".to_string();
+    let take = |at: usize, len: usize| data.get(at..at + len).ok_or_else(truncated);
+    // Length of the data sub-blocks starting at `at`, terminator included.
+    let sub_blocks = |mut at: usize| -> Result<usize, String> {
+        let start = at;
+        loop {
+            let len = *data.get(at).ok_or_else(truncated)? as usize;
+            at += 1 + len;
+            if len == 0 {
+                return Ok(at - start);
+            }
+        }
+    };
+    // Header and logical screen descriptor, then the global color table.
+    let mut at = 13 + color_table(take(10, 1)?[0]);
+    let mut out = take(0, at)?.to_vec();
+    loop {
+        match take(at, 1)?[0] {
+            0x3B => {
+                out.push(0x3B);
+                return Ok(out);
+            }
+            0x2C => {
+                let header = 10 + color_table(take(at + 9, 1)?[0]);
+                // Descriptor, local color table, LZW code size, then the pixel data.
+                let len = header + 1 + sub_blocks(at + header + 1)?;
+                out.extend_from_slice(take(at, len)?);
+                at += len;
+            }
+            0x21 => {
+                let label = take(at + 1, 1)?[0];
+                let len = 2 + sub_blocks(at + 2)?;
+                let keep = match label {
+                    0xFE => false,
+                    0xFF => take(at + 2, 12).is_ok_and(|app| {
+                        app[0] == 11 && GIF_LOOP_APPS.iter().any(|a| app[1..] == a[..])
+                    }),
+                    _ => true,
+                };
+                if keep {
+                    out.extend_from_slice(take(at, len)?);
+                }
+                at += len;
+            }
+            b => return Err(format!("Invalid image: unknown GIF block {b:#04x}")),
+        }
+    }
+}
+
+fn decode(data: &[u8], format: ImageFormat) -> Result<DynamicImage, String> {
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    reader.limits(limits);
+    reader.decode().map_err(|e| format!("Invalid image: {e}"))
+}
+
+// JPEG thumbnails for photos, PNG for everything that may be transparent.
+fn thumbnail(img: &DynamicImage, kind: Kind, size: u32) -> Result<(Vec<u8>, Kind), String> {
+    let thumb = img.thumbnail(size, size);
+    let (thumb, kind) = match kind {
+        Kind::Jpeg => (DynamicImage::ImageRgb8(thumb.to_rgb8()), Kind::Jpeg),
+        _ => (thumb, Kind::Png),
+    };
+    let mut out = Cursor::new(Vec::new());
+    thumb
+        .write_to(&mut out, kind.format())
+        .map_err(|e| format!("Failed to render thumbnail: {e}"))?;
+    Ok((out.into_inner(), kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use img_parts::png::PngChunk;
+
+    const EXIF: &[u8] = b"Exif\0\0MM\0*\0\0\0\x08\0\0";
+
+    fn encoded(format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(40, 20)
+            .write_to(&mut out, format)
+            .unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn sniff_magic_bytes() {
+        let cases: [(&[u8], Kind); 5] = [
+            (&[0xFF, 0xD8, 0xFF, 0xE0], Kind::Jpeg),
+            (b"\x89PNG\r\n\x1a\n\0\0", Kind::Png),
+            (b"GIF87a..", Kind::Gif),
+            (b"GIF89a..", Kind::Gif),
+            (b"RIFF\0\0\0\0WEBPVP8 ", Kind::WebP),
+        ];
+        for (data, kind) in cases {
+            assert_eq!(Kind::sniff(data), Some(kind), "{data:?}");
+        }
+    }
+
+    #[test]
+    fn sniff_rejects_lookalikes() {
+        let cases: [&[u8]; 7] = [
+            b"",
+            b"\xFF\xD8",
+            b"GIF88a..",
+            b"RIFF\0\0\0\0WAVEfmt ",
+            b"\0\0\0\x18ftypheic\0\0\0\0",
+            b"\0\0\0\x14ftypqt  \0\0\0\0",
+            b"<html><script>",
+        ];
+        for data in cases {
+            assert_eq!(Kind::sniff(data), None, "{data:?}");
+        }
+    }
+
+    #[test]
+    fn extensions_round_trip() {
+        for kind in [Kind::Jpeg, Kind::Png, Kind::Gif, Kind::WebP] {
+            assert_eq!(Kind::from_ext(kind.ext()), Some(kind));
+        }
+        assert_eq!(Kind::from_ext("pdf"), None);
+    }
+
+    #[test]
+    fn jpeg_exif_is_removed() {
+        let mut jpeg = Jpeg::from_bytes(encoded(ImageFormat::Jpeg).into()).unwrap();
+        jpeg.set_exif(Some(Bytes::from_static(EXIF)));
+        let data = jpeg.encoder().bytes().to_vec();
+
+        let p = process(data, 16).unwrap();
+        assert_eq!(p.kind, Kind::Jpeg);
+        assert_eq!(p.dimensions, (40, 20));
+        let jpeg = Jpeg::from_bytes(p.data.into()).unwrap();
+        assert!(jpeg.exif().is_none());
+        let (thumb, kind) = p.thumbnail;
+        assert_eq!(kind, Kind::Jpeg);
+        assert_eq!(Kind::sniff(&thumb), Some(Kind::Jpeg));
+    }
+
+    #[test]
+    fn png_metadata_is_removed() {
+        let mut png = Png::from_bytes(encoded(ImageFormat::Png).into()).unwrap();
+        png.set_exif(Some(Bytes::from_static(EXIF)));
+        let text = PngChunk::new(*b"tEXt", Bytes::from_static(b"Comment\0secret"));
+        png.chunks_mut().insert(1, text);
+        let data = png.encoder().bytes().to_vec();
+
+        let p = process(data, 16).unwrap();
+        assert_eq!(p.kind, Kind::Png);
+        let png = Png::from_bytes(p.data.into()).unwrap();
+        assert!(png.exif().is_none());
+        assert!(png.chunk_by_type(*b"tEXt").is_none());
+    }
+
+    #[test]
+    fn same_content_same_hash() {
+        let data = encoded(ImageFormat::Png);
+        let a = process(data.clone(), 16).unwrap();
+        let b = process(data, 16).unwrap();
+        assert_eq!(a.hash, b.hash);
+    }
+
+    #[test]
+    fn documents_and_video_are_refused() {
+        let cases: [&[u8]; 3] = [
+            b"%PDF-1.7\n%\xE2\xE3\n1 0 obj<</Author(me)>>",
+            b"\0\0\0\x18ftypmp42\0\0\0\0mp42isom",
+            &[0x1A, 0x45, 0xDF, 0xA3, 0x9F, 0x42, 0x86, 0x81],
+        ];
+        for data in cases {
+            assert_eq!(Kind::sniff(data), None, "{data:?}");
+            assert!(process(data.to_vec(), 16).is_err(), "{data:?}");
+        }
+    }
+
+    #[test]
+    fn gif_comments_and_xmp_are_removed() {
+        let mut data = encoded(ImageFormat::Gif);
+        let blocks = [
+            &b"\x21\xFE\x06secret\0"[..],
+            b"\x21\xFF\x0BXMP DataXMP\x0A<x:xmpmeta\x04 GPS\0",
+            b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\0\0\0",
+        ];
+        let at = 13 + color_table(data[10]);
+        data.splice(at..at, blocks.concat());
+
+        let p = process(data, 16).unwrap();
+        assert_eq!(p.kind, Kind::Gif);
+        assert_eq!(p.dimensions, (40, 20));
+        let has = |needle: &[u8]| p.data.windows(needle.len()).any(|w| w == needle);
+        assert!(!has(b"secret"));
+        assert!(!has(b"XMP DataXMP"));
+        assert!(!has(b"xmpmeta"));
+        assert!(has(b"NETSCAPE2.0"));
+    }
+
+    #[test]
+    fn truncated_gif_is_refused() {
+        let data = encoded(ImageFormat::Gif);
+        assert!(strip_gif(&data[..data.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn unknown_types_are_refused() {
+        assert!(process(b"#!/bin/sh\nrm -rf /".to_vec(), 16).is_err());
+    }
+}
@@ -1,30 +1,191 @@
+mod acks;
+mod auth;
 mod handlers;
+mod signal;
 mod state;
 
-use socketioxide::extract::SocketRef;
+use serde::Serialize;
+use socketioxide::{
+    SocketIo,
+    adapter::Adapter,
+    extract::{SocketRef, State},
+    socket::Sid,
+};
 
-pub use state::{OnlineDevs, OnlineUsers};
+use tracing::warn;
 
-pub async fn on_connect(socket: SocketRef) {
+use crate::db::DbState;
+
+use auth::Peer;
+
+pub use acks::AckSummary;
+pub use auth::{authenticate_legacy, authenticate_peer, authenticate_user};
+pub use handlers::{deliver_commands, ota_offer, push_config};
+pub use state::{OnlineDevs, OnlineUsers, RoomActivity};
+
+/// Namespaces a box can be connected on. `/` is the pre-namespace shim.
+pub const DEVICE_NAMESPACES: [&str; 3] = ["/box", "/call", "/"];
+
+/// Find a connected box in whichever namespace it joined.
+pub fn device_socket(io: &SocketIo, sid: Sid) -> Option<SocketRef> {
+    DEVICE_NAMESPACES
+        .iter()
+        .filter_map(|ns| io.of(ns))
+        .find_map(|ns| ns.get_socket(sid))
+}
+
+/// Namespaces users join their personal room on.
+pub const USER_NAMESPACES: [&str; 2] = ["/chat", "/"];
+
+/// Emit to a topic or channel room on every namespace users connect to. Events
+/// meant for one user go through `emit_to_user`.
+pub async fn emit_to_room<A: Adapter, T: Serialize + ?Sized>(
+    io: &SocketIo<A>,
+    room: &str,
+    event: &str,
+    data: &T,
+) {
+    for ns in USER_NAMESPACES.iter().filter_map(|ns| io.of(ns)) {
+        if let Err(e) = ns.to(room.to_owned()).emit(event, data).await {
+            warn!("Failed to emit {event} to {room}: {e}");
+        }
+    }
+}
+
+/// Namespaces where a user room only holds sockets signed in as that user.
+pub const AUTH_USER_NAMESPACES: [&str; 1] = ["/chat"];
+
+/// Emit a private event to the room of `user`. Legacy sockets pick their name
+/// themselves, so they never get these.
+pub async fn emit_to_user<A: Adapter, T: Serialize + ?Sized>(
+    io: &SocketIo<A>,
+    user: &str,
+    event: &str,
+    data: &T,
+) {
+    for ns in AUTH_USER_NAMESPACES.iter().filter_map(|ns| io.of(ns)) {
+        if let Err(e) = ns.to(user.to_owned()).emit(event, data).await {
+            warn!("Failed to emit {event} to {user}: {e}");
+        }
+    }
+}
+
+/// Whether `user` has a signed-in session that `emit_to_user` reaches.
+pub fn user_connected<A: Adapter>(io: &SocketIo<A>, user: &str) -> bool {
+    AUTH_USER_NAMESPACES
+        .iter()
+        .filter_map(|ns| io.of(ns))
+        .any(|ns| !ns.within(user.to_owned()).sockets().is_empty())
+}
+
+/// OpenS1 user presence, for signed-in users only.
+pub async fn on_chat_connect(socket: SocketRef) {
+    socket.on_disconnect(handlers::on_disconnect);
+    socket.on("signout", handlers::on_signout);
+    socket.on("fetchAllUsers", handlers::on_fetchuser);
+    socket.on("identify", handlers::on_identify);
+    socket.on("status", handlers::on_status);
+    socket.on("lastseen", handlers::on_lastseen);
+    socket.on("subscribeTopic", handlers::on_subscribe_topic);
+    socket.on("unsubscribeTopic", handlers::on_unsubscribe_topic);
+    socket.on("subscribeChannel", handlers::on_subscribe_channel);
+    socket.on("unsubscribeChannel", handlers::on_unsubscribe_channel);
+    socket.on("typing", handlers::on_typing);
+    socket.on("dm", handlers::on_dm);
+    socket.on("read", handlers::on_read);
+}
+
+/// AI-box management. Boxes report in, users configure and message them.
+pub async fn on_box_connect(socket: SocketRef) {
+    socket.on_disconnect(handlers::on_disconnect);
+    socket.on("heartbeatping", handlers::on_heartbeatping);
+
+    match auth::peer(&socket) {
+        Peer::Device => {
+            socket.on("find", handlers::on_find);
+            socket.on("unset", handlers::on_unset);
+            socket.on("telemetry", handlers::on_telemetry);
+            socket.on("fwinfo", handlers::on_fwinfo);
+            socket.on("otastatus", handlers::on_otastatus);
+        }
+        // Without a token a box can only show up as `Unbound` and wait to be paired.
+        Peer::Unclaimed => {
+            socket.on("find", handlers::on_find);
+            socket.on("unset", handlers::on_unset);
+        }
+        Peer::User => {
+            socket.on("message", handlers::on_message);
+            socket.on("checkbox", handlers::on_checkbox);
+            socket.on("boxconf", handlers::on_boxconf);
+            socket.on("setconf", handlers::on_setconf);
+            socket.on("getconf", handlers::on_getconf);
+        }
+    }
+}
+
+/// TJAI calls between a camera and its viewers.
+pub async fn on_call_connect(socket: SocketRef, db_state: State<DbState>) {
+    socket.on_disconnect(handlers::on_disconnect);
+    socket.on("heartbeatping", handlers::on_heartbeatping);
+
+    let legacy = db_state.legacy_signaling();
+    match auth::peer(&socket) {
+        Peer::Device => {
+            socket.on("find", handlers::on_find);
+            socket.on("viewerlimit", handlers::on_viewerlimit);
+            socket.on("speakerid", handlers::on_speakerid);
+            socket.on("hang", handlers::on_hang);
+            socket.on("leave", handlers::on_leave);
+            socket.on(signal::SIGNAL_EVENT, handlers::on_signal);
+            if legacy {
+                socket.on("accept", handlers::on_accept);
+                socket.on("reject", handlers::on_reject);
+            }
+        }
+        // Unclaimed boxes have no owner yet, so nobody may watch them.
+        Peer::Unclaimed => {
+            socket.on("find", handlers::on_find);
+        }
+        Peer::User => {
+            socket.on("checkdev", handlers::on_checkdev);
+            socket.on("watch", handlers::on_watch);
+            socket.on("speech", handlers::on_speech);
+            socket.on("hang", handlers::on_hang);
+            socket.on("leave", handlers::on_leave);
+            socket.on(signal::SIGNAL_EVENT, handlers::on_signal);
+            if legacy {
+                socket.on("auth", handlers::on_auth);
+            }
+        }
+    }
+}
+
+/// Everything on one namespace, kept while clients migrate. A token is optional here.
+pub async fn on_connect(socket: SocketRef, db_state: State<DbState>) {
     socket.on_disconnect(handlers::on_disconnect);
 
     // OpenS1 part
     socket.on("signout", handlers::on_signout);
     socket.on("fetchAllUsers", handlers::on_fetchuser);
-    // socket.on(
-    //     "notify",
-    //     |s: SocketRef, Data::<String>(quoted_author), Data::<String>(thread)| {
-    //         // console.log(`quotedAuthor: ${quotedAuthor}`);
-    //         s.to(quoted_author).emit("replyreminder", Raw(thread)).ok();
-    //     },
-    // );
     socket.on("identify", handlers::on_identify);
+    socket.on("status", handlers::on_status);
+    socket.on("lastseen", handlers::on_lastseen);
+    socket.on("subscribeTopic", handlers::on_subscribe_topic);
+    socket.on("unsubscribeTopic", handlers::on_unsubscribe_topic);
+    socket.on("subscribeChannel", handlers::on_subscribe_channel);
+    socket.on("unsubscribeChannel", handlers::on_unsubscribe_channel);
 
     // AI-box part
     socket.on("message", handlers::on_message);
     socket.on("checkbox", handlers::on_checkbox);
     socket.on("boxconf", handlers::on_boxconf);
+    socket.on("setconf", handlers::on_setconf);
+    socket.on("getconf", handlers::on_getconf);
     socket.on("unset", handlers::on_unset);
+    socket.on("devauth", handlers::on_devauth);
+    socket.on("telemetry", handlers::on_telemetry);
+    socket.on("fwinfo", handlers::on_fwinfo);
+    socket.on("otastatus", handlers::on_otastatus);
 
     // TJAI part
 
@@ -33,13 +194,21 @@ pub async fn on_connect(socket: SocketRef) {
     socket.on("checkdev", handlers::on_checkdev);
     socket.on("find", handlers::on_find);
     socket.on("watch", handlers::on_watch);
+    socket.on("viewerlimit", handlers::on_viewerlimit);
     socket.on("speakerid", handlers::on_speakerid);
-    socket.on("auth", handlers::on_auth);
-    socket.on("accept", handlers::on_accept);
 
     // Sendback acknowledgement to inform whether speaker is occupied.
     socket.on("speech", handlers::on_speech);
     socket.on("hang", handlers::on_hang);
-    socket.on("reject", handlers::on_reject);
     socket.on("leave", handlers::on_leave);
+
+    // Typed offer/answer/candidate exchange addressed to a single peer.
+    socket.on(signal::SIGNAL_EVENT, handlers::on_signal);
+
+    // Untyped room broadcasts kept for clients that predate `v1:signal`.
+    if db_state.legacy_signaling() {
+        socket.on("auth", handlers::on_auth);
+        socket.on("accept", handlers::on_accept);
+        socket.on("reject", handlers::on_reject);
+    }
 }
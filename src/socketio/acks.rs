@@ -0,0 +1,81 @@
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use serde_json::Value;
+use socketioxide::{AckError, socket::Sid};
+use std::collections::HashMap;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AckEntry {
+    pub sid: Sid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devid: Option<String>,
+    pub ack: Value,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AckFailure {
+    pub sid: Sid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devid: Option<String>,
+    pub error: String,
+}
+
+/// Every answer to a room-wide `emit_with_ack`, split by outcome so callers
+/// can tell which peer actually replied.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct AckSummary {
+    pub replies: Vec<AckEntry>,
+    pub timeouts: Vec<Sid>,
+    pub failures: Vec<AckFailure>,
+}
+
+impl AckSummary {
+    pub async fn collect<S>(stream: S) -> Self
+    where
+        S: Stream<Item = (Sid, Result<Value, AckError>)>,
+    {
+        stream
+            .fold(Self::default(), |mut summary, (sid, res)| async move {
+                summary.record(sid, res);
+                summary
+            })
+            .await
+    }
+
+    /// Add the answer of a single peer.
+    pub fn record(&mut self, sid: Sid, res: Result<Value, AckError>) {
+        match res {
+            Ok(ack) => self.replies.push(AckEntry {
+                sid,
+                devid: None,
+                ack,
+            }),
+            Err(AckError::Timeout) => self.timeouts.push(sid),
+            Err(e) => self.fail(sid, e.to_string()),
+        }
+    }
+
+    /// Add a peer the message could not even be sent to.
+    pub fn fail(&mut self, sid: Sid, error: String) {
+        self.failures.push(AckFailure {
+            sid,
+            devid: None,
+            error,
+        });
+    }
+
+    pub fn answered(&self) -> bool {
+        !self.replies.is_empty()
+    }
+
+    /// Name the responders after the devices they are registered as.
+    pub fn with_devices(mut self, devices: &HashMap<Sid, String>) -> Self {
+        for r in self.replies.iter_mut() {
+            r.devid = devices.get(&r.sid).cloned();
+        }
+        for f in self.failures.iter_mut() {
+            f.devid = devices.get(&f.sid).cloned();
+        }
+        self
+    }
+}
@@ -0,0 +1,165 @@
+use mongodb::bson::oid::ObjectId;
+use serde::Deserialize;
+use socketioxide::{
+    adapter::Adapter,
+    extract::{SocketRef, State, TryData},
+};
+use tracing::{info, warn};
+
+use crate::{
+    api::auth::{Claims, DeviceClaims},
+    db::{DbState, DeviceDoc},
+};
+
+/// What clients pass as `auth` when connecting to a namespace.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConnectAuth {
+    token: Option<String>,
+}
+
+// A user signed in with the token they got from `/auth`.
+#[derive(Debug, Clone)]
+pub struct UserAuth {
+    pub name: String,
+    pub uid: ObjectId,
+}
+
+impl UserAuth {
+    fn new(claims: &Claims) -> Result<Self, String> {
+        Ok(Self {
+            name: claims.getuser(),
+            uid: claims.userid().ok_or("Invalid token".to_string())?,
+        })
+    }
+}
+
+// Set once a box proved who it is with the token it got when it was claimed.
+#[derive(Debug, Clone)]
+pub struct DeviceAuth(pub String);
+
+// A box that connected without a token. It may only announce itself as `Unbound`
+// so it can be paired.
+#[derive(Debug, Clone)]
+pub struct Unclaimed;
+
+fn secret(db_state: &DbState) -> Result<String, String> {
+    db_state
+        .secret()
+        .ok_or("Secret not found in config".to_string())
+}
+
+/// Connect middleware of `/chat`: only signed-in users.
+pub async fn authenticate_user<A: Adapter>(
+    s: SocketRef<A>,
+    TryData(auth): TryData<ConnectAuth>,
+    db_state: State<DbState>,
+) -> Result<(), String> {
+    let token = auth
+        .unwrap_or_default()
+        .token
+        .ok_or("Missing token".to_string())?;
+    let claims =
+        Claims::verify(&token, &secret(&db_state)?).map_err(|_| "Invalid token".to_string())?;
+    s.extensions.insert(UserAuth::new(&claims)?);
+    Ok(())
+}
+
+/// Connect middleware of `/box` and `/call`: users, claimed boxes with their
+/// device token and unclaimed boxes without any token.
+pub async fn authenticate_peer<A: Adapter>(
+    s: SocketRef<A>,
+    TryData(auth): TryData<ConnectAuth>,
+    db_state: State<DbState>,
+) -> Result<(), String> {
+    let Some(token) = auth.unwrap_or_default().token else {
+        s.extensions.insert(Unclaimed);
+        return Ok(());
+    };
+    sign_in(&s, &token, &db_state)
+}
+
+// Mark `s` as the box or user `token` was issued to.
+fn sign_in<A: Adapter>(s: &SocketRef<A>, token: &str, db_state: &DbState) -> Result<(), String> {
+    let secret = secret(db_state)?;
+    if let Ok(c) = DeviceClaims::verify(token, &secret) {
+        info!("Device authenticated: {} - {}", c.devid(), s.id);
+        s.extensions.insert(DeviceAuth(c.devid()));
+    } else if let Ok(c) = Claims::verify(token, &secret) {
+        s.extensions.insert(UserAuth::new(&c)?);
+    } else {
+        warn!("Connection with invalid token refused: {}", s.id);
+        return Err("Invalid token".to_string());
+    }
+    Ok(())
+}
+
+/// Connect middleware of the legacy `/` namespace. Sockets without a token still
+/// connect, but boxes need `devauth` before `find`, and owner and viewer events
+/// need a user token.
+pub async fn authenticate_legacy<A: Adapter>(
+    s: SocketRef<A>,
+    TryData(auth): TryData<ConnectAuth>,
+    db_state: State<DbState>,
+) -> Result<(), String> {
+    match auth.unwrap_or_default().token {
+        Some(token) => sign_in(&s, &token, &db_state),
+        None => Ok(()),
+    }
+}
+
+/// Who is on the other end of a `/box` or `/call` socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    User,
+    Device,
+    Unclaimed,
+}
+
+pub fn peer<A: Adapter>(s: &SocketRef<A>) -> Peer {
+    if s.extensions.get::<DeviceAuth>().is_some() {
+        Peer::Device
+    } else if s.extensions.get::<Unclaimed>().is_some() {
+        Peer::Unclaimed
+    } else {
+        Peer::User
+    }
+}
+
+/// The claimed device `devid`, if the user signed in on `s` owns it. The same
+/// check the REST routes make with `owned_device`.
+pub async fn owned_device<A: Adapter>(
+    s: &SocketRef<A>,
+    db_state: &DbState,
+    devid: &str,
+) -> Result<DeviceDoc, String> {
+    let user = s
+        .extensions
+        .get::<UserAuth>()
+        .ok_or("Not signed in".to_string())?;
+    match db_state.get_device(devid).await {
+        Ok(device) if device.owner == user.uid => Ok(device),
+        // Don't leak which device ids exist to other accounts.
+        _ => Err(format!("Device not found: {devid}")),
+    }
+}
+
+/// The claimed device `devid`, if the user signed in on `s` may watch it: its
+/// owner, a viewer it was granted to, or a viewer of a group holding it.
+pub async fn watchable_device<A: Adapter>(
+    s: &SocketRef<A>,
+    db_state: &DbState,
+    devid: &str,
+) -> Result<DeviceDoc, String> {
+    let user = s
+        .extensions
+        .get::<UserAuth>()
+        .ok_or("Not signed in".to_string())?;
+    let Ok(device) = db_state.get_device(devid).await else {
+        return Err(format!("Device not found: {devid}"));
+    };
+    match db_state.may_watch(&device, &user.uid).await {
+        Ok(true) => Ok(device),
+        Ok(false) => Err(format!("Device not found: {devid}")),
+        Err(e) => Err(e.to_string()),
+    }
+}
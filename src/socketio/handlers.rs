@@ -1,20 +1,45 @@
-use std::{collections::HashSet, str::FromStr, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    str::FromStr,
+    time::Duration,
+};
 
-use serde_json::{json, Value};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde_json::{Value, json};
 use socketioxide::{
+    SocketIo,
     adapter::Adapter,
-    extract::{AckSender, Data, SocketRef, State},
+    extract::{AckSender, Data, SocketRef, State, TryData},
     socket::{DisconnectReason, Sid},
 };
 use tracing::{error, info, warn};
 
 use serde::{Deserialize, Serialize};
 
-use super::state::{OnlineDevs, OnlineUsers};
+use crate::{
+    api::{
+        auth::DeviceClaims,
+        boxconf::Revision,
+        dm::DirectMessage,
+        read::{mark_conversation_read, mark_topic_read},
+    },
+    db::{
+        AuditEvent, AuditKind, CommandStatus, ConfigRevision, DbState, EndReason, OtaReport,
+        OtaState, PresenceStatus, ReadTarget, ReleaseDoc, ReleaseStatus, Sample, decode_oid,
+        encode_oid, pseudonym,
+    },
+};
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(transparent)]
-struct Username(String);
+use super::{
+    acks::AckSummary,
+    auth::{DeviceAuth, UserAuth, owned_device, watchable_device},
+    emit_to_room, emit_to_user,
+    signal::{Role, SIGNAL_EVENT, SignalMessage, SignalRequest},
+    state::{Admission, OnlineDevs, OnlineUsers, RoomActivity, TypingStart, UserPresence},
+    user_connected,
+};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct Topic {
@@ -22,120 +47,892 @@ struct Topic {
     tid: Sid,
 }
 
+// The `call_sessions` record opened when this socket started watching a device.
+#[derive(Debug, Clone)]
+struct CallSession {
+    oid: ObjectId,
+    devid: String,
+    user: Option<UserAuth>,
+}
+
+/// `identify` takes a bare user name or the name together with the client type.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Identity {
+    Name(String),
+    Session {
+        user: String,
+        client: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TelemetryReport {
+    ts: Option<i64>,
+    metrics: HashMap<String, f64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FirmwareInfo {
+    model: String,
+    version: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OtaStatus {
+    rid: String,
+    state: OtaState,
+    progress: Option<u8>,
+    error: Option<String>,
+}
+
+// The device this socket is queued for while all its viewer slots are taken.
+#[derive(Debug, Clone)]
+struct Waiting(String);
+
+#[derive(Deserialize, Debug, Clone)]
+struct AppliedConf {
+    revision: i64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct CommandAck {
+    success: bool,
+    result: Option<Value>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SetConf {
+    devid: String,
+    schema: i32,
+    config: Value,
+}
+
 #[derive(Serialize, Debug)]
 struct AckReply<T: Serialize> {
     success: bool,
     message: T,
 }
 
+// Audit writes run detached so a slow database never holds up signaling.
+fn audit(db_state: &DbState, event: AuditEvent) {
+    let db_state = db_state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = db_state.log_event(&event).await {
+            error!("Failed to write audit event {:?}: {e}", event.kind);
+        }
+    });
+}
+
+async fn end_call<A: Adapter>(s: &SocketRef<A>, db_state: &DbState, reason: EndReason) {
+    let Some(session) = s.extensions.remove::<CallSession>() else {
+        return;
+    };
+    if let Err(e) = db_state.end_session(session.oid, reason).await {
+        error!("Failed to close call session {}: {e}", session.oid);
+    }
+    audit(
+        db_state,
+        AuditEvent::new(AuditKind::WatcherLeave, &session.devid, s.id.as_str())
+            .with_user(session.user.map(|u| (u.uid, u.name)))
+            .with_detail(json!({"reason": reason})),
+    );
+}
+
+// Close the sessions of every other socket watching `devid`.
+async fn end_room_calls<A: Adapter>(
+    s: &SocketRef<A>,
+    devid: &str,
+    db_state: &DbState,
+    reason: EndReason,
+) {
+    for r in s.within(devid.to_owned()).sockets() {
+        if r.id != s.id {
+            end_call(&r, db_state, reason).await;
+        }
+    }
+}
+
+// Join `devid`'s room as a viewer once a slot has been granted.
+async fn start_watch<A: Adapter>(s: &SocketRef<A>, devid: &str, camid: Sid, db_state: &DbState) {
+    s.extensions.remove::<Waiting>();
+    s.join(devid.to_owned());
+
+    s.extensions.insert::<Topic>(Topic {
+        title: devid.to_owned(),
+        tid: camid,
+        // speaker: None,
+    });
+
+    // Watching another device (or the same one again) starts a new session.
+    end_call(s, db_state, EndReason::Leave).await;
+    // Sessions are billed to the account signed in on the `/call` socket.
+    let user = s.extensions.get::<UserAuth>();
+    let viewer = user.clone().map(|u| (u.uid, u.name));
+    match db_state
+        .start_session(devid, s.id.as_str(), viewer.clone())
+        .await
+    {
+        Ok(oid) => {
+            s.extensions.insert(CallSession {
+                oid,
+                devid: devid.to_owned(),
+                user: user.clone(),
+            });
+        }
+        Err(e) => error!("Failed to record call session for {devid}: {e}"),
+    }
+    audit(
+        db_state,
+        AuditEvent::new(AuditKind::WatcherJoin, devid, s.id.as_str()).with_user(viewer),
+    );
+
+    s.within(devid.to_owned()).emit("join", &s.id).await.ok();
+    let rs = s.within(devid.to_owned()).sockets();
+    let roomsids: HashSet<String> = HashSet::from_iter(rs.iter().map(|r| r.id.to_string()));
+    info!("Watching: {} - {:?}", devid, roomsids);
+}
+
+async fn notify_queue<A: Adapter>(s: &SocketRef<A>, devid: &str, onlinedevs: &OnlineDevs) {
+    for (i, sid) in onlinedevs.queue(devid).await.into_iter().enumerate() {
+        if let Some(w) = s.broadcast().get_socket(sid) {
+            w.emit("queued", &json!({"devid": devid, "position": i + 1}))
+                .ok();
+        }
+    }
+}
+
+// Seat the queued watchers that were promoted into free slots of `devid`.
+async fn admit<A: Adapter>(
+    s: &SocketRef<A>,
+    devid: &str,
+    admitted: Vec<Sid>,
+    onlinedevs: &OnlineDevs,
+    db_state: &DbState,
+) {
+    let Some(camid) = onlinedevs.getcamid(devid).await else {
+        return;
+    };
+    // Seat them in the order they were queued.
+    let mut admitted = VecDeque::from(admitted);
+    while let Some(sid) = admitted.pop_front() {
+        match s.broadcast().get_socket(sid) {
+            Some(next) => {
+                start_watch(&next, devid, camid, db_state).await;
+                next.emit("admitted", &devid).ok();
+            }
+            // Gone without releasing its place, pass the slot on.
+            None => admitted.extend(onlinedevs.exit(devid, &sid).await),
+        }
+    }
+    notify_queue(s, devid, onlinedevs).await;
+}
+
+// Give up the slot or queue position this socket holds on `devid`.
+async fn release_slot<A: Adapter>(
+    s: &SocketRef<A>,
+    devid: &str,
+    onlinedevs: &OnlineDevs,
+    db_state: &DbState,
+) {
+    s.extensions.remove::<Waiting>();
+    let admitted = onlinedevs.exit(devid, &s.id).await;
+    admit(s, devid, admitted, onlinedevs, db_state).await;
+}
+
+// The device is gone, so nobody waiting for it will ever be admitted.
+async fn drop_queue<A: Adapter>(s: &SocketRef<A>, devid: &str, onlinedevs: &OnlineDevs) {
+    for sid in onlinedevs.drop_viewers(devid).await {
+        if let Some(w) = s.broadcast().get_socket(sid) {
+            w.extensions.remove::<Waiting>();
+            w.emit("nodev", &Value::Null).ok();
+        }
+    }
+}
+
 pub async fn on_disconnect<A: Adapter>(
     s: SocketRef<A>,
+    io: SocketIo<A>,
     reason: DisconnectReason,
     onlinedevs: State<OnlineDevs>,
     onlineusers: State<OnlineUsers>,
+    activity: State<RoomActivity>,
+    db_state: State<DbState>,
 ) {
     info!("{} has disconnected. Reason: {:?}", &s.id, reason);
+    for (tid, pseudonym) in activity.stop_all_typing(&s.id).await {
+        emit_typing(&io, &tid, &pseudonym, false).await;
+    }
+    for (room, count) in activity.exit_all(&s.id).await {
+        emit_readers(&io, &room, count).await;
+    }
+    end_call(&s, &db_state, EndReason::Disconnect).await;
+    if let Some(Waiting(devid)) = s.extensions.get::<Waiting>() {
+        release_slot(&s, &devid, &onlinedevs, &db_state).await;
+    }
     // sending to all clients in the room (channel) except sender
     if let Some(topic) = s.extensions.get::<Topic>() {
+        if topic.tid == s.id {
+            end_room_calls(&s, &topic.title, &db_state, EndReason::Disconnect).await;
+            drop_queue(&s, &topic.title, &onlinedevs).await;
+        } else {
+            release_slot(&s, &topic.title, &onlinedevs, &db_state).await;
+        }
         s.to(topic.title).emit("hangup", &s.id).await.ok();
     }
-    if let Some(user) = onlineusers.get(&s.id).await {
-        onlineusers.remove(&s.id).await;
-
-        let u = onlineusers.val().await;
-        for d in u {
-            let s = s.clone();
-            tokio::spawn(async move {
-                s.to(d).emit("userOffline", &s.id).await.ok();
-            });
-            // s.to(d.to_owned()).emit("userOffline", &s.id).await.ok();
+    if let Some((uid, user, last)) = onlineusers.remove(&s.id).await {
+        // Other tabs of the same user keep them online.
+        if let Some(status) = last {
+            went_offline(&s, uid, &user, status, &onlineusers, &db_state).await;
         }
         info!("disconnected user:{user}");
     }
     if let Some(dev) = onlinedevs.get(&s.id).await {
         onlinedevs.remove(&s.id).await;
         onlinedevs.speaker_off(&dev).await;
+        onlinedevs.unpair(&s.id).await;
+        audit(
+            &db_state,
+            AuditEvent::new(AuditKind::DeviceOffline, &dev, s.id.as_str())
+                .with_detail(json!({"reason": format!("{reason:?}")})),
+        );
         info!("disconnected device:{dev}");
     }
 }
 
-pub async fn on_identify<A: Adapter>(
-    s: SocketRef<A>,
-    Data(user): Data<String>,
-    onlineusers: State<OnlineUsers>,
+// The last session of `user` is gone.
+async fn went_offline<A: Adapter>(
+    s: &SocketRef<A>,
+    uid: ObjectId,
+    user: &str,
+    status: PresenceStatus,
+    onlineusers: &OnlineUsers,
+    db_state: &DbState,
 ) {
-    s.join(user.to_owned());
-    onlineusers.add(s.id.to_owned(), user.to_owned()).await;
-    info!("logged in: {}", &user);
-
-    // send back online users list to sender.
-    let e = onlineusers.entries().await;
-    s.emit("refreshUsers", &[e]).ok();
-
-    // notify all other users about this new online user.
-    let msg = [(s.id.to_string(), user)];
+    if let Err(e) = db_state.set_last_seen(uid).await {
+        error!("Failed to record last seen of {user}: {e}");
+    }
+    // Invisible users already look offline.
+    if status != PresenceStatus::Invisible {
+        notify_offline(s, uid, user, &[s.id], onlineusers).await;
+    }
+}
 
+// Besides `v1:userOnline`, the older `userOnline` with `[sid, name]` pairs is kept for
+// clients that predate per-user presence.
+async fn notify_online<A: Adapter>(s: &SocketRef<A>, uid: ObjectId, onlineusers: &OnlineUsers) {
+    let Some(msg) = onlineusers.presence(&uid).await else {
+        return;
+    };
+    let legacy = msg.legacy();
     let u = onlineusers.val().await;
     for d in u {
         let s = s.clone();
         let msg = msg.clone();
+        let legacy = legacy.clone();
         tokio::spawn(async move {
-            if let Err(err) = s.to(d.clone()).emit("userOnline", &msg).await {
+            if let Err(err) = s.to(d.clone()).emit("userOnline", &legacy).await {
                 error!("Error on identify handler when notifying {d}: {err}");
             }
+            s.to(d).emit("v1:userOnline", &msg).await.ok();
         });
     }
-    // for d in onlineusers.val().await.iter() {
-    //     if let Err(err) = s.to(d.to_owned()).emit("userOnline", &msg).await {
-    //         error!("Error on identify handler when notifying {d}: {err}");
-    //     }
-    // }
 }
 
-pub async fn on_signout<A: Adapter>(s: SocketRef<A>, onlineusers: State<OnlineUsers>) {
-    let msgout = json!(s.id.to_string());
-    if let Some(user) = onlineusers.get(&s.id).await {
-        info!("{} has signed out", &user);
-    }
-
+// `sids` are the sessions that went away, which older clients get one `userOffline` each for.
+async fn notify_offline<A: Adapter>(
+    s: &SocketRef<A>,
+    uid: ObjectId,
+    user: &str,
+    sids: &[Sid],
+    onlineusers: &OnlineUsers,
+) {
+    let msgout = json!({"uid": encode_oid(uid), "user": user});
+    let sids: Vec<String> = sids.iter().map(Sid::to_string).collect();
     let u = onlineusers.val().await;
     for d in u {
         let s = s.clone();
         let msgout = msgout.clone();
+        let sids = sids.clone();
         tokio::spawn(async move {
-            s.to(d).emit("userOffline", &msgout).await.ok();
+            for sid in sids {
+                s.to(d.to_owned()).emit("userOffline", &sid).await.ok();
+            }
+            s.to(d).emit("v1:userOffline", &msgout).await.ok();
         });
-        // s.to(d.to_owned()).emit("userOffline", &s.id).await.ok();
+    }
+}
+
+// Send the online list in both the `[sid, name]` and the per-user shape.
+fn refresh_users<A: Adapter>(s: &SocketRef<A>, entries: Vec<UserPresence>) {
+    let legacy: Vec<_> = entries.iter().flat_map(UserPresence::legacy).collect();
+    s.emit("refreshUsers", &[legacy]).ok();
+    s.emit("v1:refreshUsers", &entries).ok();
+}
+
+// Drop the session of `s`, leaving its user room and taking the user offline
+// if it was their last one.
+async fn sign_out<A: Adapter>(s: &SocketRef<A>, onlineusers: &OnlineUsers, db_state: &DbState) {
+    if let Some((uid, user, last)) = onlineusers.remove(&s.id).await {
+        info!("{} has signed out", &user);
+        s.leave(user.to_owned());
+        if let Some(status) = last {
+            went_offline(s, uid, &user, status, onlineusers, db_state).await;
+        }
+    }
+}
+
+pub async fn on_identify<A: Adapter>(
+    s: SocketRef<A>,
+    Data(identity): Data<Identity>,
+    onlineusers: State<OnlineUsers>,
+    db_state: State<DbState>,
+) {
+    let (name, client) = match identity {
+        Identity::Name(user) => (user, None),
+        Identity::Session { user, client } => (user, client),
+    };
+    // Sockets that signed in on connect are known by their account, the legacy
+    // namespace has to name an existing one.
+    let (uid, user) = match s.extensions.get::<UserAuth>() {
+        Some(auth) => (auth.uid, auth.name),
+        None => match db_state.find_users(std::slice::from_ref(&name)).await {
+            Ok(found) => match found.first() {
+                Some(u) => (u.uid(), u.name.to_owned()),
+                None => {
+                    warn!("{} tried to identify as unknown user {name}", s.id);
+                    return;
+                }
+            },
+            Err(e) => {
+                error!("Failed to look up user {name}: {e}");
+                return;
+            }
+        },
+    };
+    // Identifying again as someone else signs the previous user out first.
+    if onlineusers.uid(&s.id).await.is_some_and(|prev| prev != uid) {
+        sign_out(&s, &onlineusers, &db_state).await;
+    }
+    s.join(user.to_owned());
+    let status = db_state.user_status(uid).await.unwrap_or_else(|e| {
+        warn!("Failed to load status of {user}: {e}");
+        PresenceStatus::default()
+    });
+    let first = onlineusers
+        .add(s.id.to_owned(), uid, user.to_owned(), client, status)
+        .await;
+    info!("logged in: {}", &user);
+
+    // send back online users list to sender.
+    refresh_users(&s, onlineusers.entries(Some(&uid)).await);
+
+    // Only the first session of a user brings them online.
+    if first && status != PresenceStatus::Invisible {
+        notify_online(&s, uid, &onlineusers).await;
+    }
+    // Messages are only handed to sockets that proved who they are.
+    if s.extensions.get::<UserAuth>().is_some() {
+        deliver_pending_dms(&s, &user, &db_state).await;
     }
     // for d in onlineusers.val().await.iter() {
-    //     s.to(d.to_owned()).emit("userOffline", &msgout).await.ok();
+    //     if let Err(err) = s.to(d.to_owned()).emit("userOnline", &msg).await {
+    //         error!("Error on identify handler when notifying {d}: {err}");
+    //     }
     // }
-    onlineusers.remove(&s.id).await;
+}
+
+pub async fn on_signout<A: Adapter>(
+    s: SocketRef<A>,
+    onlineusers: State<OnlineUsers>,
+    db_state: State<DbState>,
+) {
+    sign_out(&s, &onlineusers, &db_state).await;
 }
 
 pub async fn on_fetchuser<A: Adapter>(s: SocketRef<A>, onlineusers: State<OnlineUsers>) {
-    let e = onlineusers.entries().await;
-    s.emit("refreshUsers", &[e]).ok();
+    let viewer = onlineusers.uid(&s.id).await;
+    refresh_users(&s, onlineusers.entries(viewer.as_ref()).await);
 }
 
-pub async fn on_message<A: Adapter>(s: SocketRef<A>, Data(msg): Data<Value>, ack: AckSender) {
+pub async fn on_status<A: Adapter>(
+    s: SocketRef<A>,
+    Data(status): Data<PresenceStatus>,
+    onlineusers: State<OnlineUsers>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    let Some(uid) = onlineusers.uid(&s.id).await else {
+        ack.send(&AckReply {
+            success: false,
+            message: "Not identified".to_string(),
+        })
+        .ok();
+        return;
+    };
+    let Some(previous) = onlineusers.set_status(&uid, status).await else {
+        return;
+    };
+    let Some(presence) = onlineusers.presence(&uid).await else {
+        return;
+    };
+    let user = presence.user.to_owned();
+    if let Err(e) = db_state.set_user_status(uid, status).await {
+        error!("Failed to store status of {user}: {e}");
+    }
+
+    if previous != status {
+        match (previous, status) {
+            (_, PresenceStatus::Invisible) => {
+                let sids: Vec<Sid> = presence.sessions.iter().map(|s| s.sid).collect();
+                notify_offline(&s, uid, &user, &sids, &onlineusers).await
+            }
+            (PresenceStatus::Invisible, _) => notify_online(&s, uid, &onlineusers).await,
+            _ => {
+                let msg = json!({"uid": presence.uid, "user": user, "status": status});
+                for d in onlineusers.val().await {
+                    s.to(d).emit("userStatus", &msg).await.ok();
+                }
+            }
+        }
+    }
+    info!("Status of {} set to {:?}", user, status);
+    ack.send(&AckReply {
+        success: true,
+        message: status,
+    })
+    .ok();
+}
+
+const MAX_DM_LEN: usize = 4000;
+// Base64 ciphertext carries padding and MACs on top of the plain text.
+const MAX_CIPHERTEXT_LEN: usize = 8192;
+const MAX_HEADER_LEN: usize = 1024;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DmRequest {
+    to: String,
+    body: String,
+    #[serde(default)]
+    encrypted: bool,
+    header: Option<String>,
+}
+
+impl DmRequest {
+    // Ciphertext is only checked to be base64, its content stays opaque. Plain
+    // text is refused unless `allow_plaintext`.
+    fn validate(&self, allow_plaintext: bool) -> Result<(), String> {
+        if !self.encrypted {
+            if !allow_plaintext {
+                return Err("Messages must be encrypted".to_string());
+            }
+            if self.header.is_some() {
+                return Err("Header is only allowed on encrypted messages".to_string());
+            }
+            if self.body.is_empty() || self.body.len() > MAX_DM_LEN {
+                return Err(format!("Message must be 1 to {MAX_DM_LEN} bytes"));
+            }
+            return Ok(());
+        }
+        if self.body.is_empty()
+            || self.body.len() > MAX_CIPHERTEXT_LEN
+            || BASE64_STANDARD.decode(&self.body).is_err()
+        {
+            return Err(format!(
+                "Ciphertext must be base64 of 1 to {MAX_CIPHERTEXT_LEN} bytes"
+            ));
+        }
+        if let Some(h) = &self.header
+            && (h.len() > MAX_HEADER_LEN || BASE64_STANDARD.decode(h).is_err())
+        {
+            return Err(format!(
+                "Header must be base64 of at most {MAX_HEADER_LEN} bytes"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Hand over what was sent to `user` while they were offline.
+async fn deliver_pending_dms<A: Adapter>(s: &SocketRef<A>, user: &str, db_state: &DbState) {
+    let msgs = match db_state.undelivered_messages(user).await {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Failed to load pending messages of {user}: {e}");
+            return;
+        }
+    };
+    if msgs.is_empty() {
+        return;
+    }
+    let mids: Vec<_> = msgs.iter().map(|m| m.oid).collect();
+    for m in msgs {
+        s.emit("dm", &DirectMessage::from(m)).ok();
+    }
+    if let Err(e) = db_state.mark_delivered(&mids).await {
+        error!("Failed to mark messages of {user} delivered: {e}");
+    }
+}
+
+async fn send_dm<A: Adapter>(
+    s: &SocketRef<A>,
+    io: &SocketIo<A>,
+    req: DmRequest,
+    db_state: &DbState,
+) -> Result<DirectMessage, String> {
+    let sender = s
+        .extensions
+        .get::<UserAuth>()
+        .map(|auth| auth.name)
+        .ok_or("Not signed in".to_string())?;
+    req.validate(db_state.allow_plaintext_dm())?;
+    if req.to == sender {
+        return Err("Cannot message yourself".to_string());
+    }
+    let known = db_state
+        .find_users(std::slice::from_ref(&req.to))
+        .await
+        .map_err(|e| e.to_string())?;
+    if known.is_empty() {
+        return Err(format!("No user found: {}", req.to));
+    }
+
+    let conv = db_state
+        .conversation(&sender, &req.to)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut msg = db_state
+        .add_message(
+            &conv,
+            &sender,
+            &req.body,
+            req.encrypted,
+            req.header.as_deref(),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Offline recipients get it on their next `identify`.
+    if user_connected(io, &req.to) {
+        emit_to_user(io, &req.to, "dm", &DirectMessage::from(msg.clone())).await;
+        match db_state.mark_delivered(&[msg.oid]).await {
+            Ok(()) => msg.delivered_at = Some(DateTime::now()),
+            Err(e) => error!("Failed to mark message {} delivered: {e}", msg.oid),
+        }
+    }
+    Ok(DirectMessage::from(msg))
+}
+
+pub async fn on_dm<A: Adapter>(
+    s: SocketRef<A>,
+    io: SocketIo<A>,
+    Data(req): Data<DmRequest>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    match send_dm(&s, &io, req, &db_state).await {
+        Ok(msg) => ack.send(&AckReply {
+            success: true,
+            message: json!(msg),
+        }),
+        Err(e) => ack.send(&AckReply {
+            success: false,
+            message: json!(e),
+        }),
+    }
+    .ok();
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReadRequest {
+    kind: ReadTarget,
+    id: String,
+    last: String,
+}
+
+/// Move the read marker of a topic or conversation up to `last`.
+pub async fn on_read<A: Adapter>(
+    s: SocketRef<A>,
+    io: SocketIo<A>,
+    Data(req): Data<ReadRequest>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    let res = match (
+        s.extensions.get::<UserAuth>(),
+        decode_oid(&req.id),
+        decode_oid(&req.last),
+    ) {
+        (None, ..) => Err("Not signed in".to_string()),
+        (Some(auth), Some(id), Some(last)) => match req.kind {
+            ReadTarget::Topic => mark_topic_read(&db_state, auth.uid, id, last).await,
+            ReadTarget::Conversation => {
+                mark_conversation_read(&io, &db_state, auth.uid, &auth.name, id, last).await
+            }
+        },
+        _ => Err("Invalid id".to_string()),
+    };
+    match res {
+        Ok(()) => ack.send(&AckReply {
+            success: true,
+            message: json!(req.last),
+        }),
+        Err(e) => ack.send(&AckReply {
+            success: false,
+            message: json!(e),
+        }),
+    }
+    .ok();
+}
+
+// A relayed typing start holds for this long unless refreshed or stopped.
+const TYPING_TTL: Duration = Duration::from_secs(5);
+// Repeated starts within this window are not relayed again.
+const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TypingState {
+    tid: String,
+    typing: bool,
+}
+
+async fn emit_readers<A: Adapter>(io: &SocketIo<A>, room: &str, count: usize) {
+    emit_to_room(io, room, "readers", &json!({"room": room, "count": count})).await;
+}
+
+async fn emit_typing<A: Adapter>(io: &SocketIo<A>, tid: &str, pseudonym: &str, typing: bool) {
+    let msg = json!({"tid": tid, "pseudonym": pseudonym, "typing": typing});
+    emit_to_room(io, tid, "typing", &msg).await;
+}
+
+// Join a topic or channel room and count the socket as one of its readers.
+async fn enter_room<A: Adapter>(
+    s: &SocketRef<A>,
+    io: &SocketIo<A>,
+    room: &str,
+    activity: &RoomActivity,
+) {
+    s.join(room.to_owned());
+    if let Some(count) = activity.enter(room, s.id).await {
+        emit_readers(io, room, count).await;
+    }
+}
+
+async fn exit_room<A: Adapter>(
+    s: &SocketRef<A>,
+    io: &SocketIo<A>,
+    room: &str,
+    activity: &RoomActivity,
+) {
+    // Anything that is not an encoded id would be a user or device room.
+    if decode_oid(room).is_none() {
+        return;
+    }
+    s.leave(room.to_owned());
+    if let Some(count) = activity.exit(room, &s.id).await {
+        emit_readers(io, room, count).await;
+    }
+}
+
+/// Join the room of a topic to receive its `postCreated`/`postUpdated`/`postDeleted` events.
+pub async fn on_subscribe_topic<A: Adapter>(
+    s: SocketRef<A>,
+    io: SocketIo<A>,
+    Data(tid): Data<String>,
+    activity: State<RoomActivity>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    let found = match decode_oid(&tid) {
+        Some(oid) => db_state.get_topic(oid).await.is_ok(),
+        None => false,
+    };
+    if !found {
+        ack.send(&AckReply {
+            success: false,
+            message: format!("No topic found: {tid}"),
+        })
+        .ok();
+        return;
+    }
+    enter_room(&s, &io, &tid, &activity).await;
+    ack.send(&AckReply {
+        success: true,
+        message: format!("Subscribed: {tid}"),
+    })
+    .ok();
+}
+
+pub async fn on_unsubscribe_topic<A: Adapter>(
+    s: SocketRef<A>,
+    io: SocketIo<A>,
+    Data(tid): Data<String>,
+    activity: State<RoomActivity>,
+) {
+    if let Some(pseudonym) = activity.stop_typing(s.id, &tid).await {
+        emit_typing(&io, &tid, &pseudonym, false).await;
+    }
+    exit_room(&s, &io, &tid, &activity).await;
+}
+
+/// Channels have no document of their own, one exists once it holds a topic.
+pub async fn on_subscribe_channel<A: Adapter>(
+    s: SocketRef<A>,
+    io: SocketIo<A>,
+    Data(cid): Data<String>,
+    activity: State<RoomActivity>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    let found = match decode_oid(&cid) {
+        Some(oid) => db_state.channel_exists(oid).await.unwrap_or_else(|e| {
+            error!("Failed to look up channel {cid}: {e}");
+            false
+        }),
+        None => false,
+    };
+    if !found {
+        ack.send(&AckReply {
+            success: false,
+            message: format!("No channel found: {cid}"),
+        })
+        .ok();
+        return;
+    }
+    enter_room(&s, &io, &cid, &activity).await;
+    ack.send(&AckReply {
+        success: true,
+        message: format!("Subscribed: {cid}"),
+    })
+    .ok();
+}
+
+pub async fn on_unsubscribe_channel<A: Adapter>(
+    s: SocketRef<A>,
+    io: SocketIo<A>,
+    Data(cid): Data<String>,
+    activity: State<RoomActivity>,
+) {
+    exit_room(&s, &io, &cid, &activity).await;
+}
+
+/// Typing start/stop in a subscribed topic, relayed under the per-topic pseudonym.
+pub async fn on_typing<A: Adapter>(
+    s: SocketRef<A>,
+    io: SocketIo<A>,
+    Data(state): Data<TypingState>,
+    activity: State<RoomActivity>,
+    db_state: State<DbState>,
+) {
+    let TypingState { tid, typing } = state;
+    if !typing {
+        if let Some(pseudonym) = activity.stop_typing(s.id, &tid).await {
+            emit_typing(&io, &tid, &pseudonym, false).await;
+        }
+        return;
+    }
+
+    // The pseudonym is derived from the account the socket signed in with.
+    let (Some(oid), Some(auth), Some(secret)) = (
+        decode_oid(&tid),
+        s.extensions.get::<UserAuth>(),
+        db_state.secret(),
+    ) else {
+        return;
+    };
+    if !s.rooms().iter().any(|r| r == &tid) {
+        return;
+    }
+    let alias = pseudonym(&secret, &auth.name, oid);
+    let started = activity
+        .start_typing(s.id, &tid, &alias, TYPING_THROTTLE)
+        .await;
+    if started == TypingStart::Quiet {
+        return;
+    }
+    emit_typing(&io, &tid, &alias, true).await;
+
+    // Clients that vanish without a stop still stop typing after a while. One
+    // watcher per typing state, refreshes only push its deadline out.
+    let TypingStart::New(since) = started else {
+        return;
+    };
+    let activity = activity.0.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(TYPING_TTL).await;
+            match activity.expire_typing(s.id, &tid, since, TYPING_TTL).await {
+                Some(true) => {
+                    emit_typing(&io, &tid, &alias, false).await;
+                    break;
+                }
+                Some(false) => continue,
+                None => break,
+            }
+        }
+    });
+}
+
+/// Last time each of `users` went offline. Invisible users never show as online.
+pub async fn on_lastseen<A: Adapter>(
+    _s: SocketRef<A>,
+    Data(users): Data<Vec<String>>,
+    onlineusers: State<OnlineUsers>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    let docs = match db_state.find_users(&users).await {
+        Ok(d) => d,
+        Err(e) => {
+            ack.send(&AckReply {
+                success: false,
+                message: json!(format!("Failed to query users: {e}")),
+            })
+            .ok();
+            return;
+        }
+    };
+    let mut seen = HashMap::new();
+    for u in docs {
+        let online = matches!(
+            onlineusers.status(&u.uid()).await,
+            Some(st) if st != PresenceStatus::Invisible
+        );
+        let entry = json!({
+            "online": online,
+            "last_seen": u.last_seen.map(|t| t.timestamp_millis()),
+        });
+        seen.insert(u.name, entry);
+    }
+    ack.send(&AckReply {
+        success: true,
+        message: json!(seen),
+    })
+    .ok();
+}
+
+pub async fn on_message<A: Adapter>(
+    s: SocketRef<A>,
+    Data(msg): Data<Value>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
     if let Some(devroom) = s.extensions.get::<Topic>() {
+        let kind = msg.get("type").and_then(Value::as_str);
+        let timeout = db_state.message_timeout(kind);
         match s
             .to(devroom.title)
-            .timeout(Duration::from_secs(5))
+            .timeout(timeout)
             .emit_with_ack::<Value, Value>("message", &msg)
             .await
         {
             Ok(ack_stream) => {
-                if let Ok(ack_s) = ack_stream.await {
-                    ack.send(&ack_s).ok();
-                } else {
-                    ack.send(&AckReply {
-                        success: false,
-                        message: "Failed to send message",
-                    })
-                    .ok();
-                }
+                let summary = AckSummary::collect(ack_stream).await;
+                ack.send(&AckReply {
+                    success: summary.answered(),
+                    message: summary,
+                })
+                .ok();
             }
             Err(_) => {
                 ack.send(&AckReply {
@@ -170,7 +967,16 @@ pub async fn on_boxconf<A: Adapter>(
     Data(devid): Data<String>,
     ack: AckSender,
     onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
 ) {
+    if let Err(e) = owned_device(&s, &db_state, &devid).await {
+        ack.send(&AckReply {
+            success: false,
+            message: e,
+        })
+        .ok();
+        return;
+    }
     let sid = match onlinedevs.getcamid(&devid).await {
         Some(s) => s,
         None => {
@@ -204,10 +1010,18 @@ pub async fn on_unset<A: Adapter>(
     s: SocketRef<A>,
     Data(devid): Data<String>,
     onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
     ack: AckSender,
 ) {
     let rsid = s.within(devid.to_owned()).sockets();
     if rsid.contains(&s) {
+        end_call(&s, &db_state, EndReason::Leave).await;
+        if onlinedevs.get(&s.id).await.as_ref() == Some(&devid) {
+            end_room_calls(&s, &devid, &db_state, EndReason::Hangup).await;
+            drop_queue(&s, &devid, &onlinedevs).await;
+        } else {
+            release_slot(&s, &devid, &onlinedevs, &db_state).await;
+        }
         // sending to all clients in the room (channel) except sender
         s.to(devid.to_owned()).emit("hangup", &s.id).await.ok();
         s.extensions.remove::<Topic>();
@@ -218,6 +1032,11 @@ pub async fn on_unset<A: Adapter>(
     if let Some(dev) = onlinedevs.get(&s.id).await {
         onlinedevs.remove(&s.id).await;
         onlinedevs.speaker_off(&dev).await;
+        onlinedevs.unpair(&s.id).await;
+        audit(
+            &db_state,
+            AuditEvent::new(AuditKind::DeviceUnset, &dev, s.id.as_str()),
+        );
         info!("Device unset: {dev}");
     }
     ack.send(&AckReply {
@@ -241,7 +1060,7 @@ pub async fn on_checkdev<A: Adapter>(
     Data(devs): Data<Vec<String>>,
     onlinedevs: State<OnlineDevs>,
 ) {
-    if devs.len() > 0 {
+    if !devs.is_empty() {
         let mut devset = onlinedevs.val().await;
         devset.retain(|d| devs.contains(d));
         let mut devvec: Vec<String> = devset.into_iter().collect();
@@ -254,7 +1073,24 @@ pub async fn on_find<A: Adapter>(
     s: SocketRef<A>,
     Data(devid): Data<String>,
     onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
 ) {
+    // A box may only announce the device its token was issued for. Without one,
+    // on `/` as much as on `/box` and `/call`, it can only wait to be paired.
+    match s.extensions.get::<DeviceAuth>() {
+        Some(DeviceAuth(own)) if own != devid => {
+            warn!("{} tried to register {} as {}", s.id, own, devid);
+            return;
+        }
+        None if !devid.starts_with("Unbound") => {
+            warn!(
+                "Unauthenticated socket {} tried to register as {}",
+                s.id, devid
+            );
+            return;
+        }
+        _ => {}
+    }
     s.join(devid.to_owned());
     s.extensions.insert::<Topic>(Topic {
         title: devid.to_owned(),
@@ -266,13 +1102,46 @@ pub async fn on_find<A: Adapter>(
     let rs = s.within(devid.to_owned()).sockets();
     let roomsids: HashSet<String> = HashSet::from_iter(rs.iter().map(|r| r.id.to_string()));
     info!("Camera online: {} - {:?}", devid, roomsids);
+    audit(
+        &db_state,
+        AuditEvent::new(AuditKind::DeviceOnline, &devid, s.id.as_str()),
+    );
+
+    // Re-send a configuration the box never confirmed.
+    match db_state.latest_box_config(&devid).await {
+        Ok(Some(rev)) if rev.applied_at.is_none() => {
+            tokio::spawn(push_config(s.clone(), rev, db_state.0.clone()));
+        }
+        Ok(_) => {}
+        Err(e) => error!("Failed to load config of {devid}: {e}"),
+    }
+
+    // Hand over whatever was queued while the box was offline.
+    tokio::spawn(deliver_commands(
+        s.clone(),
+        devid.to_owned(),
+        db_state.0.clone(),
+    ));
+
+    // Unbound boxes get a pairing code to display so an owner can claim them.
+    if devid.starts_with("Unbound") {
+        let code = onlinedevs.pair(s.id, &devid).await;
+        s.emit("paircode", &code).ok();
+        info!("Pairing code issued: {} - {}", devid, code);
+    }
 }
 
 pub async fn on_watch<A: Adapter>(
     s: SocketRef<A>,
     Data(devid): Data<String>,
     onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
 ) {
+    if let Err(e) = watchable_device(&s, &db_state, &devid).await {
+        warn!("{} may not watch {devid}: {e}", s.id);
+        s.emit("nodev", &Value::Null).ok();
+        return;
+    }
     if !onlinedevs.val().await.contains(&devid) {
         s.emit("nodev", &Value::Null).ok();
         warn!("No device found for watching: {devid}");
@@ -287,24 +1156,61 @@ pub async fn on_watch<A: Adapter>(
         }
     };
 
-    s.join(devid.to_owned());
+    // Only one device can be watched (or waited for) at a time.
+    let previous = s
+        .extensions
+        .get::<Waiting>()
+        .map(|Waiting(d)| d)
+        .or(s.extensions.get::<Topic>().map(|t| t.title));
+    if let Some(prev) = previous.filter(|p| p != &devid) {
+        release_slot(&s, &prev, &onlinedevs, &db_state).await;
+    }
 
-    s.extensions.insert::<Topic>(Topic {
-        title: devid.to_owned(),
-        tid: sid,
-        // speaker: None,
-    });
+    match onlinedevs.enter(&devid, s.id).await {
+        Admission::Admitted => {
+            start_watch(&s, &devid, sid, &db_state).await;
+        }
+        Admission::Queued(position) => {
+            s.extensions.insert(Waiting(devid.to_owned()));
+            s.emit("queued", &json!({"devid": devid, "position": position}))
+                .ok();
+            info!("Queued for {}: {} at {}", devid, s.id, position);
+        }
+    }
+}
 
-    s.within(devid.to_owned()).emit("join", &s.id).await.ok();
-    let rs = s.within(devid.to_owned()).sockets();
-    let roomsids: HashSet<String> = HashSet::from_iter(rs.iter().map(|r| r.id.to_string()));
-    info!("Watching: {} - {:?}", devid, roomsids);
+pub async fn on_viewerlimit<A: Adapter>(
+    s: SocketRef<A>,
+    Data(limit): Data<Option<usize>>,
+    onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    // Only the device itself may change its capacity.
+    let Some(devid) = onlinedevs.get(&s.id).await else {
+        ack.send(&AckReply {
+            success: false,
+            message: "Not a device".to_string(),
+        })
+        .ok();
+        return;
+    };
+    onlinedevs.set_max_viewers(&devid, limit).await;
+    let admitted = onlinedevs.refill(&devid).await;
+    admit(&s, &devid, admitted, &onlinedevs, &db_state).await;
+    info!("Viewer limit updated: {} -> {:?}", devid, limit);
+    ack.send(&AckReply {
+        success: true,
+        message: format!("Viewer limit of {devid}: {limit:?}"),
+    })
+    .ok();
 }
 
 pub async fn on_speakerid<A: Adapter>(
     s: SocketRef<A>,
     Data(speaker): Data<String>,
     onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
 ) {
     let devname = match s.extensions.get::<Topic>() {
         Some(room) => room.title,
@@ -315,6 +1221,11 @@ pub async fn on_speakerid<A: Adapter>(
     // sending to all clients in the room (channel) except sender
     if speaker.len() != 16 {
         onlinedevs.speaker_off(&devname).await;
+        audit(
+            &db_state,
+            AuditEvent::new(AuditKind::SpeakerChange, &devname, s.id.as_str())
+                .with_detail(json!({"speaker": null})),
+        );
         info!("Speakerid cleared: {}", devname);
         return;
     }
@@ -322,6 +1233,16 @@ pub async fn on_speakerid<A: Adapter>(
         Ok(sid) => {
             info!("Speakerid updated: {} -> {}", speaker, devname);
             onlinedevs.speaker_on(sid, &devname).await;
+            let user = s
+                .broadcast()
+                .get_socket(sid)
+                .and_then(|r| r.extensions.get::<UserAuth>());
+            audit(
+                &db_state,
+                AuditEvent::new(AuditKind::SpeakerChange, &devname, s.id.as_str())
+                    .with_user(user.map(|u| (u.uid, u.name)))
+                    .with_detail(json!({"speaker": speaker})),
+            );
         }
         Err(e) => {
             error!("Failed to parse Sid in `speaker` handler. {e:}");
@@ -347,6 +1268,7 @@ pub async fn on_speech<A: Adapter>(
     s: SocketRef<A>,
     Data(sid): Data<Value>,
     onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
     ack: AckSender,
 ) {
     let devname = match s.extensions.get::<Topic>() {
@@ -368,30 +1290,575 @@ pub async fn on_speech<A: Adapter>(
             message: "Failed",
         })
         .ok();
+        if let Some(session) = s.extensions.get::<CallSession>()
+            && let Err(e) = db_state.mark_speech(session.oid).await
+        {
+            error!("Failed to mark speech on call session {}: {e}", session.oid);
+        }
         s.to(devname).emit("speaking", &sid).await.ok();
     }
 }
 
-pub async fn on_hang<A: Adapter>(s: SocketRef<A>, Data(sid): Data<Value>) {
+pub async fn on_hang<A: Adapter>(
+    s: SocketRef<A>,
+    Data(sid): Data<Value>,
+    onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
+) {
+    end_call(&s, &db_state, EndReason::Hangup).await;
     // sending to all clients in the room (channel) except sender
     if let Some(devroom) = s.extensions.get::<Topic>() {
+        if devroom.tid == s.id {
+            end_room_calls(&s, &devroom.title, &db_state, EndReason::Hangup).await;
+        } else {
+            release_slot(&s, &devroom.title, &onlinedevs, &db_state).await;
+        }
         s.to(devroom.title).emit("hangup", &sid).await.ok();
     }
 }
 
-pub async fn on_reject<A: Adapter>(s: SocketRef<A>) {
+pub async fn on_reject<A: Adapter>(
+    s: SocketRef<A>,
+    onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
+) {
     if let Some(devroom) = s.extensions.get::<Topic>() {
-        s.to(devroom.title).emit("full", &Value::Null).await.ok();
+        end_room_calls(&s, &devroom.title, &db_state, EndReason::Full).await;
+        s.to(devroom.title.to_owned())
+            .emit("full", &Value::Null)
+            .await
+            .ok();
+        // The rejected viewers give their slots up so the queue can move on.
+        for r in s.within(devroom.title.to_owned()).sockets() {
+            if r.id != s.id && r.id != devroom.tid {
+                release_slot(&r, &devroom.title, &onlinedevs, &db_state).await;
+            }
+        }
     }
 }
 
-pub async fn on_leave<A: Adapter>(s: SocketRef<A>, Data(devid): Data<String>) {
+pub async fn on_leave<A: Adapter>(
+    s: SocketRef<A>,
+    Data(devid): Data<String>,
+    onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
+) {
+    release_slot(&s, &devid, &onlinedevs, &db_state).await;
     let rsid = s.within(devid.to_owned()).sockets();
     if rsid.contains(&s) {
+        end_call(&s, &db_state, EndReason::Leave).await;
         // sending to all clients in the room (channel) except sender
         s.to(devid.to_owned()).emit("hangup", &s.id).await.ok();
         s.extensions.remove::<Topic>();
         s.leave(devid.to_owned());
         info!("{} has left room: {}", &s.id, devid);
     }
-}
\ No newline at end of file
+}
+
+pub async fn on_signal<A: Adapter>(
+    s: SocketRef<A>,
+    TryData(req): TryData<SignalRequest>,
+    ack: AckSender,
+) {
+    let res = match req {
+        Ok(req) => deliver_signal(&s, req).await,
+        Err(e) => Err(format!("Malformed signal: {e}")),
+    };
+    let reply = match res {
+        Ok(message) => AckReply {
+            success: true,
+            message,
+        },
+        Err(message) => {
+            warn!("Signal from {} rejected: {}", s.id, message);
+            AckReply {
+                success: false,
+                message,
+            }
+        }
+    };
+    ack.send(&reply).ok();
+}
+
+async fn deliver_signal<A: Adapter>(
+    s: &SocketRef<A>,
+    req: SignalRequest,
+) -> Result<String, String> {
+    req.signal.validate()?;
+    let devroom = s
+        .extensions
+        .get::<Topic>()
+        .ok_or("Not in a device room".to_string())?;
+
+    // The camera registered the room with its own sid, everyone else is a viewer.
+    let role = if devroom.tid == s.id {
+        Role::Device
+    } else {
+        Role::Viewer
+    };
+    let in_room = s
+        .within(devroom.title.to_owned())
+        .sockets()
+        .iter()
+        .any(|r| r.id == req.to);
+    if !in_room || req.to == s.id {
+        return Err(format!("Peer not in room: {}", req.to));
+    }
+    if role == Role::Viewer && req.to != devroom.tid {
+        return Err("Viewers may only signal the device".to_string());
+    }
+
+    let msg = SignalMessage {
+        from: s.id,
+        signal: req.signal,
+    };
+    // Sockets are not in a room named after their own sid, so address the socket itself.
+    let peer = s
+        .broadcast()
+        .get_socket(req.to)
+        .ok_or(format!("Peer disconnected: {}", req.to))?;
+    peer.emit(SIGNAL_EVENT, &msg)
+        .map_err(|e| format!("Failed to deliver signal: {e}"))?;
+    Ok(format!("Delivered to {}", req.to))
+}
+
+/// Send a configuration revision to its box and record it once the box acks it.
+pub async fn push_config<A: Adapter>(dev: SocketRef<A>, rev: ConfigRevision, db_state: DbState) {
+    let payload = json!({"revision": rev.revision, "schema": rev.schema, "config": rev.config});
+    let timeout = db_state.message_timeout(Some("applyconf"));
+    let stream = match dev
+        .timeout(timeout)
+        .emit_with_ack::<Value, AppliedConf>("applyconf", &payload)
+    {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!(
+                "Failed to push config {} to {}: {e}",
+                rev.revision, rev.devid
+            );
+            return;
+        }
+    };
+    match stream.await {
+        Ok(applied) if applied.revision == rev.revision => {
+            if let Err(e) = db_state.mark_config_applied(&rev.devid, rev.revision).await {
+                error!("Failed to mark config {} applied: {e}", rev.revision);
+                return;
+            }
+            dev.within(rev.devid.to_owned())
+                .emit(
+                    "confapplied",
+                    &json!({"devid": rev.devid, "revision": rev.revision}),
+                )
+                .await
+                .ok();
+            info!("Config applied: {} - rev {}", rev.devid, rev.revision);
+        }
+        Ok(applied) => warn!(
+            "Box {} acked config {} instead of {}",
+            rev.devid, applied.revision, rev.revision
+        ),
+        // Still unapplied, so it goes out again when the box reconnects.
+        Err(e) => warn!("Config {} not applied by {}: {e}", rev.revision, rev.devid),
+    }
+}
+
+pub async fn on_setconf<A: Adapter>(
+    s: SocketRef<A>,
+    Data(req): Data<SetConf>,
+    onlinedevs: State<OnlineDevs>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    // Room state only says the socket went through `boxconf`, so check the owner again.
+    if let Err(e) = owned_device(&s, &db_state, &req.devid).await {
+        ack.send(&AckReply {
+            success: false,
+            message: e,
+        })
+        .ok();
+        return;
+    }
+    if s.extensions.get::<Topic>().map(|t| t.title) != Some(req.devid.to_owned()) {
+        ack.send(&AckReply {
+            success: false,
+            message: format!("Not configuring: {}", req.devid),
+        })
+        .ok();
+        return;
+    }
+    let author = match s.extensions.get::<UserAuth>() {
+        Some(auth) => auth.name,
+        None => s.id.to_string(),
+    };
+    let rev = match db_state
+        .save_box_config(&req.devid, req.schema, req.config, &author)
+        .await
+    {
+        Ok(rev) => rev,
+        Err(e) => {
+            ack.send(&AckReply {
+                success: false,
+                message: format!("Failed to save config: {e}"),
+            })
+            .ok();
+            return;
+        }
+    };
+    ack.send(&AckReply {
+        success: true,
+        message: rev.revision,
+    })
+    .ok();
+    s.to(req.devid.to_owned())
+        .emit(
+            "confrev",
+            &json!({"devid": req.devid, "revision": rev.revision}),
+        )
+        .await
+        .ok();
+
+    if let Some(camid) = onlinedevs.getcamid(&req.devid).await
+        && let Some(dev) = s.broadcast().get_socket(camid)
+    {
+        tokio::spawn(push_config(dev, rev, db_state.0.clone()));
+    }
+}
+
+pub async fn on_getconf<A: Adapter>(
+    s: SocketRef<A>,
+    Data(devid): Data<String>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    if let Err(e) = owned_device(&s, &db_state, &devid).await {
+        ack.send(&AckReply {
+            success: false,
+            message: Some(e),
+        })
+        .ok();
+        return;
+    }
+    match db_state.latest_box_config(&devid).await {
+        Ok(rev) => ack.send(&AckReply {
+            success: true,
+            message: rev.map(Revision::from),
+        }),
+        Err(e) => ack.send(&AckReply {
+            success: false,
+            message: Some(format!("Failed to load config: {e}")),
+        }),
+    }
+    .ok();
+}
+
+/// Deliver the pending commands of `devid` one by one, waiting for each ack.
+/// Delivery stops at the first unanswered command so the order is kept for the next try.
+pub async fn deliver_commands<A: Adapter>(dev: SocketRef<A>, devid: String, db_state: DbState) {
+    let lock = db_state.command_lock(&devid);
+    let _running = lock.lock().await;
+    // Nothing is in flight while we hold the lock, so anything still marked delivered
+    // was lost with an earlier connection and goes out again.
+    match db_state.requeue_commands(&devid).await {
+        Ok(0) => {}
+        Ok(n) => info!("Requeued {n} unanswered commands of {devid}"),
+        Err(e) => {
+            error!("Failed to requeue commands of {devid}: {e}");
+            return;
+        }
+    }
+    let cmds = match db_state.pending_commands(&devid).await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to load commands of {devid}: {e}");
+            return;
+        }
+    };
+    let timeout = db_state.message_timeout(Some("command"));
+    for cmd in cmds {
+        match db_state
+            .transition_command(
+                cmd.oid,
+                CommandStatus::Pending,
+                CommandStatus::Delivered,
+                None,
+            )
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                error!("Failed to deliver command {}: {e}", cmd.oid);
+                return;
+            }
+        }
+
+        let payload = json!({"cid": encode_oid(cmd.oid), "type": cmd.kind, "payload": cmd.payload});
+        let res = match dev
+            .timeout(timeout)
+            .emit_with_ack::<Value, CommandAck>("command", &payload)
+        {
+            Ok(stream) => stream.await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let (to, result) = match res {
+            Ok(ack) if ack.success => (CommandStatus::Acked, ack.result),
+            Ok(ack) => (CommandStatus::Failed, ack.result),
+            Err(e) => {
+                warn!("Command {} not acked by {devid}: {e}", cmd.oid);
+                db_state
+                    .transition_command(
+                        cmd.oid,
+                        CommandStatus::Delivered,
+                        CommandStatus::Pending,
+                        None,
+                    )
+                    .await
+                    .ok();
+                return;
+            }
+        };
+        if let Err(e) = db_state
+            .transition_command(cmd.oid, CommandStatus::Delivered, to, result)
+            .await
+        {
+            error!("Failed to record ack of command {}: {e}", cmd.oid);
+        }
+        info!("Command {} {:?} by {devid}", cmd.oid, to);
+    }
+}
+
+pub async fn on_devauth<A: Adapter>(
+    s: SocketRef<A>,
+    Data(token): Data<String>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    let claims = db_state
+        .secret()
+        .ok_or("Secret not found in config".to_string())
+        .and_then(|secret| {
+            DeviceClaims::verify(&token, &secret).map_err(|_| "Invalid token".to_string())
+        });
+    match claims {
+        Ok(c) => {
+            let devid = c.devid();
+            s.extensions.insert(DeviceAuth(devid.to_owned()));
+            info!("Device authenticated: {} - {}", devid, s.id);
+            ack.send(&AckReply {
+                success: true,
+                message: devid,
+            })
+        }
+        Err(message) => ack.send(&AckReply {
+            success: false,
+            message,
+        }),
+    }
+    .ok();
+}
+
+pub async fn on_telemetry<A: Adapter>(
+    s: SocketRef<A>,
+    Data(report): Data<TelemetryReport>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    let Some(DeviceAuth(devid)) = s.extensions.get::<DeviceAuth>() else {
+        ack.send(&AckReply {
+            success: false,
+            message: "Device not authenticated".to_string(),
+        })
+        .ok();
+        return;
+    };
+    let res = match Sample::new(&devid, report.ts, report.metrics) {
+        Ok(sample) => db_state
+            .add_sample(&sample)
+            .await
+            .map_err(|e| format!("Failed to store telemetry: {e}")),
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(kept) => ack.send(&AckReply {
+            success: true,
+            message: if kept { "Stored" } else { "Downsampled" }.to_string(),
+        }),
+        Err(message) => ack.send(&AckReply {
+            success: false,
+            message,
+        }),
+    }
+    .ok();
+}
+
+/// What a box needs to fetch and verify a release.
+pub fn ota_offer(rel: &ReleaseDoc, base_url: &str) -> Value {
+    let rid = encode_oid(rel.oid);
+    json!({
+        "rid": rid,
+        "version": rel.version,
+        "model": rel.model,
+        "checksum": rel.checksum,
+        "size": rel.size,
+        "url": format!("{base_url}/releases/{rid}/artifact"),
+    })
+}
+
+pub async fn on_fwinfo<A: Adapter>(
+    s: SocketRef<A>,
+    Data(info): Data<FirmwareInfo>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    let Some(DeviceAuth(devid)) = s.extensions.get::<DeviceAuth>() else {
+        ack.send(&AckReply {
+            success: false,
+            message: "Device not authenticated".to_string(),
+        })
+        .ok();
+        return;
+    };
+    if let Err(e) = db_state
+        .set_device_firmware(&devid, &info.model, &info.version)
+        .await
+    {
+        error!("Failed to record firmware of {devid}: {e}");
+    }
+    ack.send(&AckReply {
+        success: true,
+        message: format!("{} {}", info.model, info.version),
+    })
+    .ok();
+
+    // Offer the newest rolling release of the box's owner it is included in.
+    let device = match db_state.get_device(&devid).await {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to load device {devid}: {e}");
+            return;
+        }
+    };
+    let releases = match db_state.rolling_releases(&device.owner, &info.model).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to load releases for {}: {e}", info.model);
+            return;
+        }
+    };
+    for rel in releases {
+        match db_state.offers_release(&rel, &device).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                error!("Failed to load rollout groups of {}: {e}", rel.oid);
+                continue;
+            }
+        }
+        if rel.version != info.version {
+            s.emit("ota", &ota_offer(&rel, &db_state.releases_base_url()))
+                .ok();
+            info!("Release {} offered to {}", rel.version, devid);
+        }
+        break;
+    }
+}
+
+pub async fn on_otastatus<A: Adapter>(
+    s: SocketRef<A>,
+    Data(status): Data<OtaStatus>,
+    db_state: State<DbState>,
+    ack: AckSender,
+) {
+    let Some(DeviceAuth(devid)) = s.extensions.get::<DeviceAuth>() else {
+        ack.send(&AckReply {
+            success: false,
+            message: "Device not authenticated".to_string(),
+        })
+        .ok();
+        return;
+    };
+    let Some(rid) = decode_oid(&status.rid) else {
+        ack.send(&AckReply {
+            success: false,
+            message: "Invalid release id".to_string(),
+        })
+        .ok();
+        return;
+    };
+    // Reports only count for releases this box could have been offered.
+    if let Err(e) = eligible_release(&db_state, rid, &devid).await {
+        ack.send(&AckReply {
+            success: false,
+            message: e,
+        })
+        .ok();
+        return;
+    }
+    let report = OtaReport {
+        release: rid,
+        devid: devid.to_owned(),
+        state: status.state,
+        progress: status.progress.map(|p| p.min(100)),
+        error: status.error,
+        updated_at: DateTime::now(),
+    };
+    if let Err(e) = db_state.upsert_report(&report).await {
+        ack.send(&AckReply {
+            success: false,
+            message: format!("Failed to store report: {e}"),
+        })
+        .ok();
+        return;
+    }
+    ack.send(&AckReply {
+        success: true,
+        message: format!("{:?}", status.state),
+    })
+    .ok();
+
+    if status.state == OtaState::Success {
+        info!("Release {} installed on {}", status.rid, devid);
+    }
+    if status.state != OtaState::Failed {
+        return;
+    }
+    warn!("Release {} failed on {}", status.rid, devid);
+    if let Err(e) = check_rollout_health(&db_state, rid).await {
+        error!("Failed to check rollout of {}: {e}", status.rid);
+    }
+}
+
+async fn eligible_release(db_state: &DbState, rid: ObjectId, devid: &str) -> Result<(), String> {
+    let not_offered = || format!("Release not offered to {devid}");
+    let rel = db_state.get_release(rid).await.map_err(|_| not_offered())?;
+    let device = db_state
+        .get_device(devid)
+        .await
+        .map_err(|_| not_offered())?;
+    match db_state.offers_release(&rel, &device).await {
+        Ok(true) if rel.status != ReleaseStatus::Draft => Ok(()),
+        Ok(_) => Err(not_offered()),
+        Err(e) => Err(format!("Failed to check release: {e}")),
+    }
+}
+
+// Stop offering a release once too many of the devices that finished it failed.
+async fn check_rollout_health(
+    db_state: &DbState,
+    rid: ObjectId,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let rel = db_state.get_release(rid).await?;
+    let (done, failed) = db_state.report_counts(rid).await?;
+    if done < rel.rollout.min_reports as u64 {
+        return Ok(());
+    }
+    let ratio = failed as f64 / done as f64;
+    if ratio > rel.rollout.failure_threshold && db_state.halt_release(rid).await? {
+        warn!(
+            "Rollout of {} {} halted: {}/{} failed",
+            rel.model, rel.version, failed, done
+        );
+    }
+    Ok(())
+}
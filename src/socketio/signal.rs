@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use socketioxide::socket::Sid;
+
+// Typed WebRTC signaling. Event names carry the protocol version so that the
+// payload shapes can evolve without breaking clients on the legacy events.
+pub const SIGNAL_EVENT: &str = "v1:signal";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Signal {
+    Offer {
+        sdp: String,
+    },
+    Answer {
+        sdp: String,
+    },
+    Candidate {
+        candidate: String,
+        #[serde(rename = "sdpMid")]
+        sdp_mid: Option<String>,
+        #[serde(rename = "sdpMLineIndex")]
+        sdp_mline_index: Option<u16>,
+    },
+}
+
+/// What a client sends: a signal addressed to one peer in its device room.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SignalRequest {
+    pub to: Sid,
+    pub signal: Signal,
+}
+
+/// What the addressed peer receives.
+#[derive(Serialize, Debug, Clone)]
+pub struct SignalMessage {
+    pub from: Sid,
+    pub signal: Signal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Device,
+    Viewer,
+}
+
+impl Signal {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Signal::Offer { sdp } | Signal::Answer { sdp } if sdp.trim().is_empty() => {
+                Err("Empty sdp".to_string())
+            }
+            Signal::Candidate { candidate, .. } if candidate.trim().is_empty() => {
+                Err("Empty candidate".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_signals_are_rejected() {
+        let blank = |s: &str| Signal::Offer { sdp: s.into() };
+        assert!(blank("").validate().is_err());
+        assert!(blank(" \n").validate().is_err());
+        assert!(Signal::Answer { sdp: "\t".into() }.validate().is_err());
+        let candidate = Signal::Candidate {
+            candidate: "  ".into(),
+            sdp_mid: Some("0".into()),
+            sdp_mline_index: Some(0),
+        };
+        assert!(candidate.validate().is_err());
+    }
+
+    #[test]
+    fn signals_pass() {
+        assert!(Signal::Offer { sdp: "v=0".into() }.validate().is_ok());
+        assert!(Signal::Answer { sdp: "v=0".into() }.validate().is_ok());
+        let candidate = Signal::Candidate {
+            candidate: "candidate:1 1 udp 2122260223 10.0.0.2 54400 typ host".into(),
+            sdp_mid: None,
+            sdp_mline_index: None,
+        };
+        assert!(candidate.validate().is_ok());
+    }
+
+    #[test]
+    fn wire_format() {
+        let signal: Signal =
+            serde_json::from_str(r#"{"type":"candidate","candidate":"c","sdpMLineIndex":1}"#)
+                .unwrap();
+        assert!(matches!(
+            signal,
+            Signal::Candidate {
+                sdp_mline_index: Some(1),
+                sdp_mid: None,
+                ..
+            }
+        ));
+        assert!(serde_json::from_str::<Signal>(r#"{"type":"bye"}"#).is_err());
+    }
+}
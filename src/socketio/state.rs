@@ -1,18 +1,129 @@
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use rand::{Rng, distr::Uniform};
+use serde::Serialize;
 use socketioxide::socket::Sid;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::sync::RwLock;
 
+use crate::db::{PresenceStatus, encode_oid};
+
 pub type DevMap = HashMap<Sid, String>;
 pub type SpeakerMap = HashMap<String, Sid>;
-pub type UserMap = HashMap<Sid, String>;
+pub type UserMap = HashMap<ObjectId, OnlineUser>;
+pub type PairingMap = HashMap<String, Pairing>;
+pub type AttemptMap = HashMap<String, Attempts>;
+pub type CapacityMap = HashMap<String, Capacity>;
+pub type ReaderMap = HashMap<String, HashSet<Sid>>;
+pub type TypingMap = HashMap<(Sid, String), Typing>;
+
+// Pairing codes are short-lived and only valid while the unbound box stays connected.
+const PAIRING_TTL: Duration = Duration::from_secs(600);
+const PAIRING_CHARSET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+// Failed claims a user may make per window before being locked out, so codes can't be guessed.
+const PAIRING_ATTEMPTS: u32 = 5;
+const PAIRING_WINDOW: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+pub struct Pairing {
+    pub sid: Sid,
+    pub device: String,
+    expires: Instant,
+}
+
+/// Failed pairing claims of a user within the current window.
+#[derive(Debug, Clone)]
+pub struct Attempts {
+    failed: u32,
+    since: Instant,
+}
+
+/// Viewer slots of a single device. `limit` overrides the registry-wide default.
+#[derive(Debug, Default)]
+pub struct Capacity {
+    viewers: HashSet<Sid>,
+    queue: VecDeque<Sid>,
+    limit: Option<usize>,
+}
+
+impl Capacity {
+    fn promote(&mut self, default: Option<usize>) -> Vec<Sid> {
+        let limit = self.limit.or(default).unwrap_or(usize::MAX);
+        let mut admitted = Vec::new();
+        while self.viewers.len() < limit {
+            match self.queue.pop_front() {
+                Some(next) => {
+                    self.viewers.insert(next);
+                    admitted.push(next);
+                }
+                None => break,
+            }
+        }
+        admitted
+    }
+}
+
+/// One open connection of a signed-in user.
+#[derive(Serialize, Debug, Clone)]
+pub struct UserSession {
+    pub sid: Sid,
+    pub client: Option<String>,
+    pub connected_at: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OnlineUser {
+    name: String,
+    status: PresenceStatus,
+    sessions: HashMap<Sid, UserSession>,
+}
+
+/// A user together with every session they have open.
+#[derive(Serialize, Debug, Clone)]
+pub struct UserPresence {
+    pub uid: String,
+    pub user: String,
+    pub status: PresenceStatus,
+    pub sessions: Vec<UserSession>,
+}
+
+impl UserPresence {
+    fn new(uid: &ObjectId, entry: &OnlineUser) -> Self {
+        Self {
+            uid: encode_oid(*uid),
+            user: entry.name.to_owned(),
+            status: entry.status,
+            sessions: entry.sessions.values().cloned().collect(),
+        }
+    }
+
+    /// `[sid, name]` of every session, the shape clients without `v1:` presence expect.
+    pub fn legacy(&self) -> Vec<(String, String)> {
+        self.sessions
+            .iter()
+            .map(|s| (s.sid.to_string(), self.user.to_owned()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    Queued(usize),
+}
 
 #[derive(Default, Clone)]
 pub struct OnlineDevs {
     onlinedevs: Arc<RwLock<DevMap>>,
     speakers: Arc<RwLock<SpeakerMap>>,
+    pairings: Arc<RwLock<PairingMap>>,
+    attempts: Arc<RwLock<AttemptMap>>,
+    capacity: Arc<RwLock<CapacityMap>>,
+    max_viewers: Option<usize>,
 }
 
 #[derive(Default, Clone)]
@@ -20,7 +131,38 @@ pub struct OnlineUsers {
     onlineusers: Arc<RwLock<UserMap>>,
 }
 
+#[derive(Debug, Clone)]
+pub struct Typing {
+    since: Instant,
+    relayed: Option<Instant>,
+    touched: Instant,
+    pseudonym: String,
+}
+
+/// What `start_typing` made of a typing start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypingStart {
+    /// A fresh typing state to relay. Its creation time identifies it to `expire_typing`.
+    New(Instant),
+    /// An ongoing one whose throttle window passed, relay it again.
+    Relay,
+    /// An ongoing one relayed recently, only refreshed.
+    Quiet,
+}
+
+/// Who is reading and who is typing in topic and channel rooms.
+#[derive(Default, Clone)]
+pub struct RoomActivity {
+    readers: Arc<RwLock<ReaderMap>>,
+    typing: Arc<RwLock<TypingMap>>,
+}
+
 impl OnlineDevs {
+    pub fn with_max_viewers(mut self, max_viewers: Option<usize>) -> Self {
+        self.max_viewers = max_viewers;
+        self
+    }
+
     pub async fn add(&self, sid: Sid, device: String) {
         let mut binding = self.onlinedevs.write().await;
         binding.entry(sid).or_insert(device);
@@ -61,38 +203,399 @@ impl OnlineDevs {
         self.onlinedevs.read().await.get(sid).cloned()
     }
 
+    /// Sockets of the given devices that are currently online.
+    pub async fn camids(&self, devices: &[String]) -> HashMap<Sid, String> {
+        let devmap = self.onlinedevs.read().await;
+        devmap
+            .iter()
+            .filter(|(_, dev)| devices.contains(dev))
+            .map(|(sid, dev)| (sid.to_owned(), dev.to_owned()))
+            .collect()
+    }
+
     pub async fn val(&self) -> HashSet<String> {
         let devmap = self.onlinedevs.read().await;
         HashSet::from_iter(devmap.values().cloned())
     }
+
+    /// Issue a fresh pairing code for an unbound box, replacing any previous one.
+    pub async fn pair(&self, sid: Sid, device: &str) -> String {
+        let mut binding = self.pairings.write().await;
+        binding.retain(|_, p| p.sid != sid && p.expires > Instant::now());
+
+        let charset = Uniform::new(0, PAIRING_CHARSET.len()).expect("non-empty charset");
+        let code = loop {
+            let code: String = rand::rng()
+                .sample_iter(charset)
+                .take(6)
+                .map(|i| PAIRING_CHARSET[i] as char)
+                .collect();
+            if !binding.contains_key(&code) {
+                break code;
+            }
+        };
+        binding.insert(
+            code.to_owned(),
+            Pairing {
+                sid,
+                device: device.to_owned(),
+                expires: Instant::now() + PAIRING_TTL,
+            },
+        );
+        code
+    }
+
+    /// Consume a pairing code. Returns `None` if it is unknown or expired.
+    pub async fn claim(&self, code: &str) -> Option<Pairing> {
+        let mut binding = self.pairings.write().await;
+        binding
+            .remove(&code.to_uppercase())
+            .filter(|p| p.expires > Instant::now())
+    }
+
+    /// Put a pairing back, e.g. when the box could not be told it was claimed.
+    pub async fn restore(&self, code: &str, pairing: Pairing) {
+        let mut binding = self.pairings.write().await;
+        binding.insert(code.to_uppercase(), pairing);
+    }
+
+    /// Whether `user` still has pairing attempts left in the current window.
+    pub async fn may_claim(&self, user: &str) -> bool {
+        let mut binding = self.attempts.write().await;
+        binding.retain(|_, a| a.since.elapsed() < PAIRING_WINDOW);
+        binding
+            .get(user)
+            .is_none_or(|a| a.failed < PAIRING_ATTEMPTS)
+    }
+
+    /// Count a claim with an unknown or expired code against `user`.
+    pub async fn claim_failed(&self, user: &str) {
+        let mut binding = self.attempts.write().await;
+        let entry = binding.entry(user.to_owned()).or_insert(Attempts {
+            failed: 0,
+            since: Instant::now(),
+        });
+        entry.failed += 1;
+    }
+
+    pub async fn unpair(&self, sid: &Sid) {
+        let mut binding = self.pairings.write().await;
+        binding.retain(|_, p| &p.sid != sid);
+    }
+
+    pub async fn set_max_viewers(&self, device: &str, limit: Option<usize>) {
+        let mut binding = self.capacity.write().await;
+        binding.entry(device.to_owned()).or_default().limit = limit;
+    }
+
+    /// Take a viewer slot on `device`, or join the back of its waiting queue.
+    pub async fn enter(&self, device: &str, sid: Sid) -> Admission {
+        let mut binding = self.capacity.write().await;
+        let cap = binding.entry(device.to_owned()).or_default();
+        if cap.viewers.contains(&sid) {
+            return Admission::Admitted;
+        }
+        match cap.limit.or(self.max_viewers) {
+            Some(limit) if cap.viewers.len() >= limit => {
+                let pos = match cap.queue.iter().position(|q| q == &sid) {
+                    Some(p) => p,
+                    None => {
+                        cap.queue.push_back(sid);
+                        cap.queue.len() - 1
+                    }
+                };
+                Admission::Queued(pos + 1)
+            }
+            _ => {
+                cap.viewers.insert(sid);
+                Admission::Admitted
+            }
+        }
+    }
+
+    /// Release whatever `sid` holds on `device`, slot or queue position.
+    /// Returns the queued viewers that were promoted into the freed slots.
+    pub async fn exit(&self, device: &str, sid: &Sid) -> Vec<Sid> {
+        let mut binding = self.capacity.write().await;
+        let Some(cap) = binding.get_mut(device) else {
+            return Vec::new();
+        };
+        cap.viewers.remove(sid);
+        cap.queue.retain(|q| q != sid);
+        cap.promote(self.max_viewers)
+    }
+
+    /// Fill any free slots of `device` from its queue, e.g. after its limit was raised.
+    pub async fn refill(&self, device: &str) -> Vec<Sid> {
+        let mut binding = self.capacity.write().await;
+        match binding.get_mut(device) {
+            Some(cap) => cap.promote(self.max_viewers),
+            None => Vec::new(),
+        }
+    }
+
+    pub async fn queue(&self, device: &str) -> Vec<Sid> {
+        let binding = self.capacity.read().await;
+        binding
+            .get(device)
+            .map(|cap| cap.queue.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget all slots of a device that went away. Returns who was still waiting.
+    pub async fn drop_viewers(&self, device: &str) -> Vec<Sid> {
+        let mut binding = self.capacity.write().await;
+        match binding.get_mut(device) {
+            Some(cap) => {
+                cap.viewers.clear();
+                cap.queue.drain(..).collect()
+            }
+            None => Vec::new(),
+        }
+    }
 }
 
 impl OnlineUsers {
-    pub async fn add(&self, sid: Sid, user: String) {
+    /// Register a session. Returns `true` if it is the first one of the user,
+    /// who then starts out with `status`.
+    pub async fn add(
+        &self,
+        sid: Sid,
+        uid: ObjectId,
+        name: String,
+        client: Option<String>,
+        status: PresenceStatus,
+    ) -> bool {
         let mut binding = self.onlineusers.write().await;
-        let _ = binding.entry(sid).or_insert(user);
+        let entry = binding.entry(uid).or_default();
+        let first = entry.sessions.is_empty();
+        if first {
+            entry.name = name;
+            entry.status = status;
+        }
+        entry.sessions.entry(sid).or_insert(UserSession {
+            sid,
+            client,
+            connected_at: Utc::now().timestamp_millis(),
+        });
+        first
     }
 
-    pub async fn remove(&self, sid: &Sid) {
+    /// Drop a session. Returns its user id and name, plus the status they left
+    /// with if it was their last session.
+    pub async fn remove(&self, sid: &Sid) -> Option<(ObjectId, String, Option<PresenceStatus>)> {
         let mut binding = self.onlineusers.write().await;
-        let _ = binding.remove(sid);
+        let uid = binding
+            .iter()
+            .find_map(|(uid, entry)| entry.sessions.contains_key(sid).then_some(*uid))?;
+        let entry = binding.get_mut(&uid)?;
+        entry.sessions.remove(sid);
+        let name = entry.name.to_owned();
+        if !entry.sessions.is_empty() {
+            return Some((uid, name, None));
+        }
+        let last = binding.remove(&uid).map(|e| e.status);
+        Some((uid, name, last))
     }
 
-    pub async fn get(&self, sid: &Sid) -> Option<String> {
-        self.onlineusers.read().await.get(sid).cloned()
+    /// Id of the user `sid` identified as.
+    pub async fn uid(&self, sid: &Sid) -> Option<ObjectId> {
+        let usermap = self.onlineusers.read().await;
+        usermap
+            .iter()
+            .find_map(|(uid, entry)| entry.sessions.contains_key(sid).then_some(*uid))
     }
 
+    /// Names of everyone online, which are also the names of their rooms.
     pub async fn val(&self) -> HashSet<String> {
         let usermap = self.onlineusers.read().await;
-        HashSet::from_iter(usermap.values().cloned())
+        HashSet::from_iter(usermap.values().map(|entry| entry.name.to_owned()))
+    }
+
+    pub async fn status(&self, uid: &ObjectId) -> Option<PresenceStatus> {
+        self.onlineusers.read().await.get(uid).map(|e| e.status)
     }
 
-    pub async fn entries(&self) -> Vec<(String, String)> {
+    /// Change the status of an online user. Returns the previous one.
+    pub async fn set_status(
+        &self,
+        uid: &ObjectId,
+        status: PresenceStatus,
+    ) -> Option<PresenceStatus> {
+        let mut binding = self.onlineusers.write().await;
+        let entry = binding.get_mut(uid)?;
+        Some(std::mem::replace(&mut entry.status, status))
+    }
+
+    pub async fn presence(&self, uid: &ObjectId) -> Option<UserPresence> {
         let usermap = self.onlineusers.read().await;
-        Vec::from_iter(
-            usermap
-                .iter()
-                .map(|(key, val)| (key.to_string(), val.to_owned())),
-        )
+        usermap.get(uid).map(|entry| UserPresence::new(uid, entry))
+    }
+
+    /// Everyone online as seen by `viewer`. Invisible users only see themselves.
+    pub async fn entries(&self, viewer: Option<&ObjectId>) -> Vec<UserPresence> {
+        let usermap = self.onlineusers.read().await;
+        usermap
+            .iter()
+            .filter(|(uid, entry)| {
+                entry.status != PresenceStatus::Invisible || viewer == Some(*uid)
+            })
+            .map(|(uid, entry)| UserPresence::new(uid, entry))
+            .collect()
+    }
+}
+
+impl RoomActivity {
+    /// Count `sid` as a reader of `room`. Returns the new count if it changed.
+    pub async fn enter(&self, room: &str, sid: Sid) -> Option<usize> {
+        let mut binding = self.readers.write().await;
+        let readers = binding.entry(room.to_owned()).or_default();
+        readers.insert(sid).then_some(readers.len())
+    }
+
+    /// Stop counting `sid` in `room`. Returns the new count if it changed.
+    pub async fn exit(&self, room: &str, sid: &Sid) -> Option<usize> {
+        let mut binding = self.readers.write().await;
+        let readers = binding.get_mut(room)?;
+        if !readers.remove(sid) {
+            return None;
+        }
+        let count = readers.len();
+        if count == 0 {
+            binding.remove(room);
+        }
+        Some(count)
+    }
+
+    /// Remove `sid` from every room it reads. Returns the rooms with their new counts.
+    pub async fn exit_all(&self, sid: &Sid) -> Vec<(String, usize)> {
+        let mut binding = self.readers.write().await;
+        let mut changed = Vec::new();
+        for (room, readers) in binding.iter_mut() {
+            if readers.remove(sid) {
+                changed.push((room.to_owned(), readers.len()));
+            }
+        }
+        binding.retain(|_, readers| !readers.is_empty());
+        changed
+    }
+
+    /// Record a typing start. `New` starts a typing state that needs an expiry,
+    /// `Relay` and `Quiet` refresh an ongoing one; only `New` and `Relay` are
+    /// relayed, at most once per `throttle` while the typist keeps going.
+    pub async fn start_typing(
+        &self,
+        sid: Sid,
+        room: &str,
+        pseudonym: &str,
+        throttle: Duration,
+    ) -> TypingStart {
+        let mut binding = self.typing.write().await;
+        let now = Instant::now();
+        let key = (sid, room.to_owned());
+        let Some(entry) = binding.get_mut(&key) else {
+            binding.insert(
+                key,
+                Typing {
+                    since: now,
+                    relayed: Some(now),
+                    touched: now,
+                    pseudonym: pseudonym.to_owned(),
+                },
+            );
+            return TypingStart::New(now);
+        };
+        entry.touched = now;
+        match entry.relayed {
+            Some(at) if at.elapsed() < throttle => TypingStart::Quiet,
+            _ => {
+                entry.relayed = Some(now);
+                TypingStart::Relay
+            }
+        }
+    }
+
+    /// Clear a typing state. Returns the pseudonym it was relayed under.
+    pub async fn stop_typing(&self, sid: Sid, room: &str) -> Option<String> {
+        let mut binding = self.typing.write().await;
+        binding.remove(&(sid, room.to_owned())).map(|t| t.pseudonym)
+    }
+
+    /// Drop the typing state started at `since` if nobody refreshed it for `ttl`.
+    /// `None` if it is gone or was replaced already, otherwise whether it expired now.
+    pub async fn expire_typing(
+        &self,
+        sid: Sid,
+        room: &str,
+        since: Instant,
+        ttl: Duration,
+    ) -> Option<bool> {
+        let mut binding = self.typing.write().await;
+        let key = (sid, room.to_owned());
+        let entry = binding.get(&key).filter(|t| t.since == since)?;
+        let idle = entry.touched.elapsed() >= ttl;
+        if idle {
+            binding.remove(&key);
+        }
+        Some(idle)
+    }
+
+    /// Clear every typing state of `sid`. Returns the rooms and pseudonyms involved.
+    pub async fn stop_all_typing(&self, sid: &Sid) -> Vec<(String, String)> {
+        let mut binding = self.typing.write().await;
+        let keys: Vec<_> = binding.keys().filter(|(s, _)| s == sid).cloned().collect();
+        keys.into_iter()
+            .filter_map(|key| binding.remove(&key).map(|t| (key.1, t.pseudonym)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(n: usize, limit: Option<usize>) -> (Capacity, Vec<Sid>) {
+        let sids: Vec<Sid> = (0..n).map(|_| Sid::new()).collect();
+        let cap = Capacity {
+            queue: sids.iter().copied().collect(),
+            limit,
+            ..Capacity::default()
+        };
+        (cap, sids)
+    }
+
+    #[test]
+    fn promote_in_arrival_order() {
+        let (mut cap, sids) = queued(3, Some(2));
+        assert_eq!(cap.promote(None), sids[..2]);
+        assert_eq!(cap.queue, [sids[2]]);
+        assert!(cap.promote(None).is_empty());
+
+        cap.viewers.remove(&sids[0]);
+        assert_eq!(cap.promote(None), [sids[2]]);
+        assert!(cap.queue.is_empty());
+    }
+
+    #[test]
+    fn device_limit_beats_default() {
+        let (mut cap, sids) = queued(3, Some(1));
+        assert_eq!(cap.promote(Some(3)), [sids[0]]);
+
+        let (mut cap, sids) = queued(3, None);
+        assert_eq!(cap.promote(Some(2)), sids[..2]);
+    }
+
+    #[test]
+    fn unlimited_admits_everyone() {
+        let (mut cap, sids) = queued(5, None);
+        assert_eq!(cap.promote(None), sids);
+        assert_eq!(cap.viewers.len(), 5);
+    }
+
+    #[test]
+    fn zero_limit_admits_nobody() {
+        let (mut cap, _) = queued(2, Some(0));
+        assert!(cap.promote(None).is_empty());
+        assert_eq!(cap.queue.len(), 2);
     }
 }
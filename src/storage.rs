@@ -0,0 +1,59 @@
+use futures_util::future::BoxFuture;
+use std::{io, path::PathBuf};
+use tokio::fs;
+
+/// Where uploaded files live. Keys are flat names like `<sha256>.png`.
+pub trait Storage: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+    fn exists<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<bool>>;
+}
+
+/// Files in one directory on the local filesystem.
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    // Keys come from hashes, but never let one escape the directory.
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.is_empty() || key.contains(['/', '\\']) || key.starts_with('.') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid key"));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put<'a>(&'a self, key: &'a str, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            fs::create_dir_all(&self.dir).await?;
+            // Write aside and rename, so readers never see a partial file. Every
+            // write gets its own temp file, concurrent uploads of one file included.
+            let tmp = self
+                .dir
+                .join(format!("{key}.{:016x}.part", rand::random::<u64>()));
+            let res = match fs::write(&tmp, data).await {
+                Ok(()) => fs::rename(&tmp, &path).await,
+                Err(e) => Err(e),
+            };
+            if res.is_err() {
+                let _ = fs::remove_file(&tmp).await;
+            }
+            res
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        Box::pin(async move { fs::read(self.path(key)?).await })
+    }
+
+    fn exists<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        Box::pin(async move { fs::try_exists(self.path(key)?).await })
+    }
+}